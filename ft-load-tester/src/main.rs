@@ -12,6 +12,8 @@ async fn main() {
     env_logger::init();
     
     let target_url = "http://127.0.0.1:8080/transfer";
+    // A key with the transfer scope, created with POST /admin/api-keys.
+    let api_key = std::env::var("API_KEY").unwrap_or_default();
     let requests_per_second = 100;
     let duration_minutes = 10;
     let total_requests = requests_per_second * 60 * duration_minutes;
@@ -38,6 +40,7 @@ async fn main() {
             let success_count = Arc::clone(&success_count);
             let error_count = Arc::clone(&error_count);
             let bar = bar.clone();
+            let api_key = api_key.clone();
             
             // Generate a unique receiver for each request
             let receiver_id = format!("loadtest-{}-{}.test.near", i, j);
@@ -49,7 +52,8 @@ async fn main() {
                     "memo": "benchmark"
                 });
 
-                match client.post(target_url).json(&body).send().await {
+                let request = client.post(target_url).header("X-API-Key", api_key).json(&body);
+                match request.send().await {
                     Ok(response) if response.status().is_success() => {
                         success_count.fetch_add(1, Ordering::SeqCst);
                     }
@@ -0,0 +1,67 @@
+use crate::retry;
+use near_api::near_primitives::account::id::AccountType;
+use near_api::{Account, NetworkConfig};
+use near_sdk::AccountId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+// Expired entries are swept once the cache grows past this many accounts.
+const MAX_CACHED_ACCOUNTS: usize = 100_000;
+
+/// Checks with `view_account` that named receivers exist before tokens are
+/// sent to them, remembering the ones that do for `ttl`. Only positive
+/// answers are cached, since a missing account can be created at any time.
+pub struct AccountChecker {
+    network_config: NetworkConfig,
+    ttl: Duration,
+    existing: RwLock<HashMap<AccountId, Instant>>,
+}
+
+impl AccountChecker {
+    pub fn new(network_config: NetworkConfig, ttl: Duration) -> Self {
+        Self {
+            network_config,
+            ttl,
+            existing: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `account_id` exists on chain. Implicit and ETH-implicit
+    /// accounts always count as existing: they are created by the first
+    /// transfer to them.
+    pub async fn exists(
+        &self,
+        account_id: &AccountId,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        if account_id.get_account_type() != AccountType::NamedAccount {
+            return Ok(true);
+        }
+        let cached = self
+            .existing
+            .read()
+            .await
+            .get(account_id)
+            .is_some_and(|checked_at| checked_at.elapsed() < self.ttl);
+        if cached {
+            return Ok(true);
+        }
+
+        match Account(account_id.clone())
+            .view()
+            .fetch_from(&self.network_config)
+            .await
+        {
+            Ok(_) => {
+                let mut existing = self.existing.write().await;
+                if existing.len() >= MAX_CACHED_ACCOUNTS {
+                    existing.retain(|_, checked_at| checked_at.elapsed() < self.ttl);
+                }
+                existing.insert(account_id.clone(), Instant::now());
+                Ok(true)
+            }
+            Err(e) if retry::is_unknown_account(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
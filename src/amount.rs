@@ -0,0 +1,129 @@
+/// Lua `add(a, b)` for the Redis scripts: sums two non-negative decimal
+/// strings digit by digit, since token amounts don't fit in Redis integers
+/// or Lua numbers.
+pub const LUA_ADD: &str = r"
+local function add(a, b)
+    local digits = {}
+    local carry = 0
+    local i, j = #a, #b
+    while i > 0 or j > 0 or carry > 0 do
+        local da = i > 0 and tonumber(string.sub(a, i, i)) or 0
+        local db = j > 0 and tonumber(string.sub(b, j, j)) or 0
+        local sum = da + db + carry
+        table.insert(digits, 1, tostring(sum % 10))
+        carry = math.floor(sum / 10)
+        i = i - 1
+        j = j - 1
+    end
+    local result = string.gsub(table.concat(digits), '^0+', '')
+    if result == '' then
+        return '0'
+    end
+    return result
+end
+";
+
+/// Converts a decimal token amount such as `"12.5"` to its raw integer
+/// value for a token with `decimals` places. Done on the digits so no
+/// precision is lost; more fractional digits than the token has is an error
+/// rather than being rounded away.
+pub fn parse_decimal(amount: &str, decimals: u8) -> Result<u128, String> {
+    let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    if (whole.is_empty() && fraction.is_empty())
+        || !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit())
+    {
+        return Err(format!("amount_decimal {:?} is not a decimal number", amount));
+    }
+
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() > decimals as usize {
+        return Err(format!(
+            "amount_decimal {:?} has more than the token's {} decimal places",
+            amount, decimals
+        ));
+    }
+
+    let digits = format!("{}{:0<width$}", whole, fraction, width = decimals as usize);
+    let digits = digits.trim_start_matches('0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    digits
+        .parse::<u128>()
+        .map_err(|_| format!("amount_decimal {:?} is too large", amount))
+}
+
+/// Formats a raw amount with `decimals` places, dropping trailing zeros
+/// (`12500000` with 6 decimals is `"12.5"`).
+pub fn format_amount(raw: u128, decimals: u8) -> String {
+    let digits = format!("{:0>width$}", raw, width = decimals as usize + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals as usize);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_decimal_amounts() {
+        assert_eq!(parse_decimal("12.5", 6), Ok(12_500_000));
+        assert_eq!(parse_decimal(".5", 6), Ok(500_000));
+        assert_eq!(parse_decimal("12.", 6), Ok(12_000_000));
+        assert_eq!(parse_decimal("0.000", 6), Ok(0));
+        assert_eq!(parse_decimal("007", 2), Ok(700));
+    }
+
+    #[test]
+    fn rejects_malformed_amounts() {
+        assert!(parse_decimal("", 6).is_err());
+        assert!(parse_decimal(".", 6).is_err());
+        assert!(parse_decimal("-1", 6).is_err());
+        assert!(parse_decimal("1.2.3", 6).is_err());
+        assert!(parse_decimal("1e6", 6).is_err());
+    }
+
+    #[test]
+    fn rejects_more_fractional_digits_than_the_token_has() {
+        assert!(parse_decimal("1.1234567", 6).is_err());
+        // Trailing zeros don't count.
+        assert_eq!(parse_decimal("1.1234560000", 6), Ok(1_123_456));
+    }
+
+    #[test]
+    fn rejects_amounts_beyond_u128() {
+        assert_eq!(parse_decimal(&u128::MAX.to_string(), 0), Ok(u128::MAX));
+        assert!(parse_decimal("340282366920938463463374607431768211456", 0).is_err());
+        assert!(parse_decimal("1", 39).is_err());
+    }
+
+    #[test]
+    fn handles_tokens_without_decimals() {
+        assert_eq!(parse_decimal("42", 0), Ok(42));
+        assert_eq!(parse_decimal("42.0", 0), Ok(42));
+        assert!(parse_decimal("42.5", 0).is_err());
+        assert_eq!(format_amount(42, 0), "42");
+    }
+
+    #[test]
+    fn formats_raw_amounts() {
+        assert_eq!(format_amount(12_500_000, 6), "12.5");
+        assert_eq!(format_amount(500_000, 6), "0.5");
+        assert_eq!(format_amount(0, 6), "0");
+        assert_eq!(format_amount(1, 18), "0.000000000000000001");
+    }
+
+    #[test]
+    fn round_trips_through_format_amount() {
+        for (amount, decimals) in [("12.5", 6), ("0.000000000000000001", 18), ("1000", 0), ("0", 24)] {
+            let raw = parse_decimal(amount, decimals).unwrap();
+            assert_eq!(format_amount(raw, decimals), amount);
+            assert_eq!(parse_decimal(&format_amount(raw, decimals), decimals), Ok(raw));
+        }
+    }
+}
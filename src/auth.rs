@@ -0,0 +1,230 @@
+use crate::config::Settings;
+use crate::types::{ApiKey, ApiKeyScope, ClientLimits};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, header};
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse};
+use chrono::Utc;
+use deadpool_redis::{Connection, Pool};
+use log::error;
+use redis::{AsyncCommands, RedisResult};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+// Key ID -> ApiKey JSON, revoked keys included so old transfers can still be
+// attributed.
+const API_KEYS_KEY: &str = "api_keys";
+// SHA-256 of a live key's secret -> key ID.
+const API_KEY_HASHES_KEY: &str = "api_key_hashes";
+
+/// ID the `ADMIN_API_KEY` from the environment is attributed to.
+pub const BOOTSTRAP_KEY_ID: &str = "admin";
+
+fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn generate_secret() -> String {
+    let bytes: [u8; 32] = rand::random();
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("nft_{}", hex)
+}
+
+/// Creates a key and returns it with its secret, which is not stored.
+pub async fn create(
+    conn: &mut Connection,
+    name: String,
+    scopes: Vec<ApiKeyScope>,
+    limits: Option<ClientLimits>,
+) -> RedisResult<(ApiKey, String)> {
+    let key = ApiKey {
+        id: Uuid::new_v4().to_string(),
+        name,
+        scopes,
+        created_at: Utc::now(),
+        revoked_at: None,
+        limits,
+    };
+    let secret = generate_secret();
+    redis::pipe()
+        .atomic()
+        .hset(API_KEYS_KEY, &key.id, serde_json::to_string(&key).unwrap())
+        .ignore()
+        .hset(API_KEY_HASHES_KEY, hash_secret(&secret), &key.id)
+        .ignore()
+        .query_async::<()>(conn)
+        .await?;
+    Ok((key, secret))
+}
+
+pub async fn get(conn: &mut Connection, id: &str) -> RedisResult<Option<ApiKey>> {
+    let key_json: Option<String> = conn.hget(API_KEYS_KEY, id).await?;
+    Ok(key_json.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+/// Every key, oldest first.
+pub async fn list(conn: &mut Connection) -> RedisResult<Vec<ApiKey>> {
+    let entries: Vec<String> = conn.hvals(API_KEYS_KEY).await?;
+    let mut keys: Vec<ApiKey> = entries
+        .iter()
+        .filter_map(|json| serde_json::from_str(json).ok())
+        .collect();
+    keys.sort_by_key(|key| key.created_at);
+    Ok(keys)
+}
+
+/// Stops the key from authenticating. Its record is kept, marked revoked.
+/// Returns `None` if there is no such key.
+pub async fn revoke(conn: &mut Connection, id: &str) -> RedisResult<Option<ApiKey>> {
+    let Some(mut key) = get(conn, id).await? else {
+        return Ok(None);
+    };
+    if key.revoked_at.is_none() {
+        key.revoked_at = Some(Utc::now());
+    }
+    // The secret isn't known any more, so find its hash by the key ID.
+    let hashes: std::collections::HashMap<String, String> =
+        conn.hgetall(API_KEY_HASHES_KEY).await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .hset(API_KEYS_KEY, &key.id, serde_json::to_string(&key).unwrap())
+        .ignore();
+    for (hash, key_id) in hashes {
+        if key_id == key.id {
+            pipe.hdel(API_KEY_HASHES_KEY, hash).ignore();
+        }
+    }
+    pipe.query_async::<()>(conn).await?;
+    Ok(Some(key))
+}
+
+/// Looks up the live key whose secret is `secret`.
+pub async fn authenticate(conn: &mut Connection, secret: &str) -> RedisResult<Option<ApiKey>> {
+    let key_id: Option<String> = conn.hget(API_KEY_HASHES_KEY, hash_secret(secret)).await?;
+    let Some(key_id) = key_id else {
+        return Ok(None);
+    };
+    Ok(get(conn, &key_id)
+        .await?
+        .filter(|key| key.revoked_at.is_none()))
+}
+
+/// The key a request was authenticated with, set by `require_api_key`.
+pub fn key(request: &HttpRequest) -> Option<ApiKey> {
+    request.extensions().get::<ApiKey>().cloned()
+}
+
+// The scope an endpoint needs, or `None` for the public API docs.
+fn required_scope(method: &Method, path: &str) -> Option<ApiKeyScope> {
+    if path.starts_with("/admin/") {
+        return Some(ApiKeyScope::Admin);
+    }
+    if method != Method::GET && method != Method::HEAD {
+        return Some(ApiKeyScope::Transfer);
+    }
+    let is_api = [
+        "/transaction/",
+        "/transactions",
+        "/campaigns/",
+        "/token",
+        "/balance",
+        "/webhooks",
+    ]
+    .iter()
+    .any(|prefix| path.starts_with(prefix));
+    is_api.then_some(ApiKeyScope::Read)
+}
+
+fn presented_secret(req: &ServiceRequest) -> Option<String> {
+    let headers = req.headers();
+    if let Some(secret) = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+    {
+        return Some(secret.trim().to_string());
+    }
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|secret| secret.trim().to_string())
+}
+
+// The key presented with the request if it may call the endpoint, `None` for
+// public endpoints, or the response to reject the request with.
+async fn authorize(
+    settings: &Settings,
+    redis_pool: &Pool,
+    req: &ServiceRequest,
+) -> Result<Option<ApiKey>, HttpResponse> {
+    let Some(scope) = required_scope(req.method(), req.path()) else {
+        return Ok(None);
+    };
+    let Some(secret) = presented_secret(req).filter(|secret| !secret.is_empty()) else {
+        return Err(HttpResponse::Unauthorized().body("An API key is required."));
+    };
+
+    let bootstrap = settings
+        .admin_api_key
+        .as_deref()
+        .is_some_and(|admin_key| hash_secret(admin_key) == hash_secret(&secret));
+    let key = if bootstrap {
+        ApiKey {
+            id: BOOTSTRAP_KEY_ID.to_string(),
+            name: "ADMIN_API_KEY".to_string(),
+            scopes: vec![ApiKeyScope::Admin],
+            created_at: Utc::now(),
+            revoked_at: None,
+            limits: None,
+        }
+    } else {
+        let mut conn = redis_pool.get().await.map_err(|e| {
+            error!("Could not get Redis connection: {}", e);
+            HttpResponse::InternalServerError().finish()
+        })?;
+        match authenticate(&mut conn, &secret).await {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                return Err(HttpResponse::Unauthorized().body("Unknown or revoked API key."));
+            }
+            Err(e) => {
+                error!("Redis API key lookup error: {}", e);
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+        }
+    };
+
+    if !key.allows(scope) {
+        return Err(HttpResponse::Forbidden().body(format!(
+            "API key {} does not have the {:?} scope.",
+            key.id, scope
+        )));
+    }
+    Ok(Some(key))
+}
+
+/// Rejects requests without a live API key that has the endpoint's scope,
+/// and attaches the key to the request for attribution.
+pub async fn require_api_key(
+    settings: Data<Settings>,
+    redis_pool: Data<Pool>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    match authorize(&settings, &redis_pool, &req).await {
+        Ok(key) => {
+            if let Some(key) = key {
+                req.extensions_mut().insert(key);
+            }
+            Ok(next.call(req).await?.map_into_left_body())
+        }
+        Err(response) => Ok(req.into_response(response).map_into_right_body()),
+    }
+}
@@ -0,0 +1,220 @@
+use crate::config::Settings;
+use crate::keypool::KeyPool;
+use crate::keystore::{self, KeyStore};
+use deadpool_redis::Pool;
+use log::{error, info};
+use near_api::{NetworkConfig, Signer};
+use near_crypto::{PublicKey, SecretKey};
+use near_sdk::AccountId;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// Retired keys may still be signing a batch, so they are deleted from the
+// account only after this long.
+const RETIRED_KEY_GRACE: Duration = Duration::from_secs(120);
+
+/// Grows and shrinks the key pool at runtime, keeping the number of batches
+/// in flight in step with it: concurrency stays `num_pool_keys - concurrency`
+/// keys below the pool size, as configured at boot.
+pub struct PoolManager {
+    key_pool: Arc<KeyPool>,
+    key_store: Arc<KeyStore>,
+    master_signer: Arc<Signer>,
+    account_id: AccountId,
+    network_config: NetworkConfig,
+    redis_pool: Pool,
+    settings: Settings,
+    resizing: Mutex<()>,
+}
+
+impl PoolManager {
+    pub fn new(
+        key_pool: Arc<KeyPool>,
+        key_store: Arc<KeyStore>,
+        master_signer: Arc<Signer>,
+        account_id: AccountId,
+        network_config: NetworkConfig,
+        redis_pool: Pool,
+        settings: Settings,
+    ) -> Self {
+        Self {
+            key_pool,
+            key_store,
+            master_signer,
+            account_id,
+            network_config,
+            redis_pool,
+            settings,
+            resizing: Mutex::new(()),
+        }
+    }
+
+    pub fn max_pool_keys(&self) -> usize {
+        self.settings.max_pool_keys
+    }
+
+    fn concurrency_for(&self, pool_size: usize) -> usize {
+        let headroom = self.settings.num_pool_keys.saturating_sub(self.settings.concurrency);
+        pool_size.saturating_sub(headroom).max(1)
+    }
+
+    /// Adds or retires keys until the pool has `target` keys, then adjusts
+    /// concurrency. Returns the new pool size, which is smaller than
+    /// `target` if not every key could be added.
+    pub async fn resize(
+        &self,
+        target: usize,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let _resizing = self.resizing.lock().await;
+        let current = self.key_pool.size().await;
+
+        if target > current {
+            let mut conn = self.redis_pool.get().await?;
+            let added = keystore::add_pool_keys(
+                &self.key_store,
+                &mut conn,
+                &self.account_id,
+                target - current,
+                &self.master_signer,
+                &self.network_config,
+            )
+            .await?;
+            for secret_key in added {
+                self.key_pool.add(secret_key).await?;
+            }
+        } else if target < current {
+            let retired = self.key_pool.retire(current - target).await;
+            self.delete_later(retired);
+        }
+
+        let size = self.key_pool.size().await;
+        self.key_pool.set_concurrency(self.concurrency_for(size));
+        info!(
+            "Key pool resized from {} to {} keys; concurrency is now {}.",
+            current,
+            size,
+            self.key_pool.concurrency()
+        );
+        Ok(size)
+    }
+
+    fn delete_later(&self, retired: Vec<SecretKey>) {
+        if retired.is_empty() {
+            return;
+        }
+        let key_pool = Arc::clone(&self.key_pool);
+        let key_store = Arc::clone(&self.key_store);
+        let master_signer = Arc::clone(&self.master_signer);
+        let account_id = self.account_id.clone();
+        let network_config = self.network_config.clone();
+        let redis_pool = self.redis_pool.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(RETIRED_KEY_GRACE).await;
+            let mut conn = match redis_pool.get().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // Still stored, so the next start deletes them as orphans.
+                    error!("Could not get Redis connection to delete retired keys: {}", e);
+                    return;
+                }
+            };
+            keystore::delete_orphaned_keys(
+                &key_store,
+                &mut conn,
+                &account_id,
+                &retired,
+                &master_signer,
+                &network_config,
+            )
+            .await;
+            let public_keys: Vec<PublicKey> = retired.iter().map(SecretKey::public_key).collect();
+            key_pool.forget(&public_keys).await;
+        });
+    }
+
+    /// Replaces every key of the pool with a fresh one: the new keys are
+    /// added first, then the old ones retired and deleted from the account
+    /// after the grace period. Returns the number of keys replaced.
+    pub async fn rotate(&self) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let _resizing = self.resizing.lock().await;
+        let current = self.key_pool.size().await;
+        if current == 0 {
+            return Ok(0);
+        }
+
+        let mut conn = self.redis_pool.get().await?;
+        let added = keystore::add_pool_keys(
+            &self.key_store,
+            &mut conn,
+            &self.account_id,
+            current,
+            &self.master_signer,
+            &self.network_config,
+        )
+        .await?;
+        let replaced = added.len();
+        for secret_key in added {
+            self.key_pool.add(secret_key).await?;
+        }
+        // Keys are retired oldest first (quarantined ones before that), so
+        // the ones that go are keys from before the rotation.
+        let retired = self.key_pool.retire(replaced).await;
+        self.delete_later(retired);
+        info!("Rotated {} of {} pool keys.", replaced, current);
+        Ok(replaced)
+    }
+
+    /// Rotates the pool every `key_rotation_secs`, so a leaked pool key is
+    /// only usable until the next rotation.
+    pub async fn run_rotation(self: Arc<Self>) {
+        let interval = Duration::from_secs(self.settings.key_rotation_secs.max(1));
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.rotate().await {
+                error!("Failed to rotate the key pool: {}", e);
+            }
+        }
+    }
+
+    /// Grows the pool by `autoscale_step` keys while batches are waiting for
+    /// a slot, and shrinks it by the same step once nothing has been sent or
+    /// waiting for `autoscale_idle_secs`, within `min_pool_keys` and
+    /// `max_pool_keys`.
+    pub async fn run_autoscaler(self: Arc<Self>) {
+        let interval = Duration::from_secs(self.settings.autoscale_interval_secs.max(1));
+        let idle_after = Duration::from_secs(self.settings.autoscale_idle_secs);
+        let mut last_busy = Instant::now();
+
+        loop {
+            tokio::time::sleep(interval).await;
+            let size = self.key_pool.size().await;
+            let waiting = self.key_pool.waiting();
+            let in_flight = self.key_pool.in_flight();
+
+            let target = if waiting > 0 {
+                last_busy = Instant::now();
+                (size + self.settings.autoscale_step).min(self.settings.max_pool_keys)
+            } else if in_flight > 0 {
+                last_busy = Instant::now();
+                size
+            } else if last_busy.elapsed() >= idle_after {
+                last_busy = Instant::now();
+                size.saturating_sub(self.settings.autoscale_step)
+                    .max(self.settings.min_pool_keys)
+            } else {
+                size
+            };
+
+            if target != size {
+                info!(
+                    "Autoscaling key pool from {} to {} keys ({} batches waiting).",
+                    size, target, waiting
+                );
+                if let Err(e) = self.resize(target).await {
+                    error!("Failed to resize key pool: {}", e);
+                }
+            }
+        }
+    }
+}
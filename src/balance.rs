@@ -0,0 +1,128 @@
+use crate::store::{StoreResult, TransactionStore};
+use crate::types::{BalanceResponse, TransactionRecord, TransactionStatus};
+use chrono::{DateTime, Utc};
+use near_api::{Contract, NetworkConfig};
+use near_sdk::AccountId;
+use near_sdk::json_types::U128;
+use serde_json::json;
+use std::sync::Mutex;
+
+// Pending records are summed in pages of this size at startup.
+const RESTORE_PAGE_SIZE: usize = 1_000;
+
+struct BalanceState {
+    /// Last `ft_balance_of` of the sender, minus transfers that succeeded since.
+    balance: Option<u128>,
+    /// Sum of the amounts of accepted transfers that haven't finished yet.
+    reserved: u128,
+    refreshed_at: Option<DateTime<Utc>>,
+}
+
+/// Tracks the sender's token balance and the amount already promised to
+/// pending transfers, so new transfers can be turned away when the balance
+/// can't cover them instead of failing on chain.
+pub struct BalanceTracker {
+    ft_contract_id: AccountId,
+    account_id: AccountId,
+    network_config: NetworkConfig,
+    state: Mutex<BalanceState>,
+}
+
+impl BalanceTracker {
+    pub fn new(ft_contract_id: AccountId, account_id: AccountId, network_config: NetworkConfig) -> Self {
+        Self {
+            ft_contract_id,
+            account_id,
+            network_config,
+            state: Mutex::new(BalanceState {
+                balance: None,
+                reserved: 0,
+                refreshed_at: None,
+            }),
+        }
+    }
+
+    /// Reads the sender's balance from the contract.
+    pub async fn refresh(&self) -> Result<u128, Box<dyn std::error::Error + Send + Sync>> {
+        let balance = Contract(self.ft_contract_id.clone())
+            .call_function("ft_balance_of", json!({ "account_id": self.account_id }))?
+            .read_only::<U128>()
+            .fetch_from(&self.network_config)
+            .await?
+            .data
+            .0;
+        let mut state = self.state.lock().unwrap();
+        state.balance = Some(balance);
+        state.refreshed_at = Some(Utc::now());
+        Ok(balance)
+    }
+
+    /// Rebuilds the reserved amount from the transfers a previous run left
+    /// unfinished.
+    pub async fn restore_reservations(&self, store: &dyn TransactionStore) -> StoreResult<u128> {
+        let mut reserved: u128 = 0;
+        for status in TransactionStatus::ALL.into_iter().filter(|s| !s.is_terminal()) {
+            let mut offset = 0;
+            loop {
+                let records = store.list_by_status(status, offset, RESTORE_PAGE_SIZE).await?;
+                if records.is_empty() {
+                    break;
+                }
+                for record in records {
+                    reserved = reserved.saturating_add(record.raw_amount());
+                }
+                offset += RESTORE_PAGE_SIZE;
+            }
+        }
+        self.state.lock().unwrap().reserved = reserved;
+        Ok(reserved)
+    }
+
+    /// Reserves `amount` for a new transfer, or returns the available
+    /// balance when it isn't enough. Transfers are let through while the
+    /// balance has never been read.
+    pub fn reserve(&self, amount: u128) -> Result<(), u128> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(balance) = state.balance {
+            let available = balance.saturating_sub(state.reserved);
+            if amount > available {
+                return Err(available);
+            }
+        }
+        state.reserved = state.reserved.saturating_add(amount);
+        Ok(())
+    }
+
+    /// Gives back a reservation made for a transfer that was never stored.
+    pub fn unreserve(&self, amount: u128) {
+        let mut state = self.state.lock().unwrap();
+        state.reserved = state.reserved.saturating_sub(amount);
+    }
+
+    /// Releases the reservations of records that reached a final status.
+    /// Successful transfers are also taken off the balance, since the next
+    /// refresh is the first to see them.
+    pub fn settle(&self, finished: &[TransactionRecord]) {
+        let mut state = self.state.lock().unwrap();
+        for record in finished.iter().filter(|record| record.status.is_terminal()) {
+            let amount = record.raw_amount();
+            state.reserved = state.reserved.saturating_sub(amount);
+            if record.status == TransactionStatus::Success {
+                state.balance = state.balance.map(|balance| balance.saturating_sub(amount));
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> BalanceResponse {
+        let state = self.state.lock().unwrap();
+        BalanceResponse {
+            account_id: self.account_id.to_string(),
+            balance: state.balance.map(|balance| balance.to_string()),
+            reserved: state.reserved.to_string(),
+            available: state
+                .balance
+                .map(|balance| balance.saturating_sub(state.reserved).to_string()),
+            refreshed_at: state.refreshed_at,
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use crate::queue;
+use crate::store::{StoreResult, TransactionStore};
+use crate::types::{
+    Campaign, CampaignProgress, CampaignStatus, TokenTransferRequest, TransactionRecord,
+    TransactionStatus,
+};
+use deadpool_redis::Connection;
+use log::error;
+use redis::{AsyncCommands, Direction, RedisResult};
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+
+// Records are stored and queued in chunks so a large airdrop isn't written
+// in one huge command.
+const ENQUEUE_CHUNK_SIZE: usize = 1_000;
+
+pub fn campaign_key(id: &str) -> String {
+    format!("campaign:{}", id)
+}
+
+/// List of the IDs of a campaign's transactions, in submission order.
+pub fn campaign_txns_key(id: &str) -> String {
+    format!("campaign_txns:{}", id)
+}
+
+/// Per-campaign counterpart of `records::status_index_key`. The campaign
+/// index keys below are only maintained by the Redis transaction store.
+pub fn status_index_key(id: &str, status: TransactionStatus) -> String {
+    format!("campaign_txns_by_status:{}:{}", id, status)
+}
+
+/// Hash of the campaign's settled amounts (`amount_sent`, `amount_unsent`).
+pub fn stats_key(id: &str) -> String {
+    format!("campaign_stats:{}", id)
+}
+
+/// Set of transfers whose amount is already counted in `stats_key`.
+pub fn settled_key(id: &str) -> String {
+    format!("campaign_settled:{}", id)
+}
+
+/// Queued transfers held back while the campaign is paused.
+pub fn paused_key(id: &str) -> String {
+    format!("campaign_paused:{}", id)
+}
+
+pub async fn get(conn: &mut Connection, id: &str) -> RedisResult<Option<Campaign>> {
+    let campaign_json: Option<String> = conn.get(campaign_key(id)).await?;
+    Ok(campaign_json.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+async fn save(conn: &mut Connection, campaign: &Campaign) -> RedisResult<()> {
+    conn.set(
+        campaign_key(&campaign.id),
+        serde_json::to_string(campaign).unwrap(),
+    )
+    .await
+}
+
+/// Stores `campaign` and queues all of its records. Every record is in the
+/// transaction store before any of them is queued, and they are queued in
+/// one MULTI, so a failure leaves nothing for the worker to send: the
+/// records already stored are cancelled and the caller can undo the whole
+/// campaign.
+pub async fn create(
+    conn: &mut Connection,
+    store: &dyn TransactionStore,
+    campaign: &Campaign,
+    transfers: &[TransactionRecord],
+) -> StoreResult<()> {
+    save(conn, campaign).await?;
+
+    let mut stored = 0;
+    for chunk in transfers.chunks(ENQUEUE_CHUNK_SIZE) {
+        if let Err(e) = store.create(chunk).await {
+            abandon(store, &transfers[..stored]).await;
+            return Err(e);
+        }
+        stored += chunk.len();
+    }
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for chunk in transfers.chunks(ENQUEUE_CHUNK_SIZE) {
+        let ids: Vec<&str> = chunk.iter().map(|record| record.id.as_str()).collect();
+        pipe.lpush(queue::QUEUE_KEY, ids).ignore();
+    }
+    if let Err(e) = pipe.query_async::<()>(conn).await {
+        abandon(store, transfers).await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+// Cancels records that were stored but will never be queued, so the
+// campaign doesn't show them as pending forever.
+async fn abandon(store: &dyn TransactionStore, records: &[TransactionRecord]) {
+    let cancelled: Vec<TransactionRecord> = records
+        .iter()
+        .cloned()
+        .filter_map(|mut record| {
+            record.transition(TransactionStatus::Cancelled).ok()?;
+            record.error_message = Some("The campaign could not be queued".to_string());
+            Some(record)
+        })
+        .collect();
+    if let Err(e) = store.update(&cancelled).await {
+        error!("Failed to cancel {} records of an unqueued campaign: {}", cancelled.len(), e);
+    }
+}
+
+/// Counts the campaign's transfers per status and works out how much of
+/// its total has been sent and how much is still to go.
+pub async fn progress(
+    store: &dyn TransactionStore,
+    campaign: Campaign,
+) -> StoreResult<CampaignProgress> {
+    let totals = store.campaign_totals(&campaign.id).await?;
+    let pending: u64 = totals
+        .counts
+        .iter()
+        .filter(|(status, _)| !status.is_terminal())
+        .map(|(_, count)| count)
+        .sum();
+    let counts: BTreeMap<String, u64> = totals
+        .counts
+        .into_iter()
+        .map(|(status, count)| (status.to_string(), count))
+        .collect();
+
+    let total: u128 = campaign.total_amount.parse().unwrap_or(0);
+    Ok(CampaignProgress {
+        amount_sent: totals.amount_sent.to_string(),
+        amount_unsent: totals.amount_unsent.to_string(),
+        amount_remaining: total
+            .saturating_sub(totals.amount_sent)
+            .saturating_sub(totals.amount_unsent)
+            .to_string(),
+        completed: pending == 0,
+        counts,
+        campaign,
+    })
+}
+
+/// Changes the campaign's status. Resuming puts every held-back transfer
+/// back on the queue; cancelling cancels them and returns the cancelled
+/// records. Transfers still in the queue or retry set are handled by the
+/// worker when it reaches them.
+pub async fn set_status(
+    conn: &mut Connection,
+    store: &dyn TransactionStore,
+    campaign: &mut Campaign,
+    status: CampaignStatus,
+) -> StoreResult<Vec<TransactionRecord>> {
+    let mut cancelled = Vec::new();
+    campaign.status = status;
+    save(conn, campaign).await?;
+
+    match status {
+        CampaignStatus::Paused => {}
+        CampaignStatus::Active => {
+            while conn
+                .lmove::<_, _, Option<String>>(
+                    paused_key(&campaign.id),
+                    queue::QUEUE_KEY,
+                    Direction::Right,
+                    Direction::Left,
+                )
+                .await?
+                .is_some()
+            {}
+        }
+        CampaignStatus::Cancelled => loop {
+            let ids: Vec<String> = conn.lpop(paused_key(&campaign.id), NonZeroUsize::new(500)).await?;
+            if ids.is_empty() {
+                break;
+            }
+            let mut batch = Vec::with_capacity(ids.len());
+            for mut record in store.get_many(&ids).await? {
+                let from = record.status;
+                if record.transition(TransactionStatus::Cancelled).is_ok() {
+                    batch.push((from, record));
+                }
+            }
+            let left_alone = store.update_from(&batch).await?;
+            cancelled.extend(
+                batch
+                    .into_iter()
+                    .map(|(_, record)| record)
+                    .filter(|record| !left_alone.contains(&record.id)),
+            );
+        },
+    }
+    Ok(cancelled)
+}
+
+// Parks ARGV[1] in KEYS[2] if the campaign in KEYS[1] is still paused, or
+// puts it back on the queue KEYS[3] otherwise, and takes it off the
+// processing list KEYS[4]. Returns 1 if it was parked.
+const HOLD_SCRIPT: &str = r"
+local campaign = redis.call('GET', KEYS[1])
+local paused = campaign and cjson.decode(campaign)['status'] == 'Paused'
+if paused then
+    redis.call('RPUSH', KEYS[2], ARGV[1])
+else
+    redis.call('LPUSH', KEYS[3], ARGV[1])
+end
+redis.call('LREM', KEYS[4], 1, ARGV[1])
+return paused and 1 or 0
+";
+
+/// Parks a dequeued transfer of a paused campaign until it is resumed,
+/// taking it off the processing list. The campaign is checked again in the
+/// same script, so a resume that drained the paused list after the worker
+/// read the campaign can't strand the transfer there; it is queued again
+/// instead. Returns whether it was parked.
+pub async fn hold(conn: &mut Connection, record: &TransactionRecord) -> RedisResult<bool> {
+    let Some(campaign_id) = &record.campaign_id else {
+        return Ok(false);
+    };
+    redis::Script::new(HOLD_SCRIPT)
+        .key(campaign_key(campaign_id))
+        .key(paused_key(campaign_id))
+        .key(queue::QUEUE_KEY)
+        .key(queue::PROCESSING_KEY)
+        .arg(&record.id)
+        .invoke_async(conn)
+        .await
+}
+
+// The reader reports where it started looking for a record, which is before
+// any blank lines it skipped; the record itself starts after them.
+fn record_line(body: &[u8], position: &csv::Position) -> usize {
+    let skipped = body
+        .get(position.byte() as usize..)
+        .unwrap_or_default()
+        .iter()
+        .take_while(|byte| matches!(byte, b'\r' | b'\n'))
+        .filter(|byte| **byte == b'\n')
+        .count();
+    position.line() as usize + skipped
+}
+
+/// A bulk row's number and either its request or why it couldn't be read.
+pub type BulkRow = (usize, Result<TokenTransferRequest, String>);
+
+/// Parses a `receiver,amount,memo` CSV file. A header row is skipped if
+/// present and the memo column is optional. Each row yields the line it
+/// starts on and either a request or the reason it couldn't be read, so one
+/// bad line only rejects that row.
+pub fn parse_csv(body: &[u8]) -> Vec<BulkRow> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(body);
+
+    let mut rows = Vec::new();
+    let mut line = 0;
+    for (index, row) in reader.records().enumerate() {
+        let position = match &row {
+            Ok(row) => row.position(),
+            Err(e) => e.position(),
+        };
+        line = position.map_or(line + 1, |position| record_line(body, position));
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                rows.push((line, Err(format!("Unreadable CSV row: {}", e))));
+                continue;
+            }
+        };
+
+        let receiver = row.get(0).unwrap_or_default();
+        if index == 0
+            && matches!(
+                receiver.to_lowercase().as_str(),
+                "receiver" | "receiver_id" | "reciever_id"
+            )
+        {
+            continue;
+        }
+        if row.len() < 2 || row.len() > 3 {
+            rows.push((
+                line,
+                Err(format!(
+                    "Expected receiver,amount[,memo] but found {} columns",
+                    row.len()
+                )),
+            ));
+            continue;
+        }
+
+        // Amounts with a decimal point are in whole tokens, the rest are raw.
+        let amount = row.get(1).unwrap_or_default();
+        let (amount, amount_decimal) = if amount.contains('.') {
+            (String::new(), Some(amount.to_string()))
+        } else {
+            (amount.to_string(), None)
+        };
+        rows.push((
+            line,
+            Ok(TokenTransferRequest {
+                reciever_id: receiver.to_string(),
+                amount,
+                amount_decimal,
+                memo: row.get(2).filter(|memo| !memo.is_empty()).map(str::to_string),
+                dedupe_key: None,
+                callback_url: None,
+            }),
+        ));
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_rows_are_numbered_by_line() {
+        let body = b"receiver,amount\nalice.near,10\n\nbob.near\ncarol.near,1.5,thanks\n";
+        let rows = parse_csv(body);
+        let lines: Vec<usize> = rows.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![2, 4, 5]);
+        assert!(rows[0].1.is_ok());
+        assert!(rows[1].1.is_err());
+        let carol = rows[2].1.as_ref().unwrap();
+        assert_eq!(carol.amount_decimal.as_deref(), Some("1.5"));
+        assert_eq!(carol.memo.as_deref(), Some("thanks"));
+    }
+
+    #[test]
+    fn csv_without_header_starts_at_line_one_with_crlf() {
+        let rows = parse_csv(b"alice.near,10\r\n\r\nbob.near,20\r\n");
+        let lines: Vec<usize> = rows.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![1, 3]);
+    }
+}
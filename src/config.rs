@@ -1,3 +1,7 @@
+use crate::keystore::IncompletePoolMode;
+use crate::storage::StorageRegistrationMode;
+use crate::store::StoreBackend;
+use crate::token::DecimalsMismatchMode;
 use dotenv::dotenv;
 use serde::Deserialize;
 use std::{env, fs};
@@ -15,6 +19,200 @@ struct FileSettings {
     pub num_pool_keys: usize,
     pub key_allowance_near: f64,
     pub network: String,
+    #[serde(default = "default_idempotency_ttl_secs")]
+    pub idempotency_ttl_secs: u64,
+    #[serde(default = "default_storage_registration")]
+    pub storage_registration: StorageRegistrationMode,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
+    #[serde(default)]
+    pub transfer_ttl_secs: u64,
+    #[serde(default = "default_bulk_max_rows")]
+    pub bulk_max_rows: usize,
+    #[serde(default = "default_bulk_check_concurrency")]
+    pub bulk_check_concurrency: usize,
+    #[serde(default = "default_decimals_mismatch")]
+    pub decimals_mismatch: DecimalsMismatchMode,
+    #[serde(default = "default_balance_refresh_secs")]
+    pub balance_refresh_secs: u64,
+    #[serde(default = "default_incomplete_pool")]
+    pub incomplete_pool: IncompletePoolMode,
+    #[serde(default = "default_key_quarantine_failures")]
+    pub key_quarantine_failures: u32,
+    #[serde(default = "default_key_quarantine_secs")]
+    pub key_quarantine_secs: u64,
+    #[serde(default)]
+    pub autoscale: bool,
+    pub min_pool_keys: Option<usize>,
+    pub max_pool_keys: Option<usize>,
+    #[serde(default = "default_autoscale_step")]
+    pub autoscale_step: usize,
+    #[serde(default = "default_autoscale_interval_secs")]
+    pub autoscale_interval_secs: u64,
+    #[serde(default = "default_autoscale_idle_secs")]
+    pub autoscale_idle_secs: u64,
+    #[serde(default)]
+    pub key_rotation_secs: u64,
+    #[serde(default = "default_transaction_store")]
+    pub transaction_store: StoreBackend,
+    #[serde(default)]
+    pub key_requests_per_second: u64,
+    pub key_amount_per_hour: Option<String>,
+    pub key_amount_per_day: Option<String>,
+    #[serde(default)]
+    pub global_requests_per_second: u64,
+    pub global_amount_per_hour: Option<String>,
+    pub global_amount_per_day: Option<String>,
+    #[serde(default = "default_receiver_window_secs")]
+    pub receiver_window_secs: u64,
+    #[serde(default)]
+    pub receiver_max_transfers: u64,
+    pub receiver_max_amount: Option<String>,
+    #[serde(default)]
+    pub screening_allow: Vec<String>,
+    #[serde(default)]
+    pub screening_deny: Vec<String>,
+    #[serde(default)]
+    pub screening_allow_patterns: Vec<String>,
+    #[serde(default)]
+    pub screening_deny_patterns: Vec<String>,
+    #[serde(default)]
+    pub screening_deny_implicit: bool,
+    #[serde(default)]
+    pub screening_deny_eth_implicit: bool,
+    pub screening_hook_url: Option<String>,
+    #[serde(default = "default_screening_hook_timeout_ms")]
+    pub screening_hook_timeout_ms: u64,
+    #[serde(default)]
+    pub screening_hook_fail_open: bool,
+    #[serde(default)]
+    pub receiver_must_exist: bool,
+    #[serde(default = "default_receiver_exists_cache_secs")]
+    pub receiver_exists_cache_secs: u64,
+    #[serde(default = "default_webhook_timeout_ms")]
+    pub webhook_timeout_ms: u64,
+    #[serde(default = "default_webhook_concurrency")]
+    pub webhook_concurrency: usize,
+    #[serde(default = "default_webhook_max_attempts")]
+    pub webhook_max_attempts: u32,
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub webhook_retry_base_delay_ms: u64,
+    #[serde(default = "default_webhook_retry_max_delay_ms")]
+    pub webhook_retry_max_delay_ms: u64,
+    #[serde(default = "default_webhook_log_ttl_secs")]
+    pub webhook_log_ttl_secs: u64,
+}
+
+fn default_webhook_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_webhook_concurrency() -> usize {
+    16
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    10
+}
+
+fn default_webhook_retry_max_delay_ms() -> u64 {
+    60 * 60 * 1_000
+}
+
+fn default_webhook_log_ttl_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_receiver_exists_cache_secs() -> u64 {
+    60 * 60
+}
+
+fn default_screening_hook_timeout_ms() -> u64 {
+    2_000
+}
+
+fn default_receiver_window_secs() -> u64 {
+    24 * 60 * 60
+}
+
+// Amount limits are raw integer strings, since they may not fit in a TOML
+// integer. Missing means no limit.
+fn parse_amount_limit(name: &str, value: Option<String>) -> Result<u128, String> {
+    match value {
+        None => Ok(0),
+        Some(value) => value
+            .trim()
+            .parse::<u128>()
+            .map_err(|_| format!("{} must be a raw integer amount, got {:?}", name, value)),
+    }
+}
+
+fn default_transaction_store() -> StoreBackend {
+    StoreBackend::Redis
+}
+
+fn default_autoscale_step() -> usize {
+    5
+}
+
+fn default_autoscale_interval_secs() -> u64 {
+    10
+}
+
+fn default_autoscale_idle_secs() -> u64 {
+    300
+}
+
+fn default_key_quarantine_failures() -> u32 {
+    3
+}
+
+fn default_key_quarantine_secs() -> u64 {
+    30
+}
+
+fn default_incomplete_pool() -> IncompletePoolMode {
+    IncompletePoolMode::Degraded
+}
+
+fn default_balance_refresh_secs() -> u64 {
+    30
+}
+
+fn default_decimals_mismatch() -> DecimalsMismatchMode {
+    DecimalsMismatchMode::Fail
+}
+
+fn default_bulk_max_rows() -> usize {
+    50_000
+}
+
+fn default_bulk_check_concurrency() -> usize {
+    32
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    1_000
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    60_000
+}
+
+fn default_storage_registration() -> StorageRegistrationMode {
+    StorageRegistrationMode::Auto
+}
+
+fn default_idempotency_ttl_secs() -> u64 {
+    24 * 60 * 60
 }
 
 // This is the final, complete Settings struct for the application
@@ -24,6 +222,7 @@ pub struct Settings {
     pub ft_contract_id: String,
     pub account_id: String,
     pub master_key: String, // Loaded from .env
+    pub key_pool_secret: String, // Loaded from .env, defaults to the master key
     pub ft_decimals: u8,
     pub batch_size: usize,
     pub batch_timeout_secs: u64,
@@ -32,6 +231,56 @@ pub struct Settings {
     pub key_allowance_near: f64,
     pub network: String,
     pub redis_url: String,
+    pub idempotency_ttl_secs: u64,
+    pub storage_registration: StorageRegistrationMode,
+    pub max_attempts: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub transfer_ttl_secs: u64,
+    pub bulk_max_rows: usize,
+    pub bulk_check_concurrency: usize,
+    pub decimals_mismatch: DecimalsMismatchMode,
+    pub balance_refresh_secs: u64,
+    pub incomplete_pool: IncompletePoolMode,
+    pub key_quarantine_failures: u32,
+    pub key_quarantine_secs: u64,
+    pub autoscale: bool,
+    pub min_pool_keys: usize,
+    pub max_pool_keys: usize,
+    pub autoscale_step: usize,
+    pub autoscale_interval_secs: u64,
+    pub autoscale_idle_secs: u64,
+    pub key_rotation_secs: u64,
+    pub transaction_store: StoreBackend,
+    pub database_url: Option<String>, // Loaded from .env, only needed for Postgres
+    pub admin_api_key: Option<String>, // Loaded from .env, bootstrap key with the admin scope
+    pub key_requests_per_second: u64,
+    pub key_amount_per_hour: u128,
+    pub key_amount_per_day: u128,
+    pub global_requests_per_second: u64,
+    pub global_amount_per_hour: u128,
+    pub global_amount_per_day: u128,
+    pub receiver_window_secs: u64,
+    pub receiver_max_transfers: u64,
+    pub receiver_max_amount: u128,
+    pub screening_allow: Vec<String>,
+    pub screening_deny: Vec<String>,
+    pub screening_allow_patterns: Vec<String>,
+    pub screening_deny_patterns: Vec<String>,
+    pub screening_deny_implicit: bool,
+    pub screening_deny_eth_implicit: bool,
+    pub screening_hook_url: Option<String>,
+    pub screening_hook_timeout_ms: u64,
+    pub screening_hook_fail_open: bool,
+    pub receiver_must_exist: bool,
+    pub receiver_exists_cache_secs: u64,
+    pub webhook_secret: Option<String>, // Loaded from .env, signs deliveries to callback_url
+    pub webhook_timeout_ms: u64,
+    pub webhook_concurrency: usize,
+    pub webhook_max_attempts: u32,
+    pub webhook_retry_base_delay_ms: u64,
+    pub webhook_retry_max_delay_ms: u64,
+    pub webhook_log_ttl_secs: u64,
 }
 
 impl Settings {
@@ -45,14 +294,24 @@ impl Settings {
         let master_key = env::var("NEAR_MASTER_KEY")
             .map_err(|_| "MASTER_KEY not found in environment or .env file")?;
 
+        let key_pool_secret = env::var("KEY_POOL_SECRET").unwrap_or_else(|_| master_key.clone());
+
         let redis_url =
             env::var("REDIS_URL").map_err(|_| "REDIS_URL not found in environment or .env file")?;
+        let database_url = env::var("DATABASE_URL").ok();
+        let admin_api_key = env::var("ADMIN_API_KEY")
+            .ok()
+            .filter(|key| !key.trim().is_empty());
+        let webhook_secret = env::var("WEBHOOK_SECRET")
+            .ok()
+            .filter(|secret| !secret.trim().is_empty());
         // Combine into the final Settings struct
         Ok(Settings {
             rpc_urls: file_settings.rpc_urls,
             ft_contract_id: file_settings.ft_contract_id,
             account_id: file_settings.account_id,
             master_key,
+            key_pool_secret,
             ft_decimals: file_settings.ft_decimals,
             batch_size: file_settings.batch_size,
             batch_timeout_secs: file_settings.batch_timeout_secs,
@@ -61,6 +320,77 @@ impl Settings {
             key_allowance_near: file_settings.key_allowance_near,
             network: file_settings.network,
             redis_url,
+            idempotency_ttl_secs: file_settings.idempotency_ttl_secs,
+            storage_registration: file_settings.storage_registration,
+            max_attempts: file_settings.max_attempts,
+            retry_base_delay_ms: file_settings.retry_base_delay_ms,
+            retry_max_delay_ms: file_settings.retry_max_delay_ms,
+            transfer_ttl_secs: file_settings.transfer_ttl_secs,
+            bulk_max_rows: file_settings.bulk_max_rows,
+            bulk_check_concurrency: file_settings.bulk_check_concurrency.max(1),
+            decimals_mismatch: file_settings.decimals_mismatch,
+            balance_refresh_secs: file_settings.balance_refresh_secs,
+            incomplete_pool: file_settings.incomplete_pool,
+            key_quarantine_failures: file_settings.key_quarantine_failures,
+            key_quarantine_secs: file_settings.key_quarantine_secs,
+            autoscale: file_settings.autoscale,
+            min_pool_keys: file_settings
+                .min_pool_keys
+                .unwrap_or(file_settings.num_pool_keys),
+            max_pool_keys: file_settings
+                .max_pool_keys
+                .unwrap_or(file_settings.num_pool_keys * 4),
+            autoscale_step: file_settings.autoscale_step,
+            autoscale_interval_secs: file_settings.autoscale_interval_secs,
+            autoscale_idle_secs: file_settings.autoscale_idle_secs,
+            key_rotation_secs: file_settings.key_rotation_secs,
+            transaction_store: file_settings.transaction_store,
+            database_url,
+            admin_api_key,
+            key_requests_per_second: file_settings.key_requests_per_second,
+            key_amount_per_hour: parse_amount_limit(
+                "key_amount_per_hour",
+                file_settings.key_amount_per_hour,
+            )?,
+            key_amount_per_day: parse_amount_limit(
+                "key_amount_per_day",
+                file_settings.key_amount_per_day,
+            )?,
+            global_requests_per_second: file_settings.global_requests_per_second,
+            global_amount_per_hour: parse_amount_limit(
+                "global_amount_per_hour",
+                file_settings.global_amount_per_hour,
+            )?,
+            global_amount_per_day: parse_amount_limit(
+                "global_amount_per_day",
+                file_settings.global_amount_per_day,
+            )?,
+            receiver_window_secs: file_settings.receiver_window_secs,
+            receiver_max_transfers: file_settings.receiver_max_transfers,
+            receiver_max_amount: parse_amount_limit(
+                "receiver_max_amount",
+                file_settings.receiver_max_amount,
+            )?,
+            screening_allow: file_settings.screening_allow,
+            screening_deny: file_settings.screening_deny,
+            screening_allow_patterns: file_settings.screening_allow_patterns,
+            screening_deny_patterns: file_settings.screening_deny_patterns,
+            screening_deny_implicit: file_settings.screening_deny_implicit,
+            screening_deny_eth_implicit: file_settings.screening_deny_eth_implicit,
+            screening_hook_url: file_settings
+                .screening_hook_url
+                .filter(|url| !url.trim().is_empty()),
+            screening_hook_timeout_ms: file_settings.screening_hook_timeout_ms,
+            screening_hook_fail_open: file_settings.screening_hook_fail_open,
+            receiver_must_exist: file_settings.receiver_must_exist,
+            receiver_exists_cache_secs: file_settings.receiver_exists_cache_secs,
+            webhook_secret,
+            webhook_timeout_ms: file_settings.webhook_timeout_ms,
+            webhook_concurrency: file_settings.webhook_concurrency.max(1),
+            webhook_max_attempts: file_settings.webhook_max_attempts.max(1),
+            webhook_retry_base_delay_ms: file_settings.webhook_retry_base_delay_ms,
+            webhook_retry_max_delay_ms: file_settings.webhook_retry_max_delay_ms,
+            webhook_log_ttl_secs: file_settings.webhook_log_ttl_secs,
         })
     }
 }
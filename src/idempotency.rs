@@ -0,0 +1,137 @@
+use deadpool_redis::Connection;
+use redis::{AsyncCommands, ExistenceCheck, RedisResult, SetExpiry, SetOptions};
+use serde::{Deserialize, Serialize};
+
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+// How long a claimed key is kept before its record is stored, so a request
+// that dies in between doesn't hold the key for the full TTL.
+const PENDING_TTL_SECS: u64 = 60;
+
+// Marks KEYS[1] stored and keeps it for ARGV[2] seconds, while it is still
+// bound to transaction ARGV[1].
+const CONFIRM_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if not current then
+    return 0
+end
+local entry = cjson.decode(current)
+if entry['transaction_id'] ~= ARGV[1] then
+    return 0
+end
+entry['stored'] = true
+redis.call('SET', KEYS[1], cjson.encode(entry), 'EX', ARGV[2])
+return 1
+";
+
+// Deletes KEYS[1] while it is still bound to transaction ARGV[1].
+const RELEASE_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if current and cjson.decode(current)['transaction_id'] == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+// What we remember about a request that carried an idempotency key.
+#[derive(Serialize, Deserialize)]
+struct IdempotencyEntry {
+    transaction_id: String,
+    fingerprint: String,
+    /// Whether the transfer's record was stored. Entries from before this
+    /// was tracked were only kept once it was.
+    #[serde(default = "stored_by_default")]
+    stored: bool,
+}
+
+fn stored_by_default() -> bool {
+    true
+}
+
+pub enum Claim {
+    /// First time this key is seen; the caller owns it and must create the
+    /// record, then `confirm` it.
+    New,
+    /// The key was used before with the same body; reply with the original
+    /// ID. `stored` says whether that transfer's record was stored, rather
+    /// than still being accepted.
+    Replay { transaction_id: String, stored: bool },
+    /// The key was used before with a different body.
+    Mismatch,
+}
+
+fn entry_key(idempotency_key: &str) -> String {
+    format!("idempotency:{}", idempotency_key)
+}
+
+/// Tries to bind `idempotency_key` to `transaction_id`. The claim lapses
+/// after a minute unless it is confirmed.
+///
+/// `fingerprint` identifies the request body so a retried key can be told
+/// apart from a key reused for a different transfer.
+pub async fn claim(
+    conn: &mut Connection,
+    idempotency_key: &str,
+    fingerprint: &str,
+    transaction_id: &str,
+) -> RedisResult<Claim> {
+    let entry = serde_json::to_string(&IdempotencyEntry {
+        transaction_id: transaction_id.to_string(),
+        fingerprint: fingerprint.to_string(),
+        stored: false,
+    })
+    .unwrap_or_default();
+
+    loop {
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::EX(PENDING_TTL_SECS));
+        let set: Option<String> = conn
+            .set_options(entry_key(idempotency_key), &entry, options)
+            .await?;
+        if set.is_some() {
+            return Ok(Claim::New);
+        }
+
+        let existing: Option<String> = conn.get(entry_key(idempotency_key)).await?;
+        // The key expired between SET and GET; try to claim it again.
+        let Some(existing) = existing else { continue };
+        return match serde_json::from_str::<IdempotencyEntry>(&existing) {
+            Ok(entry) if entry.fingerprint == fingerprint => Ok(Claim::Replay {
+                transaction_id: entry.transaction_id,
+                stored: entry.stored,
+            }),
+            _ => Ok(Claim::Mismatch),
+        };
+    }
+}
+
+/// Keeps a claimed key for `ttl_secs` once the record of `transaction_id`
+/// is stored.
+pub async fn confirm(
+    conn: &mut Connection,
+    idempotency_key: &str,
+    transaction_id: &str,
+    ttl_secs: u64,
+) -> RedisResult<()> {
+    redis::Script::new(CONFIRM_SCRIPT)
+        .key(entry_key(idempotency_key))
+        .arg(transaction_id)
+        .arg(ttl_secs)
+        .invoke_async(conn)
+        .await
+}
+
+/// Forgets a key bound to `transaction_id`, used when that transfer could
+/// not be stored or its record is gone.
+pub async fn release(
+    conn: &mut Connection,
+    idempotency_key: &str,
+    transaction_id: &str,
+) -> RedisResult<()> {
+    redis::Script::new(RELEASE_SCRIPT)
+        .key(entry_key(idempotency_key))
+        .arg(transaction_id)
+        .invoke_async(conn)
+        .await
+}
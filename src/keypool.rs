@@ -0,0 +1,376 @@
+use crate::retry;
+use crate::types::{KeyHealth, KeyState};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use near_api::errors::ExecuteTransactionError;
+use near_api::near_primitives::views::{FinalExecutionOutcomeView, FinalExecutionStatus};
+use near_api::{Account, NetworkConfig, Signer};
+use near_crypto::{PublicKey, SecretKey};
+use near_sdk::AccountId;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+
+#[derive(Default)]
+struct KeyStats {
+    state: KeyState,
+    successes: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    last_nonce: Option<u64>,
+    last_error: Option<String>,
+    last_used_at: Option<DateTime<Utc>>,
+    quarantined_until: Option<DateTime<Utc>>,
+}
+
+/// One access key of the pool with its own `Signer`, so its nonce cache can
+/// be thrown away without touching the other keys.
+pub struct PoolKey {
+    public_key: PublicKey,
+    secret_key: SecretKey,
+    signer: RwLock<Arc<Signer>>,
+    stats: Mutex<KeyStats>,
+}
+
+impl PoolKey {
+    pub async fn signer(&self) -> Arc<Signer> {
+        Arc::clone(&*self.signer.read().await)
+    }
+}
+
+/// The access keys batches are signed with. Keys are handed out round-robin
+/// like near-api's own pool, but skip keys that keep failing: those are
+/// quarantined and only come back once `view_access_key` shows they still
+/// exist, with a fresh nonce.
+///
+/// The pool also owns the permits that cap how many batches are in flight,
+/// so concurrency can follow the pool size when it is resized.
+pub struct KeyPool {
+    account_id: AccountId,
+    network_config: NetworkConfig,
+    keys: RwLock<Vec<Arc<PoolKey>>>,
+    next: AtomicUsize,
+    quarantine_after: u32,
+    quarantine_secs: u64,
+    permits: Arc<Semaphore>,
+    concurrency: AtomicUsize,
+    // Permits a lowered concurrency still has to take out once running
+    // batches hand them back.
+    surplus: Mutex<usize>,
+    waiting: AtomicUsize,
+}
+
+impl KeyPool {
+    pub fn new(
+        account_id: AccountId,
+        network_config: NetworkConfig,
+        quarantine_after: u32,
+        quarantine_secs: u64,
+        concurrency: usize,
+    ) -> Self {
+        Self {
+            account_id,
+            network_config,
+            keys: RwLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+            quarantine_after: quarantine_after.max(1),
+            quarantine_secs,
+            permits: Arc::new(Semaphore::new(concurrency)),
+            concurrency: AtomicUsize::new(concurrency),
+            surplus: Mutex::new(0),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Waits for a free batch slot. Batches waiting here are what tells the
+    /// autoscaler the pool is too small.
+    pub async fn acquire_permit(&self) -> OwnedSemaphorePermit {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let permit = loop {
+            let permit = Arc::clone(&self.permits).acquire_owned().await.unwrap();
+            let mut surplus = self.surplus.lock().unwrap();
+            if *surplus == 0 {
+                break permit;
+            }
+            *surplus -= 1;
+            permit.forget();
+        };
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        permit
+    }
+
+    /// Number of batches waiting for a slot.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// Number of batches being sent right now.
+    pub fn in_flight(&self) -> usize {
+        let surplus = *self.surplus.lock().unwrap();
+        (self.concurrency() + surplus).saturating_sub(self.permits.available_permits())
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.load(Ordering::SeqCst)
+    }
+
+    /// Changes how many batches may be in flight. Lowering it takes free
+    /// permits out at once and the rest as running batches hand them back;
+    /// raising it first cancels what a lowering still had to take out.
+    pub fn set_concurrency(&self, concurrency: usize) {
+        let mut surplus = self.surplus.lock().unwrap();
+        let current = self.concurrency.swap(concurrency, Ordering::SeqCst);
+        if concurrency > current {
+            let raise = concurrency - current;
+            let cancelled = raise.min(*surplus);
+            *surplus -= cancelled;
+            self.permits.add_permits(raise - cancelled);
+        } else if concurrency < current {
+            *surplus += current - concurrency;
+            *surplus -= self.permits.forget_permits(*surplus);
+        }
+    }
+
+    /// Number of keys that are part of the pool, quarantined ones included.
+    pub async fn size(&self) -> usize {
+        self.keys
+            .read()
+            .await
+            .iter()
+            .filter(|key| {
+                matches!(
+                    key.stats.lock().unwrap().state,
+                    KeyState::Active | KeyState::Quarantined
+                )
+            })
+            .count()
+    }
+
+    /// Takes `count` keys out of rotation, quarantined ones first, and
+    /// returns their secret keys so they can be deleted from the account.
+    pub async fn retire(&self, count: usize) -> Vec<SecretKey> {
+        let keys = self.keys.read().await;
+        let mut candidates: Vec<&Arc<PoolKey>> = keys
+            .iter()
+            .filter(|key| {
+                matches!(
+                    key.stats.lock().unwrap().state,
+                    KeyState::Active | KeyState::Quarantined
+                )
+            })
+            .collect();
+        candidates.sort_by_key(|key| key.stats.lock().unwrap().state != KeyState::Quarantined);
+
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|key| {
+                let mut stats = key.stats.lock().unwrap();
+                stats.state = KeyState::Retired;
+                stats.quarantined_until = None;
+                key.secret_key.clone()
+            })
+            .collect()
+    }
+
+    /// Drops keys that were deleted from the account.
+    pub async fn forget(&self, public_keys: &[PublicKey]) {
+        self.keys
+            .write()
+            .await
+            .retain(|key| !public_keys.contains(&key.public_key));
+    }
+
+    pub async fn add(&self, secret_key: SecretKey) -> Result<(), near_api::errors::SignerError> {
+        let signer = Signer::new(Signer::from_secret_key(secret_key.clone()))?;
+        self.keys.write().await.push(Arc::new(PoolKey {
+            public_key: secret_key.public_key(),
+            secret_key,
+            signer: RwLock::new(signer),
+            stats: Mutex::new(KeyStats::default()),
+        }));
+        Ok(())
+    }
+
+    /// The next active key, or `None` when every key is quarantined or removed.
+    pub async fn acquire(&self) -> Option<Arc<PoolKey>> {
+        let keys = self.keys.read().await;
+        if keys.is_empty() {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..keys.len())
+            .map(|offset| &keys[(start + offset) % keys.len()])
+            .find(|key| key.stats.lock().unwrap().state == KeyState::Active)
+            .cloned()
+    }
+
+    /// Updates the key's stats with the outcome of a transaction it signed.
+    /// Contract failures count as a success for the key: the transaction
+    /// went through. A nonce or access key error quarantines the key at
+    /// once; other failures after `quarantine_after` in a row.
+    pub fn record_result(
+        &self,
+        key: &PoolKey,
+        result: &Result<FinalExecutionOutcomeView, ExecuteTransactionError>,
+    ) {
+        let failure = match result {
+            Ok(outcome) => match &outcome.status {
+                FinalExecutionStatus::Failure(error) if retry::is_key_execution_error(error) => {
+                    Some((format!("{:?}", error), true))
+                }
+                _ => None,
+            },
+            Err(e) => Some((e.to_string(), retry::is_key_send_error(e))),
+        };
+
+        let mut stats = key.stats.lock().unwrap();
+        stats.last_used_at = Some(Utc::now());
+        match failure {
+            None => {
+                stats.successes += 1;
+                stats.consecutive_failures = 0;
+                if let Ok(outcome) = result {
+                    stats.last_nonce = Some(outcome.transaction.nonce);
+                }
+            }
+            Some((message, key_error)) => {
+                stats.failures += 1;
+                stats.consecutive_failures += 1;
+                stats.last_error = Some(message);
+                if stats.state == KeyState::Active
+                    && (key_error || stats.consecutive_failures >= self.quarantine_after)
+                {
+                    stats.state = KeyState::Quarantined;
+                    stats.quarantined_until =
+                        Some(Utc::now() + chrono::Duration::seconds(self.quarantine_secs as i64));
+                    warn!(
+                        "Quarantined pool key {} after {} consecutive failures: {}",
+                        key.public_key,
+                        stats.consecutive_failures,
+                        stats.last_error.as_deref().unwrap_or_default()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Checks quarantined keys whose quarantine is over. Keys that are still
+    /// on the account get a new `Signer`, which drops the stale nonce cache,
+    /// and go back into rotation; keys that are gone are marked removed.
+    pub async fn recover_quarantined(&self) {
+        let due: Vec<Arc<PoolKey>> = self
+            .keys
+            .read()
+            .await
+            .iter()
+            .filter(|key| {
+                let stats = key.stats.lock().unwrap();
+                stats.state == KeyState::Quarantined
+                    && stats.quarantined_until.is_none_or(|until| until <= Utc::now())
+            })
+            .cloned()
+            .collect();
+
+        for key in due {
+            let access_key = Account(self.account_id.clone())
+                .access_key(key.public_key.clone())
+                .fetch_from(&self.network_config)
+                .await;
+            match access_key {
+                Ok(access_key) => {
+                    let signer = match Signer::new(Signer::from_secret_key(key.secret_key.clone())) {
+                        Ok(signer) => signer,
+                        Err(e) => {
+                            warn!("Could not recreate signer for {}: {}", key.public_key, e);
+                            continue;
+                        }
+                    };
+                    *key.signer.write().await = signer;
+                    let mut stats = key.stats.lock().unwrap();
+                    stats.state = KeyState::Active;
+                    stats.consecutive_failures = 0;
+                    stats.quarantined_until = None;
+                    stats.last_nonce = Some(access_key.data.nonce);
+                    info!(
+                        "Pool key {} back in rotation at nonce {}.",
+                        key.public_key, access_key.data.nonce
+                    );
+                }
+                Err(e) if retry::is_unknown_access_key(&e) => {
+                    let mut stats = key.stats.lock().unwrap();
+                    stats.state = KeyState::Removed;
+                    stats.quarantined_until = None;
+                    stats.last_error = Some(e.to_string());
+                    warn!("Pool key {} is no longer on the account; removing it.", key.public_key);
+                }
+                // Try again on the next pass.
+                Err(e) => warn!("Could not check quarantined key {}: {}", key.public_key, e),
+            }
+        }
+    }
+
+    pub async fn health(&self) -> Vec<KeyHealth> {
+        self.keys
+            .read()
+            .await
+            .iter()
+            .map(|key| {
+                let stats = key.stats.lock().unwrap();
+                KeyHealth {
+                    public_key: key.public_key.to_string(),
+                    state: stats.state,
+                    successes: stats.successes,
+                    failures: stats.failures,
+                    consecutive_failures: stats.consecutive_failures,
+                    last_nonce: stats.last_nonce,
+                    last_error: stats.last_error.clone(),
+                    last_used_at: stats.last_used_at,
+                    quarantined_until: stats.quarantined_until,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(concurrency: usize) -> KeyPool {
+        KeyPool::new("sender.testnet".parse().unwrap(), NetworkConfig::testnet(), 3, 60, concurrency)
+    }
+
+    #[actix_web::test]
+    async fn raising_concurrency_cancels_a_pending_lowering() {
+        let pool = pool(2);
+        let first = pool.acquire_permit().await;
+        let second = pool.acquire_permit().await;
+
+        // Both permits are out, so the lowering has to wait for them.
+        pool.set_concurrency(1);
+        pool.set_concurrency(3);
+        drop(first);
+        drop(second);
+
+        assert_eq!(pool.concurrency(), 3);
+        assert_eq!(pool.in_flight(), 0);
+        assert_eq!(pool.permits.available_permits(), 3);
+    }
+
+    #[actix_web::test]
+    async fn lowering_concurrency_takes_permits_as_they_come_back() {
+        let pool = pool(3);
+        let first = pool.acquire_permit().await;
+        let second = pool.acquire_permit().await;
+
+        pool.set_concurrency(1);
+        assert_eq!(pool.in_flight(), 2);
+        drop(first);
+        drop(second);
+
+        let _permit = pool.acquire_permit().await;
+        assert_eq!(pool.in_flight(), 1);
+        assert_eq!(pool.permits.available_permits(), 0);
+    }
+}
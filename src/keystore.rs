@@ -0,0 +1,250 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use deadpool_redis::Connection;
+use log::{info, warn};
+use near_api::near_primitives::account::{AccessKey, AccessKeyPermission};
+use near_api::near_primitives::transaction::{Action, AddKeyAction};
+use near_api::near_primitives::views::FinalExecutionStatus;
+use near_api::signer::generate_secret_key;
+use near_api::{Account, NetworkConfig, Signer, Transaction};
+use near_crypto::{PublicKey, SecretKey};
+use near_sdk::AccountId;
+use redis::{AsyncCommands, RedisResult};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+// Hash of public key -> encrypted secret key for every pool key this service
+// has added to the account.
+const KEY_POOL_KEY: &str = "key_pool";
+const NONCE_LEN: usize = 12;
+// AddKey actions per provisioning transaction, well under the protocol's
+// limit of 100 actions per transaction.
+const ADD_KEYS_PER_TRANSACTION: usize = 50;
+
+// What to do when fewer than `num_pool_keys` keys could be provisioned.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IncompletePoolMode {
+    /// Refuse to start.
+    Fail,
+    /// Start with the keys that were provisioned.
+    Degraded,
+}
+
+/// Keeps the pool's secret keys in Redis, encrypted with AES-256-GCM, so
+/// they can be reused after a restart instead of adding new keys every time.
+pub struct KeyStore {
+    cipher: Aes256Gcm,
+}
+
+impl KeyStore {
+    pub fn new(secret: &str) -> Self {
+        let key = Sha256::new()
+            .chain_update(b"nearn_ft key pool:")
+            .chain_update(secret.as_bytes())
+            .finalize();
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        }
+    }
+
+    /// Returns every stored key. Entries that don't decrypt (e.g. after the
+    /// secret changed) are skipped with a warning.
+    pub async fn load(&self, conn: &mut Connection) -> RedisResult<Vec<SecretKey>> {
+        let entries: HashMap<String, String> = conn.hgetall(KEY_POOL_KEY).await?;
+        let mut keys = Vec::with_capacity(entries.len());
+        for (public_key, encrypted) in entries {
+            match self.decrypt(&encrypted) {
+                Some(secret_key) => keys.push(secret_key),
+                None => warn!("Could not decrypt stored pool key {}; ignoring it.", public_key),
+            }
+        }
+        Ok(keys)
+    }
+
+    pub async fn save(&self, conn: &mut Connection, secret_key: &SecretKey) -> RedisResult<()> {
+        conn.hset(
+            KEY_POOL_KEY,
+            secret_key.public_key().to_string(),
+            self.encrypt(secret_key),
+        )
+        .await
+    }
+
+    pub async fn remove(&self, conn: &mut Connection, public_keys: &[PublicKey]) -> RedisResult<()> {
+        if public_keys.is_empty() {
+            return Ok(());
+        }
+        let fields: Vec<String> = public_keys.iter().map(|key| key.to_string()).collect();
+        conn.hdel(KEY_POOL_KEY, fields).await
+    }
+
+    fn encrypt(&self, secret_key: &SecretKey) -> String {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, secret_key.to_string().as_bytes())
+            .expect("AES-GCM encryption failed");
+        nonce
+            .iter()
+            .chain(ciphertext.iter())
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn decrypt(&self, encrypted: &str) -> Option<SecretKey> {
+        if !encrypted.len().is_multiple_of(2) || encrypted.len() < NONCE_LEN * 2 {
+            return None;
+        }
+        let bytes = (0..encrypted.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&encrypted[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        String::from_utf8(plaintext).ok()?.parse().ok()
+    }
+}
+
+/// Picks up to `wanted` stored keys that are still on the account. Stored
+/// keys that are gone from the account are forgotten, and stored keys beyond
+/// `wanted` are deleted from the account as orphans.
+pub async fn restore_pool_keys(
+    store: &KeyStore,
+    conn: &mut Connection,
+    account_id: &AccountId,
+    wanted: usize,
+    master_signer: &Arc<Signer>,
+    network_config: &NetworkConfig,
+) -> Result<Vec<SecretKey>, Box<dyn std::error::Error + Send + Sync>> {
+    let stored = store.load(conn).await?;
+    if stored.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let on_chain: HashSet<PublicKey> = Account(account_id.clone())
+        .list_keys()
+        .fetch_from(network_config)
+        .await?
+        .keys
+        .into_iter()
+        .map(|key| key.public_key)
+        .collect();
+
+    let (mut live, gone): (Vec<SecretKey>, Vec<SecretKey>) = stored
+        .into_iter()
+        .partition(|key| on_chain.contains(&key.public_key()));
+    if !gone.is_empty() {
+        warn!("{} stored pool keys are no longer on the account; forgetting them.", gone.len());
+        let gone: Vec<PublicKey> = gone.iter().map(SecretKey::public_key).collect();
+        store.remove(conn, &gone).await?;
+    }
+
+    let orphaned = live.split_off(live.len().min(wanted));
+    delete_orphaned_keys(store, conn, account_id, &orphaned, master_signer, network_config).await;
+    Ok(live)
+}
+
+/// Deletes pool keys this service added but no longer uses, in a single
+/// transaction, and forgets them once they are gone from the account.
+pub async fn delete_orphaned_keys(
+    store: &KeyStore,
+    conn: &mut Connection,
+    account_id: &AccountId,
+    orphaned: &[SecretKey],
+    master_signer: &Arc<Signer>,
+    network_config: &NetworkConfig,
+) {
+    if orphaned.is_empty() {
+        return;
+    }
+    let public_keys: Vec<PublicKey> = orphaned.iter().map(SecretKey::public_key).collect();
+    let result = Account(account_id.clone())
+        .delete_keys(public_keys.clone())
+        .with_signer(Arc::clone(master_signer))
+        .send_to(network_config)
+        .await;
+    match result {
+        Ok(outcome) if matches!(outcome.status, FinalExecutionStatus::SuccessValue(_)) => {
+            info!("Deleted {} unused pool keys from the account.", public_keys.len());
+            if let Err(e) = store.remove(conn, &public_keys).await {
+                warn!("Failed to forget deleted pool keys: {}", e);
+            }
+        }
+        // The keys stay stored, so the next start tries again.
+        Ok(outcome) => warn!("Deleting unused pool keys failed: {:?}", outcome.status),
+        Err(e) => warn!("Deleting unused pool keys failed: {}", e),
+    }
+}
+
+/// Generates `count` keys and adds them to the account with a few
+/// multi-action transactions signed by the master key, sent one after the
+/// other so they don't race for its nonce. Keys are stored before they are
+/// sent; afterwards the account's key list is checked and only the keys
+/// that actually landed are returned.
+pub async fn add_pool_keys(
+    store: &KeyStore,
+    conn: &mut Connection,
+    account_id: &AccountId,
+    count: usize,
+    master_signer: &Arc<Signer>,
+    network_config: &NetworkConfig,
+) -> Result<Vec<SecretKey>, Box<dyn std::error::Error + Send + Sync>> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    let mut new_keys = Vec::with_capacity(count);
+    for _ in 0..count {
+        let secret_key = generate_secret_key()?;
+        store.save(conn, &secret_key).await?;
+        new_keys.push(secret_key);
+    }
+
+    for chunk in new_keys.chunks(ADD_KEYS_PER_TRANSACTION) {
+        // Full-access; the README explains why function-call keys won't do.
+        let actions = chunk
+            .iter()
+            .map(|secret_key| {
+                Action::AddKey(Box::new(AddKeyAction {
+                    public_key: secret_key.public_key(),
+                    access_key: AccessKey {
+                        nonce: 0,
+                        permission: AccessKeyPermission::FullAccess,
+                    },
+                }))
+            })
+            .collect();
+        let result = Transaction::construct(account_id.clone(), account_id.clone())
+            .add_actions(actions)
+            .with_signer(Arc::clone(master_signer))
+            .send_to(network_config)
+            .await;
+        match result {
+            Ok(outcome) if matches!(outcome.status, FinalExecutionStatus::SuccessValue(_)) => {
+                info!("Added {} pool keys in {}.", chunk.len(), outcome.transaction.hash);
+            }
+            Ok(outcome) => warn!("Adding {} pool keys failed: {:?}", chunk.len(), outcome.status),
+            Err(e) => warn!("Adding {} pool keys failed: {}", chunk.len(), e),
+        }
+    }
+
+    let on_chain: HashSet<PublicKey> = Account(account_id.clone())
+        .list_keys()
+        .fetch_from(network_config)
+        .await?
+        .keys
+        .into_iter()
+        .map(|key| key.public_key)
+        .collect();
+    let (added, missing): (Vec<SecretKey>, Vec<SecretKey>) = new_keys
+        .into_iter()
+        .partition(|key| on_chain.contains(&key.public_key()));
+    let missing: Vec<PublicKey> = missing.iter().map(SecretKey::public_key).collect();
+    store.remove(conn, &missing).await?;
+    Ok(added)
+}
@@ -1,16 +1,45 @@
+pub mod accounts;
+pub mod amount;
+pub mod auth;
+pub mod autoscale;
+pub mod balance;
+pub mod campaign;
 pub mod config;
+pub mod idempotency;
+pub mod keypool;
+pub mod keystore;
+pub mod payouts;
+pub mod queue;
+pub mod ratelimit;
+pub mod records;
+pub mod retry;
+pub mod screening;
+pub mod storage;
+pub mod store;
+pub mod token;
 pub mod types;
+pub mod validation;
+pub mod webhooks;
 pub mod worker;
 
-use actix_web::{get, post, web::{Data, Json}, HttpResponse, Responder};
-use actix_web::web::{Path, Query};
+use actix_web::{get, post, web::{Data, Json}, HttpRequest, HttpResponse, Responder};
+use actix_web::http::{StatusCode, header};
+use actix_web::web::{Bytes, Path, Query};
 use deadpool_redis::Pool;
-use log::error;
-use redis::AsyncCommands;
-use tokio::sync::mpsc::Sender;
+use futures::StreamExt;
+use std::collections::HashMap;
+use log::{error, warn};
 use types::*;
-use utoipa::OpenApi;
+use utoipa::{Modify, OpenApi};
+use utoipa::openapi::security::{ApiKeyValue, SecurityScheme};
+use crate::accounts::AccountChecker;
+use crate::autoscale::PoolManager;
+use crate::balance::BalanceTracker;
 use crate::config::Settings;
+use crate::keypool::KeyPool;
+use crate::screening::Screener;
+use crate::store::TransactionStore;
+use crate::token::TokenMetadata;
 
 #[derive(OpenApi)]
 #[openapi(
@@ -19,101 +48,1592 @@ use crate::config::Settings;
         get_transaction_by_id,
         get_transactions_by_receiver,
         get_all_transactions,
-        get_transactions_by_status
+        get_transactions_by_status,
+        cancel_transaction,
+        bulk_transfer,
+        get_campaign,
+        get_campaign_transactions,
+        pause_campaign,
+        resume_campaign,
+        cancel_campaign,
+        get_token,
+        get_balance,
+        get_admin_keys,
+        resize_key_pool,
+        create_api_key,
+        get_api_keys,
+        revoke_api_key,
+        get_screening_lists,
+        update_screening_list,
+        create_webhook,
+        get_webhooks,
+        get_callback_secret,
+        remove_webhook,
+        get_webhook_deliveries,
+        get_transaction_deliveries
     ),
     components(schemas(
         TokenTransferRequest,
         TransactionRecord,
         TransactionStatus,
+        TransferAttempt,
+        StatusChange,
         TransferResponse,
         PaginatedTransactionResponse,
         Pagination,
-        ScanPagination
+        ScanPagination,
+        Campaign,
+        CampaignStatus,
+        CampaignProgress,
+        BulkTransferRequest,
+        BulkRowResult,
+        BulkTransferResponse,
+        TokenMetadata,
+        BalanceResponse,
+        KeyState,
+        KeyHealth,
+        ResizePoolRequest,
+        ResizePoolResponse,
+        ApiKey,
+        ApiKeyScope,
+        ClientLimits,
+        CreateApiKeyRequest,
+        CreateApiKeyResponse,
+        ScreeningDecision,
+        ScreeningVerdict,
+        ScreeningList,
+        ScreeningLists,
+        ScreeningListsResponse,
+        UpdateScreeningListRequest,
+        Webhook,
+        CreateWebhookRequest,
+        CreateWebhookResponse,
+        CallbackSecretResponse,
+        WebhookEvent,
+        DeliveryStatus,
+        DeliveryAttempt,
+        WebhookDelivery
     )),
+    modifiers(&SecurityAddon),
+    security(("api_key" = [])),
     tags(
         (name = "NEAR FT Transfer Service", description = "Endpoints for a high-throughput FT transfer service")
     )
 )]
 pub struct ApiDoc;
 
+// Lets Swagger UI send the `X-API-Key` header.
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(utoipa::openapi::security::ApiKey::Header(ApiKeyValue::new(
+                auth::API_KEY_HEADER,
+            ))),
+        );
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/transfer",
+    request_body = TokenTransferRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Optional key that makes retries of the same request return the original transaction")
+    ),
+    responses(
+        (status = 202, description = "Transfer request accepted for processing", body = TransferResponse),
+        (status = 400, description = "Invalid input, e.g. a receiver_id that isn't a valid account ID, a callback_url that isn't an http(s) URL or, with receiver_must_exist, a named account that doesn't exist", body = TransferResponse),
+        (status = 409, description = "Idempotency key was already used with a different request body or its first request is still being accepted, the receiver was already paid for the dedupe_key (transaction_id is that transfer), or another transfer to the receiver is being accepted", body = TransferResponse),
+        (status = 422, description = "The receiver was rejected by screening, the sender's available balance can't cover the amount, or the receiver is at its cap for the window", body = TransferResponse),
+        (status = 429, description = "Over a request-rate limit or amount quota; see Retry-After and the X-RateLimit-* headers", body = TransferResponse),
+        (status = 500, description = "Internal server error", body = TransferResponse),
+        (status = 503, description = "The screening hook or, with receiver_must_exist, the RPC could not be reached", body = TransferResponse)
+    )
+)]
+#[post("/transfer")]
+#[allow(clippy::too_many_arguments)]
+pub async fn ft_transfer(
+    http_request: HttpRequest,
+    payload: Json<TokenTransferRequest>,
+    settings: Data<Settings>,
+    redis_pool: Data<Pool>,
+    store: Data<dyn TransactionStore>,
+    balance: Data<BalanceTracker>,
+    screener: Data<Screener>,
+    accounts: Data<AccountChecker>,
+) -> impl Responder {
+    let mut request = payload.into_inner();
+    let amount = match validation::normalize_transfer(&mut request, settings.ft_decimals)
+        .and_then(|amount| webhooks::check_callback_url(&settings, &request).map(|()| amount))
+    {
+        Ok(amount) => amount,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(TransferResponse {
+                success: false,
+                message,
+                transaction_id: String::new(),
+            });
+        }
+    };
+    let mut record = TransactionRecord::new(
+        settings.account_id.clone(),
+        request.clone(),
+        settings.ft_decimals,
+    );
+    let api_key = auth::key(&http_request);
+    record.api_key_id = api_key.as_ref().map(|key| key.id.clone());
+    let record_id = record.id.clone();
+    let limits = ratelimit::limits_for(&settings, api_key.as_ref());
+
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().json(TransferResponse {
+                success: false,
+                message: "Failed to queue transfer request.".to_string(),
+                transaction_id: record_id,
+            });
+        }
+    };
+
+    // Scoped to the API key so two clients can't collide on the same value.
+    let idempotency_key = http_request
+        .headers()
+        .get(idempotency::IDEMPOTENCY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|key| match &record.api_key_id {
+            Some(api_key_id) => format!("{}:{}", api_key_id, key),
+            None => key.to_string(),
+        });
+
+    if let Some(key) = &idempotency_key {
+        let fingerprint = serde_json::to_string(&request).unwrap_or_default();
+        if let Err(response) =
+            claim_idempotency_key(&mut conn, store.get_ref(), key, &fingerprint, &record_id).await
+        {
+            return response;
+        }
+    }
+
+    // Checked after the idempotency key, so a retry of an accepted request
+    // gets its original answer even when it would now be limited.
+    let response = admit_transfer(
+        &mut conn,
+        &settings,
+        store.get_ref(),
+        &balance,
+        &screener,
+        &accounts,
+        &limits,
+        record,
+        amount,
+    )
+    .await;
+    if let Some(key) = &idempotency_key {
+        if response.status().is_success() {
+            let confirmed =
+                idempotency::confirm(&mut conn, key, &record_id, settings.idempotency_ttl_secs)
+                    .await;
+            if let Err(e) = confirmed {
+                error!("Failed to keep Idempotency-Key for {}: {}", record_id, e);
+            }
+        } else {
+            let _ = idempotency::release(&mut conn, key, &record_id).await;
+        }
+    }
+    response
+}
+
+// Binds the Idempotency-Key to the new transfer, or returns the response to
+// a request that already used it. A key whose transfer was stored but is
+// gone from the store is taken over; one whose transfer is still being
+// accepted is not, since it may yet be stored.
+async fn claim_idempotency_key(
+    conn: &mut deadpool_redis::Connection,
+    store: &dyn TransactionStore,
+    key: &str,
+    fingerprint: &str,
+    record_id: &str,
+) -> Result<(), HttpResponse> {
+    loop {
+        let claim = idempotency::claim(conn, key, fingerprint, record_id)
+            .await
+            .map_err(|e| {
+                error!("Redis idempotency check error: {}", e);
+                transfer_failed(record_id.to_string())
+            })?;
+        let (transaction_id, stored) = match claim {
+            idempotency::Claim::New => return Ok(()),
+            idempotency::Claim::Replay {
+                transaction_id,
+                stored,
+            } => (transaction_id, stored),
+            idempotency::Claim::Mismatch => {
+                return Err(HttpResponse::Conflict().json(TransferResponse {
+                    success: false,
+                    message: "Idempotency-Key was already used with a different request."
+                        .to_string(),
+                    transaction_id: String::new(),
+                }));
+            }
+        };
+        match store.get(&transaction_id).await {
+            Ok(Some(_)) => {
+                return Err(HttpResponse::Accepted().json(TransferResponse {
+                    success: true,
+                    message: "Transfer request accepted and queued for processing.".to_string(),
+                    transaction_id,
+                }));
+            }
+            Ok(None) if stored => {
+                warn!(
+                    "Transaction {} of an Idempotency-Key is gone; claiming the key again.",
+                    transaction_id
+                );
+                if let Err(e) = idempotency::release(conn, key, &transaction_id).await {
+                    error!("Redis idempotency release error: {}", e);
+                    return Err(transfer_failed(record_id.to_string()));
+                }
+            }
+            Ok(None) => {
+                return Err(HttpResponse::Conflict().json(TransferResponse {
+                    success: false,
+                    message: "A request with this Idempotency-Key is still being accepted; try again shortly."
+                        .to_string(),
+                    transaction_id: String::new(),
+                }));
+            }
+            Err(e) => {
+                error!("Failed to read transaction {}: {}", transaction_id, e);
+                return Err(transfer_failed(record_id.to_string()));
+            }
+        }
+    }
+}
+
+// Runs the checks a new transfer has to pass and accepts it, or returns the
+// response turning it away.
+#[allow(clippy::too_many_arguments)]
+async fn admit_transfer(
+    conn: &mut deadpool_redis::Connection,
+    settings: &Settings,
+    store: &dyn TransactionStore,
+    balance: &BalanceTracker,
+    screener: &Screener,
+    accounts: &AccountChecker,
+    limits: &[ratelimit::Limit],
+    mut record: TransactionRecord,
+    amount: u128,
+) -> HttpResponse {
+    match ratelimit::check_request(conn, limits).await {
+        Ok(Ok(())) => {}
+        Ok(Err(exceeded)) => return rate_limited(&exceeded),
+        Err(e) => {
+            error!("Redis rate limit error: {}", e);
+            return transfer_failed(record.id);
+        }
+    }
+
+    if let Err((status, message)) =
+        check_receiver_exists(settings, accounts, &record.request.reciever_id).await
+    {
+        return HttpResponse::build(status).json(TransferResponse {
+            success: false,
+            message,
+            transaction_id: String::new(),
+        });
+    }
+
+    match screen_receiver(conn, screener, &mut record).await {
+        Ok(()) => accept_transfer(conn, settings, store, balance, limits, record, amount).await,
+        Err(response) => response,
+    }
+}
+
+// Whether `key` may see or act on what was submitted with `api_key_id`:
+// admin keys anything, other keys only their own.
+fn owns(key: Option<&ApiKey>, api_key_id: Option<&str>) -> bool {
+    key.is_some_and(|key| key.allows(ApiKeyScope::Admin) || api_key_id == Some(key.id.as_str()))
+}
+
+// The records of a listing the request's key may see.
+fn visible(http_request: &HttpRequest, records: Vec<TransactionRecord>) -> Vec<TransactionRecord> {
+    let key = auth::key(http_request);
+    records
+        .into_iter()
+        .filter(|record| owns(key.as_ref(), record.api_key_id.as_deref()))
+        .collect()
+}
+
+fn transfer_failed(transaction_id: String) -> HttpResponse {
+    HttpResponse::InternalServerError().json(TransferResponse {
+        success: false,
+        message: "Failed to queue transfer request.".to_string(),
+        transaction_id,
+    })
+}
+
+// With `receiver_must_exist`, rejects receivers that are named accounts not
+// on chain. The error is the status and message to reject the transfer with.
+async fn check_receiver_exists(
+    settings: &Settings,
+    accounts: &AccountChecker,
+    receiver_id: &str,
+) -> Result<(), (StatusCode, String)> {
+    if !settings.receiver_must_exist {
+        return Ok(());
+    }
+    let account_id = validation::parse_account_id(receiver_id)
+        .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+    match accounts.exists(&account_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "receiver_id {} does not exist on {}",
+                receiver_id, settings.network
+            ),
+        )),
+        Err(e) => {
+            error!("Failed to look up account {}: {}", receiver_id, e);
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("Could not check that {} exists; try again later.", receiver_id),
+            ))
+        }
+    }
+}
+
+// Screens the receiver and keeps the verdict on the record, or returns the
+// response turning the transfer away.
+async fn screen_receiver(
+    conn: &mut deadpool_redis::Connection,
+    screener: &Screener,
+    record: &mut TransactionRecord,
+) -> Result<(), HttpResponse> {
+    match screener.screen(conn, record).await {
+        Ok(Ok(verdict)) if verdict.is_allowed() => {
+            record.screening = Some(verdict);
+            Ok(())
+        }
+        Ok(Ok(verdict)) => Err(HttpResponse::UnprocessableEntity().json(TransferResponse {
+            success: false,
+            message: screening::rejection_message(&record.request.reciever_id, &verdict),
+            transaction_id: String::new(),
+        })),
+        Ok(Err(message)) => {
+            error!("Screening {} failed: {}", record.request.reciever_id, message);
+            Err(HttpResponse::ServiceUnavailable().json(TransferResponse {
+                success: false,
+                message: "Receiver screening is unavailable; try again later.".to_string(),
+                transaction_id: String::new(),
+            }))
+        }
+        Err(e) => {
+            error!("Redis screening list error: {}", e);
+            Err(transfer_failed(record.id.clone()))
+        }
+    }
+}
+
+// Checks the per-receiver caps, then hands the transfer to `store_transfer`.
+// The receiver stays locked until the record is stored, so two concurrent
+// transfers to it can't both fit under a cap only one of them fits under.
+async fn accept_transfer(
+    conn: &mut deadpool_redis::Connection,
+    settings: &Settings,
+    store: &dyn TransactionStore,
+    balance: &BalanceTracker,
+    limits: &[ratelimit::Limit],
+    record: TransactionRecord,
+    amount: u128,
+) -> HttpResponse {
+    if !payouts::caps_enabled(settings) {
+        return store_transfer(conn, store, balance, limits, record, amount).await;
+    }
+    let receiver_id = record.request.reciever_id.clone();
+    let record_id = record.id.clone();
+    match payouts::lock(conn, &receiver_id, &record_id, payouts::TRANSFER_LOCK_TTL_MS).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::Conflict().json(TransferResponse {
+                success: false,
+                message: format!(
+                    "Another transfer to {} is being accepted; try again.",
+                    receiver_id
+                ),
+                transaction_id: String::new(),
+            });
+        }
+        Err(e) => {
+            error!("Redis receiver lock error: {}", e);
+            return transfer_failed(record_id);
+        }
+    }
+
+    let response = match payouts::usage(store, settings, &receiver_id).await {
+        Ok(usage) => match payouts::check_caps(settings, &receiver_id, &usage, amount) {
+            Ok(()) => store_transfer(conn, store, balance, limits, record, amount).await,
+            Err(message) => HttpResponse::UnprocessableEntity().json(TransferResponse {
+                success: false,
+                message,
+                transaction_id: String::new(),
+            }),
+        },
+        Err(e) => {
+            error!("Failed to read the history of {}: {}", receiver_id, e);
+            transfer_failed(record_id.clone())
+        }
+    };
+    let _ = payouts::unlock(conn, &receiver_id, &record_id).await;
+    response
+}
+
+// Claims the payout dedupe key, reserves the amount against the balance and
+// the quotas, then stores and queues the record. Anything taken is given
+// back if the transfer isn't accepted.
+async fn store_transfer(
+    conn: &mut deadpool_redis::Connection,
+    store: &dyn TransactionStore,
+    balance: &BalanceTracker,
+    limits: &[ratelimit::Limit],
+    record: TransactionRecord,
+    amount: u128,
+) -> HttpResponse {
+    let record_id = record.id.clone();
+    let receiver_id = record.request.reciever_id.clone();
+    let dedupe_key = record.request.dedupe_key.clone();
+
+    if let Some(key) = &dedupe_key {
+        match payouts::claim_dedupe_key(conn, store, &receiver_id, key, &record_id).await {
+            Ok(None) => {}
+            Ok(Some(paid_by)) => {
+                return HttpResponse::Conflict().json(TransferResponse {
+                    success: false,
+                    message: format!(
+                        "{} was already paid for dedupe_key {:?}.",
+                        receiver_id, key
+                    ),
+                    transaction_id: paid_by,
+                });
+            }
+            Err(e) => {
+                error!("Failed to claim dedupe key {:?}: {}", key, e);
+                return transfer_failed(record_id);
+            }
+        }
+    }
+
+    let response = reserve_and_queue(conn, store, balance, limits, record, amount).await;
+    if let Some(key) = &dedupe_key {
+        if response.status().is_success() {
+            let claim = [(receiver_id.as_str(), key.as_str(), record_id.as_str())];
+            if let Err(e) = payouts::confirm_dedupe_keys(conn, &claim).await {
+                error!("Failed to keep dedupe key {:?}: {}", key, e);
+            }
+        } else {
+            let _ = payouts::release_dedupe_key(conn, &receiver_id, key, &record_id).await;
+        }
+    }
+    response
+}
+
+async fn reserve_and_queue(
+    conn: &mut deadpool_redis::Connection,
+    store: &dyn TransactionStore,
+    balance: &BalanceTracker,
+    limits: &[ratelimit::Limit],
+    record: TransactionRecord,
+    amount: u128,
+) -> HttpResponse {
+    let record_id = record.id.clone();
+    if let Err(available) = balance.reserve(amount) {
+        return HttpResponse::UnprocessableEntity().json(TransferResponse {
+            success: false,
+            message: insufficient_balance_message(amount, available),
+            transaction_id: String::new(),
+        });
+    }
+
+    let spent = match ratelimit::spend(conn, limits, amount).await {
+        Ok(Ok(spent)) => spent,
+        Ok(Err(exceeded)) => {
+            balance.unreserve(amount);
+            return rate_limited(&exceeded);
+        }
+        Err(e) => {
+            error!("Redis quota error: {}", e);
+            balance.unreserve(amount);
+            return transfer_failed(record_id);
+        }
+    };
+
+    // The record is stored before its ID is queued so the worker never sees
+    // an ID without its record.
+    let result = match store.create(std::slice::from_ref(&record)).await {
+        Ok(_) => match queue::enqueue(conn, std::slice::from_ref(&record_id)).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // The record exists but will never be sent; say so on it.
+                let mut record = record;
+                if record.transition(TransactionStatus::Cancelled).is_ok() {
+                    record.error_message = Some("Failed to queue transfer request.".to_string());
+                    let _ = store
+                        .update_from(&[(TransactionStatus::Queued, record)])
+                        .await;
+                }
+                Err(e.to_string())
+            }
+        },
+        Err(e) => Err(e.to_string()),
+    };
+
+    match result {
+        Ok(_) => HttpResponse::Accepted().json(TransferResponse {
+            success: true,
+            message: "Transfer request accepted and queued for processing.".to_string(),
+            transaction_id: record_id,
+        }),
+        Err(e) => {
+            error!("Failed to persist transfer {}: {}", record_id, e);
+            balance.unreserve(amount);
+            let _ = ratelimit::refund(conn, &spent).await;
+            transfer_failed(record_id)
+        }
+    }
+}
+
+// 429 for a request over one of its rate limits or quotas, saying which one
+// and how much of it is used.
+fn rate_limited(exceeded: &ratelimit::Exceeded) -> HttpResponse {
+    let limit = &exceeded.limit;
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, exceeded.retry_after_secs.to_string()))
+        .insert_header(("X-RateLimit-Scope", limit.scope()))
+        .insert_header(("X-RateLimit-Limit", limit.max.to_string()))
+        .insert_header(("X-RateLimit-Used", exceeded.used.to_string()))
+        .insert_header((
+            "X-RateLimit-Remaining",
+            limit.max.saturating_sub(exceeded.used).to_string(),
+        ))
+        .json(TransferResponse {
+            success: false,
+            message: format!(
+                "Rate limit {} of {} reached; retry in {}s.",
+                limit.scope(),
+                limit.max,
+                exceeded.retry_after_secs
+            ),
+            transaction_id: String::new(),
+        })
+}
+
+fn insufficient_balance_message(amount: u128, available: u128) -> String {
+    format!(
+        "Insufficient balance: the transfer needs {} but only {} is available.",
+        amount, available
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/transfers/bulk",
+    request_body(
+        content = BulkTransferRequest,
+        description = "Either a JSON object with a campaign name and a list of transfers, or a `text/csv` body with `receiver,amount,memo` rows and the campaign name in the `name` query parameter"
+    ),
+    params(BulkTransferQuery),
+    responses(
+        (status = 202, description = "Valid rows were queued as a new campaign; rows whose receiver was rejected by screening are listed as rejected", body = BulkTransferResponse),
+        (status = 400, description = "No row was valid, or the body could not be read", body = BulkTransferResponse),
+        (status = 413, description = "Too many rows"),
+        (status = 429, description = "Over a request-rate limit, or the campaign total is over an amount quota", body = TransferResponse),
+        (status = 500, description = "Internal server error")
+    )
+)]
+#[post("/transfers/bulk")]
+#[allow(clippy::too_many_arguments)]
+pub async fn bulk_transfer(
+    http_request: HttpRequest,
+    body: Bytes,
+    query: Query<BulkTransferQuery>,
+    settings: Data<Settings>,
+    redis_pool: Data<Pool>,
+    store: Data<dyn TransactionStore>,
+    balance: Data<BalanceTracker>,
+    screener: Data<Screener>,
+    accounts: Data<AccountChecker>,
+) -> impl Responder {
+    let is_csv = http_request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/csv"));
+
+    // Rows are numbered by their line in a CSV file, or from 1 in a JSON list.
+    let (name, rows): (Option<String>, Vec<campaign::BulkRow>) = if is_csv {
+        (query.name.clone(), campaign::parse_csv(&body))
+    } else {
+        match serde_json::from_slice::<BulkTransferRequest>(&body) {
+            Ok(bulk) => (
+                Some(bulk.name),
+                bulk.transfers
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, transfer)| (index + 1, Ok(transfer)))
+                    .collect(),
+            ),
+            Err(e) => {
+                return HttpResponse::BadRequest().body(format!("Invalid bulk transfer body: {}", e));
+            }
+        }
+    };
+
+    let Some(name) = name.filter(|name| !name.trim().is_empty()) else {
+        return HttpResponse::BadRequest().body("A campaign name is required.");
+    };
+    if rows.len() > settings.bulk_max_rows {
+        return HttpResponse::PayloadTooLarge().body(format!(
+            "A bulk request may contain at most {} transfers.",
+            settings.bulk_max_rows
+        ));
+    }
+
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // The whole campaign counts as one request, and its total against the
+    // amount quotas.
+    let api_key = auth::key(&http_request);
+    let limits = ratelimit::limits_for(&settings, api_key.as_ref());
+    match ratelimit::check_request(&mut conn, &limits).await {
+        Ok(Ok(())) => {}
+        Ok(Err(exceeded)) => return rate_limited(&exceeded),
+        Err(e) => {
+            error!("Redis rate limit error: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    // Every row is validated before anything is queued. Rows are accepted
+    // until the sender's available balance runs out.
+    let mut campaign = Campaign::new(name);
+    let api_key_id = api_key.as_ref().map(|key| key.id.clone());
+    campaign.api_key_id = api_key_id.clone();
+    let checked: Vec<CheckedRow> = rows
+        .into_iter()
+        .map(|(row_number, row)| {
+            let reciever_id = row
+                .as_ref()
+                .map(|request| request.reciever_id.clone())
+                .unwrap_or_default();
+            let checked = row.and_then(|mut request| {
+                validation::normalize_transfer(&mut request, settings.ft_decimals)
+                    .and_then(|amount| webhooks::check_callback_url(&settings, &request).map(|()| amount))
+                    .map(|amount| {
+                        let mut record =
+                            TransactionRecord::new(settings.account_id.clone(), request, settings.ft_decimals);
+                        record.campaign_id = Some(campaign.id.clone());
+                        record.api_key_id = api_key_id.clone();
+                        (record, amount)
+                    })
+            });
+            (row_number, reciever_id, checked)
+        })
+        .collect();
+
+    // The screening lists are read for every receiver in one pipeline; the
+    // on-chain check and the screening hook then run for
+    // `bulk_check_concurrency` rows at a time.
+    let receiver_ids: Vec<&str> = checked
+        .iter()
+        .filter_map(|(_, _, checked)| checked.as_ref().ok())
+        .map(|(record, _)| record.request.reciever_id.as_str())
+        .collect();
+    let mut listed = match screening::listed(&mut conn, &receiver_ids).await {
+        Ok(listed) => listed.into_iter().map(Ok).collect(),
+        Err(e) => {
+            error!("Failed to read the screening lists: {}", e);
+            vec![Err(format!("Failed to read the screening lists: {}", e)); receiver_ids.len()]
+        }
+    }
+    .into_iter();
+    let screened: Vec<CheckedRow> =
+        futures::stream::iter(checked.into_iter().map(|(row_number, reciever_id, checked)| {
+            let listed = checked.as_ref().ok().and_then(|_| listed.next());
+            let (settings, accounts, screener) = (&settings, &accounts, &screener);
+            async move {
+                let checked = match (checked, listed) {
+                    (Ok((mut record, amount)), Some(listed)) => {
+                        screen_bulk_row(settings, accounts, screener, &mut record, listed)
+                            .await
+                            .map(|()| (record, amount))
+                    }
+                    (Ok(_), None) => Err("Failed to read the screening lists.".to_string()),
+                    (Err(message), _) => Err(message),
+                };
+                (row_number, reciever_id, checked)
+            }
+        }))
+        .buffered(settings.bulk_check_concurrency)
+        .collect()
+        .await;
+
+    // Caps, dedupe keys and the balance depend on the rows before, so rows
+    // are admitted one at a time, in order.
+    let mut records = Vec::with_capacity(screened.len());
+    let mut results = Vec::with_capacity(screened.len());
+    let mut total_amount: u128 = 0;
+    let mut receiver_usage = HashMap::new();
+    for (row_number, reciever_id, checked) in screened {
+        let checked = match checked {
+            Ok((record, amount)) => admit_bulk_row(
+                &mut conn,
+                &settings,
+                store.get_ref(),
+                &balance,
+                &mut receiver_usage,
+                &record,
+                amount,
+            )
+            .await
+            .map(|_| (record, amount)),
+            Err(message) => Err(message),
+        };
+        match checked {
+            Ok((record, amount)) => {
+                total_amount = total_amount.saturating_add(amount);
+                results.push(BulkRowResult {
+                    row: row_number,
+                    reciever_id,
+                    accepted: true,
+                    transaction_id: Some(record.id.clone()),
+                    message: None,
+                });
+                records.push(record);
+            }
+            Err(message) => results.push(BulkRowResult {
+                row: row_number,
+                reciever_id,
+                accepted: false,
+                transaction_id: None,
+                message: Some(message),
+            }),
+        }
+    }
+
+    let accepted = records.len();
+    let rejected = results.len() - accepted;
+    if accepted == 0 {
+        let locked: Vec<&str> = receiver_usage.keys().map(String::as_str).collect();
+        let _ = payouts::unlock_all(&mut conn, &locked, &campaign.id).await;
+        return HttpResponse::BadRequest().json(BulkTransferResponse {
+            campaign_id: None,
+            accepted,
+            rejected,
+            results,
+        });
+    }
+
+    campaign.total_transfers = accepted as u64;
+    campaign.total_amount = total_amount.to_string();
+
+    let spent = match ratelimit::spend(&mut conn, &limits, total_amount).await {
+        Ok(Ok(spent)) => Ok(spent),
+        Ok(Err(exceeded)) => Err(rate_limited(&exceeded)),
+        Err(e) => {
+            error!("Redis quota error: {}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    };
+    let result = match spent {
+        Ok(spent) => match campaign::create(&mut conn, store.get_ref(), &campaign, &records).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Failed to queue campaign {}: {}", campaign.id, e);
+                let _ = ratelimit::refund(&mut conn, &spent).await;
+                Err(HttpResponse::InternalServerError().finish())
+            }
+        },
+        Err(response) => Err(response),
+    };
+    let locked: Vec<&str> = receiver_usage.keys().map(String::as_str).collect();
+    if let Err(e) = payouts::unlock_all(&mut conn, &locked, &campaign.id).await {
+        error!("Failed to unlock the receivers of campaign {}: {}", campaign.id, e);
+    }
+    if let Err(response) = result {
+        balance.unreserve(total_amount);
+        for record in &records {
+            if let Some(key) = &record.request.dedupe_key {
+                let _ = payouts::release_dedupe_key(
+                    &mut conn,
+                    &record.request.reciever_id,
+                    key,
+                    &record.id,
+                )
+                .await;
+            }
+        }
+        return response;
+    }
+    let claims: Vec<(&str, &str, &str)> = records
+        .iter()
+        .filter_map(|record| {
+            let key = record.request.dedupe_key.as_deref()?;
+            Some((record.request.reciever_id.as_str(), key, record.id.as_str()))
+        })
+        .collect();
+    if let Err(e) = payouts::confirm_dedupe_keys(&mut conn, &claims).await {
+        error!("Failed to keep the dedupe keys of campaign {}: {}", campaign.id, e);
+    }
+
+    HttpResponse::Accepted().json(BulkTransferResponse {
+        campaign_id: Some(campaign.id),
+        accepted,
+        rejected,
+        results,
+    })
+}
+
+// A bulk row's number, receiver and either its record and raw amount or the
+// reason it was rejected.
+type CheckedRow = (usize, String, Result<(TransactionRecord, u128), String>);
+
+// `check_receiver_exists` and `screen_receiver` for a bulk row, which is
+// rejected on its own instead. `listed` is the receiver's membership of the
+// screening lists, read for the whole request by `screening::listed`.
+async fn screen_bulk_row(
+    settings: &Settings,
+    accounts: &AccountChecker,
+    screener: &Screener,
+    record: &mut TransactionRecord,
+    listed: Result<(bool, bool), String>,
+) -> Result<(), String> {
+    check_receiver_exists(settings, accounts, &record.request.reciever_id)
+        .await
+        .map_err(|(_, message)| message)?;
+    let verdict = screener.screen_listed(record, listed?).await?;
+    if !verdict.is_allowed() {
+        return Err(screening::rejection_message(
+            &record.request.reciever_id,
+            &verdict,
+        ));
+    }
+    record.screening = Some(verdict);
+    Ok(())
+}
+
+// Checks a validated bulk row against its receiver's caps (counting the
+// campaign's earlier rows too) and dedupe key, then reserves its amount.
+// The first row for a receiver locks it for the campaign, as a single
+// transfer would, so a concurrent transfer can't slip under the same cap;
+// every receiver in `receiver_usage` is locked and has to be unlocked.
+async fn admit_bulk_row(
+    conn: &mut deadpool_redis::Connection,
+    settings: &Settings,
+    store: &dyn TransactionStore,
+    balance: &BalanceTracker,
+    receiver_usage: &mut HashMap<String, payouts::ReceiverUsage>,
+    record: &TransactionRecord,
+    amount: u128,
+) -> Result<(), String> {
+    let receiver_id = &record.request.reciever_id;
+    let mut usage = None;
+    if payouts::caps_enabled(settings) {
+        let current = match receiver_usage.get(receiver_id) {
+            Some(usage) => *usage,
+            None => lock_bulk_receiver(conn, settings, store, receiver_usage, record).await?,
+        };
+        payouts::check_caps(settings, receiver_id, &current, amount)?;
+        usage = Some(current);
+    }
+
+    if let Some(key) = &record.request.dedupe_key {
+        match payouts::claim_dedupe_key(conn, store, receiver_id, key, &record.id).await {
+            Ok(None) => {}
+            Ok(Some(paid_by)) => {
+                return Err(format!(
+                    "{} was already paid for dedupe_key {:?} by transaction {}.",
+                    receiver_id, key, paid_by
+                ));
+            }
+            Err(e) => return Err(format!("Failed to claim dedupe_key {:?}: {}", key, e)),
+        }
+    }
+
+    if let Err(available) = balance.reserve(amount) {
+        if let Some(key) = &record.request.dedupe_key {
+            let _ = payouts::release_dedupe_key(conn, receiver_id, key, &record.id).await;
+        }
+        return Err(insufficient_balance_message(amount, available));
+    }
+    if let Some(mut usage) = usage {
+        usage.add(amount);
+        receiver_usage.insert(receiver_id.clone(), usage);
+    }
+    Ok(())
+}
+
+// Locks a bulk row's receiver for its campaign and records its usage so far.
+async fn lock_bulk_receiver(
+    conn: &mut deadpool_redis::Connection,
+    settings: &Settings,
+    store: &dyn TransactionStore,
+    receiver_usage: &mut HashMap<String, payouts::ReceiverUsage>,
+    record: &TransactionRecord,
+) -> Result<payouts::ReceiverUsage, String> {
+    let receiver_id = &record.request.reciever_id;
+    let holder = record.campaign_id.as_deref().unwrap_or(&record.id);
+    match payouts::lock(conn, receiver_id, holder, payouts::CAMPAIGN_LOCK_TTL_MS).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err(format!(
+                "Another transfer to {} is being accepted; try again.",
+                receiver_id
+            ));
+        }
+        Err(e) => return Err(format!("Failed to lock {}: {}", receiver_id, e)),
+    }
+    match payouts::usage(store, settings, receiver_id).await {
+        Ok(usage) => {
+            receiver_usage.insert(receiver_id.clone(), usage);
+            Ok(usage)
+        }
+        Err(e) => {
+            let _ = payouts::unlock(conn, receiver_id, holder).await;
+            Err(format!("Failed to read the history of {}: {}", receiver_id, e))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/transaction/{id}",
+    params(("id" = String, Path, description = "Unique ID of the transaction")),
+    responses(
+        (status = 200, body = TransactionRecord),
+        (status = 404)
+    )
+)]
+#[get("/transaction/{id}")]
+pub async fn get_transaction_by_id(
+    http_request: HttpRequest,
+    path: Path<String>,
+    store: Data<dyn TransactionStore>,
+) -> impl Responder {
+    let tx_id = path.into_inner();
+    let key = auth::key(&http_request);
+    match store.get(&tx_id).await {
+        Ok(Some(record)) if owns(key.as_ref(), record.api_key_id.as_deref()) => {
+            HttpResponse::Ok().json(record)
+        }
+        Ok(_) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Failed to read transaction {}: {}", tx_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/transaction/{id}/cancel",
+    params(("id" = String, Path, description = "Unique ID of the transaction")),
+    responses(
+        (status = 200, description = "The transfer was withdrawn before being sent", body = TransactionRecord),
+        (status = 404),
+        (status = 409, description = "The transfer is already being processed or finished")
+    )
+)]
+#[post("/transaction/{id}/cancel")]
+pub async fn cancel_transaction(
+    http_request: HttpRequest,
+    path: Path<String>,
+    redis_pool: Data<Pool>,
+    store: Data<dyn TransactionStore>,
+    balance: Data<BalanceTracker>,
+) -> impl Responder {
+    let tx_id = path.into_inner();
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // The campaign tells which paused list the transfer may be parked in.
+    let key = auth::key(&http_request);
+    let campaign_id = match store.get(&tx_id).await {
+        Ok(Some(record)) if owns(key.as_ref(), record.api_key_id.as_deref()) => record.campaign_id,
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Failed to read transaction {}: {}", tx_id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // Only a transfer we manage to take off the queue, the retry set or a
+    // paused list is guaranteed not to be sent by the worker, so withdraw it
+    // before reading its status.
+    let withdrawn = match queue::withdraw(&mut conn, &tx_id, campaign_id.as_deref()).await {
+        Ok(withdrawn) => withdrawn,
+        Err(e) => {
+            error!("Redis withdraw error: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let mut record = match store.get(&tx_id).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Failed to read transaction {}: {}", tx_id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    if !withdrawn {
+        return HttpResponse::Conflict()
+            .body(format!("Transaction is {} and can no longer be cancelled.", record.status));
+    }
+
+    let from = record.status;
+    if let Err(message) = record.transition(TransactionStatus::Cancelled) {
+        return HttpResponse::Conflict().body(message);
+    }
+    match store.update_from(&[(from, record.clone())]).await {
+        Ok(left_alone) if !left_alone.is_empty() => HttpResponse::Conflict()
+            .body("Transaction changed while it was being cancelled."),
+        Ok(_) => {
+            balance.settle(std::slice::from_ref(&record));
+            HttpResponse::Ok().json(record)
+        }
+        Err(e) => {
+            error!("Failed to store cancelled transaction {}: {}", tx_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/transactions/{receiver_id}",
+    params(
+        ("receiver_id" = String, Path, description = "The NEAR account ID of the receiver"),
+        ("offset" = Option<u64>, Query, description = "Pagination offset, default 0"),
+        ("limit" = Option<u64>, Query, description = "Pagination limit, default 10")
+    ),
+    responses((status = 200, body = [TransactionRecord]))
+)]
+#[get("/transactions/{receiver_id}")]
+pub async fn get_transactions_by_receiver(
+    http_request: HttpRequest,
+    path: Path<String>,
+    query: Query<Pagination>,
+    store: Data<dyn TransactionStore>,
+) -> impl Responder {
+    let receiver_id = path.into_inner();
+    let offset = query.offset.unwrap_or(0).max(0) as usize;
+    let limit = query.limit.unwrap_or(10).max(0) as usize;
+    match store.list_by_receiver(&receiver_id, offset, limit).await {
+        Ok(records) => HttpResponse::Ok().json(visible(&http_request, records)),
+        Err(e) => {
+            error!("Failed to list transactions of {}: {}", receiver_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/transactions",
+    params(
+        ("receiver_id" = String, Path, description = "The NEAR account ID of the receiver"),
+        ("offset" = Option<u64>, Query, description = "Pagination offset, default 0"),
+        ("limit" = Option<u64>, Query, description = "Pagination limit, default 10")
+    ),
+    responses((status = 200, body = PaginatedTransactionResponse))
+)]
+#[get("/transactions")]
+pub async fn get_all_transactions(
+    http_request: HttpRequest,
+    query: Query<ScanPagination>,
+    store: Data<dyn TransactionStore>,
+) -> impl Responder {
+    let cursor = query.cursor.unwrap_or(0);
+    let count = query.count.unwrap_or(10) as usize;
+    match store.list_all(cursor, count).await {
+        Ok((next_cursor, records)) => HttpResponse::Ok().json(PaginatedTransactionResponse {
+            next_cursor,
+            records: visible(&http_request, records),
+        }),
+        Err(e) => {
+            error!("Failed to list transactions: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/transactions/status/{status}",
+    params(
+        ("status" = String, Path, description = "The status to filter by (Queued, Batched, Submitted, Retrying, Success, Failure, Cancelled, Expired)"),
+        ("offset" = Option<u64>, Query, description = "Pagination offset, default 0"),
+        ("limit" = Option<u64>, Query, description = "Pagination limit, default 10")
+    ),
+    responses(
+        (status = 200, description = "A paginated list of transaction records matching the status", body = [TransactionRecord])
+    )
+)]
+#[get("/transactions/status/{status}")]
+pub async fn get_transactions_by_status(
+    http_request: HttpRequest,
+    path: Path<String>,
+    query: Query<Pagination>,
+    store: Data<dyn TransactionStore>,
+) -> impl Responder {
+    let status_str = path.into_inner();
+    let status_to_filter = match status_str.parse::<TransactionStatus>() {
+        Ok(status) => status,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let offset = query.offset.unwrap_or(0).max(0) as usize;
+    let limit = query.limit.unwrap_or(10).max(0) as usize;
+
+    match store.list_by_status(status_to_filter, offset, limit).await {
+        Ok(records) => HttpResponse::Ok().json(visible(&http_request, records)),
+        Err(e) => {
+            error!("Failed to list {} transactions: {}", status_to_filter, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/campaigns/{id}",
+    params(("id" = String, Path, description = "ID of the campaign returned by POST /transfers/bulk")),
+    responses(
+        (status = 200, description = "Per-status counts and amounts sent so far", body = CampaignProgress),
+        (status = 404)
+    )
+)]
+#[get("/campaigns/{id}")]
+pub async fn get_campaign(
+    http_request: HttpRequest,
+    path: Path<String>,
+    redis_pool: Data<Pool>,
+    store: Data<dyn TransactionStore>,
+) -> impl Responder {
+    let campaign_id = path.into_inner();
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let key = auth::key(&http_request);
+    let campaign = match campaign::get(&mut conn, &campaign_id).await {
+        Ok(Some(campaign)) if owns(key.as_ref(), campaign.api_key_id.as_deref()) => campaign,
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Redis GET error: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match campaign::progress(store.get_ref(), campaign).await {
+        Ok(progress) => HttpResponse::Ok().json(progress),
+        Err(e) => {
+            error!("Failed to read campaign progress: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/campaigns/{id}/transactions",
+    params(
+        ("id" = String, Path, description = "ID of the campaign"),
+        CampaignTransactionsQuery
+    ),
+    responses(
+        (status = 200, description = "The campaign's transfers, in submission order unless filtered by status", body = [TransactionRecord]),
+        (status = 400, description = "Unknown status"),
+        (status = 404)
+    )
+)]
+#[get("/campaigns/{id}/transactions")]
+pub async fn get_campaign_transactions(
+    http_request: HttpRequest,
+    path: Path<String>,
+    query: Query<CampaignTransactionsQuery>,
+    redis_pool: Data<Pool>,
+    store: Data<dyn TransactionStore>,
+) -> impl Responder {
+    let campaign_id = path.into_inner();
+    let status = match query.status.as_deref().map(str::parse::<TransactionStatus>) {
+        Some(Ok(status)) => Some(status),
+        Some(Err(message)) => return HttpResponse::BadRequest().body(message),
+        None => None,
+    };
+    let offset = query.offset.unwrap_or(0).max(0) as usize;
+    let limit = query.limit.unwrap_or(10).max(0) as usize;
+
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let key = auth::key(&http_request);
+    match campaign::get(&mut conn, &campaign_id).await {
+        Ok(Some(campaign)) if owns(key.as_ref(), campaign.api_key_id.as_deref()) => {}
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Redis GET error: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match store.list_by_campaign(&campaign_id, status, offset, limit).await {
+        Ok(records) => HttpResponse::Ok().json(records),
+        Err(e) => {
+            error!("Failed to list transactions of campaign {}: {}", campaign_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Shared by the pause, resume and cancel endpoints. Cancelled campaigns are
+// final; the other two states can be switched back and forth.
+async fn change_campaign_status(
+    key: Option<&ApiKey>,
+    redis_pool: &Pool,
+    store: &dyn TransactionStore,
+    balance: &BalanceTracker,
+    campaign_id: &str,
+    status: CampaignStatus,
+) -> HttpResponse {
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let mut campaign = match campaign::get(&mut conn, campaign_id).await {
+        Ok(Some(campaign)) if owns(key, campaign.api_key_id.as_deref()) => campaign,
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Redis GET error: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if campaign.status == CampaignStatus::Cancelled {
+        return HttpResponse::Conflict().body("Campaign is cancelled.");
+    }
+
+    match campaign::set_status(&mut conn, store, &mut campaign, status).await {
+        Ok(cancelled) => balance.settle(&cancelled),
+        Err(e) => {
+            error!("Failed to update campaign {}: {}", campaign_id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    match campaign::progress(store, campaign).await {
+        Ok(progress) => HttpResponse::Ok().json(progress),
+        Err(e) => {
+            error!("Failed to read campaign progress: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/campaigns/{id}/pause",
+    params(("id" = String, Path, description = "ID of the campaign")),
+    responses(
+        (status = 200, description = "Queued transfers are held back until the campaign is resumed", body = CampaignProgress),
+        (status = 404),
+        (status = 409, description = "The campaign is cancelled")
+    )
+)]
+#[post("/campaigns/{id}/pause")]
+pub async fn pause_campaign(
+    http_request: HttpRequest,
+    path: Path<String>,
+    redis_pool: Data<Pool>,
+    store: Data<dyn TransactionStore>,
+    balance: Data<BalanceTracker>,
+) -> impl Responder {
+    change_campaign_status(
+        auth::key(&http_request).as_ref(),
+        &redis_pool,
+        store.get_ref(),
+        &balance,
+        &path.into_inner(),
+        CampaignStatus::Paused,
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/campaigns/{id}/resume",
+    params(("id" = String, Path, description = "ID of the campaign")),
+    responses(
+        (status = 200, description = "Held-back transfers are queued again", body = CampaignProgress),
+        (status = 404),
+        (status = 409, description = "The campaign is cancelled")
+    )
+)]
+#[post("/campaigns/{id}/resume")]
+pub async fn resume_campaign(
+    http_request: HttpRequest,
+    path: Path<String>,
+    redis_pool: Data<Pool>,
+    store: Data<dyn TransactionStore>,
+    balance: Data<BalanceTracker>,
+) -> impl Responder {
+    change_campaign_status(
+        auth::key(&http_request).as_ref(),
+        &redis_pool,
+        store.get_ref(),
+        &balance,
+        &path.into_inner(),
+        CampaignStatus::Active,
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/campaigns/{id}/cancel",
+    params(("id" = String, Path, description = "ID of the campaign")),
+    responses(
+        (status = 200, description = "Transfers not yet sent are cancelled; ones already submitted still complete", body = CampaignProgress),
+        (status = 404),
+        (status = 409, description = "The campaign is already cancelled")
+    )
+)]
+#[post("/campaigns/{id}/cancel")]
+pub async fn cancel_campaign(
+    http_request: HttpRequest,
+    path: Path<String>,
+    redis_pool: Data<Pool>,
+    store: Data<dyn TransactionStore>,
+    balance: Data<BalanceTracker>,
+) -> impl Responder {
+    change_campaign_status(
+        auth::key(&http_request).as_ref(),
+        &redis_pool,
+        store.get_ref(),
+        &balance,
+        &path.into_inner(),
+        CampaignStatus::Cancelled,
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/token",
+    responses(
+        (status = 200, description = "Metadata of the token read from the contract at startup", body = TokenMetadata),
+        (status = 503, description = "The metadata couldn't be read at startup")
+    )
+)]
+#[get("/token")]
+pub async fn get_token(metadata: Data<Option<TokenMetadata>>) -> impl Responder {
+    match metadata.as_ref() {
+        Some(metadata) => HttpResponse::Ok().json(metadata),
+        None => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/balance",
+    responses(
+        (status = 200, description = "The sender's token balance, the amount reserved for pending transfers and what is left", body = BalanceResponse)
+    )
+)]
+#[get("/balance")]
+pub async fn get_balance(balance: Data<BalanceTracker>) -> impl Responder {
+    HttpResponse::Ok().json(balance.snapshot())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/keys",
+    responses(
+        (status = 200, description = "Health of every access key in the signing pool", body = [KeyHealth])
+    )
+)]
+#[get("/admin/keys")]
+pub async fn get_admin_keys(key_pool: Data<KeyPool>) -> impl Responder {
+    HttpResponse::Ok().json(key_pool.health().await)
+}
+
 #[utoipa::path(
     post,
-    path = "/transfer",
-    request_body = TokenTransferRequest,
+    path = "/admin/keys/resize",
+    request_body = ResizePoolRequest,
     responses(
-        (status = 202, description = "Transfer request accepted for processing", body = TransferResponse),
-        (status = 400, description = "Invalid input", body = TransferResponse),
-        (status = 500, description = "Internal server error", body = TransferResponse)
+        (status = 200, description = "The pool was resized; concurrency follows the new size", body = ResizePoolResponse),
+        (status = 400, description = "Target outside 1..=max_pool_keys"),
+        (status = 500, description = "Keys could not be added")
     )
 )]
-#[post("/transfer")]
-pub async fn ft_transfer(
-    payload: Json<TokenTransferRequest>,
-    sender: Data<Sender<TokenTransferRequest>>,
-    settings: Data<Settings>,
+#[post("/admin/keys/resize")]
+pub async fn resize_key_pool(
+    payload: Json<ResizePoolRequest>,
+    pool_manager: Data<PoolManager>,
+    key_pool: Data<KeyPool>,
+) -> impl Responder {
+    let target = payload.target;
+    if target == 0 || target > pool_manager.max_pool_keys() {
+        return HttpResponse::BadRequest().body(format!(
+            "target must be between 1 and {}.",
+            pool_manager.max_pool_keys()
+        ));
+    }
+    match pool_manager.resize(target).await {
+        Ok(pool_size) => HttpResponse::Ok().json(ResizePoolResponse {
+            pool_size,
+            concurrency: key_pool.concurrency(),
+        }),
+        Err(e) => {
+            error!("Failed to resize key pool: {}", e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/api-keys",
+    request_body = CreateApiKeyRequest,
+    responses(
+        (status = 201, description = "The key was created; its secret is only shown in this response", body = CreateApiKeyResponse),
+        (status = 400, description = "Missing name or scopes, or an amount limit that isn't a raw integer")
+    )
+)]
+#[post("/admin/api-keys")]
+pub async fn create_api_key(
+    payload: Json<CreateApiKeyRequest>,
     redis_pool: Data<Pool>,
 ) -> impl Responder {
     let request = payload.into_inner();
-    let record = TransactionRecord::new(settings.account_id.clone(), request.clone());
-    let record_id = record.id.clone();
-    // Basic validation
-    if request.reciever_id.to_string().is_empty() || request.amount.parse::<u128>().is_err() {
-        return HttpResponse::BadRequest().json(TransferResponse {
-            success: false,
-            message: "Invalid receiver_id or amount".to_string(),
-            transaction_id: record_id,
-        });
+    if request.name.trim().is_empty() || request.scopes.is_empty() {
+        return HttpResponse::BadRequest().body("A name and at least one scope are required.");
     }
+    if let Some(limits) = &request.limits {
+        let amounts = [&limits.amount_per_hour, &limits.amount_per_day];
+        if amounts
+            .into_iter()
+            .flatten()
+            .any(|amount| amount.parse::<u128>().is_err())
+        {
+            return HttpResponse::BadRequest().body("Amount limits must be raw integer amounts.");
+        }
+    }
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match auth::create(&mut conn, request.name, request.scopes, request.limits).await {
+        Ok((key, secret)) => HttpResponse::Created().json(CreateApiKeyResponse { key, secret }),
+        Err(e) => {
+            error!("Failed to create API key: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
 
-    // --- Spawn Green Thread for Redis Write ---
-    tokio::spawn(async move {
-        let record_json = serde_json::to_string(&record).unwrap_or_default();
-        let mut conn = redis_pool.get().await.expect("Failed to get redis conn");
-
-        // Save the full record
-        let _: () = conn
-            .set(format!("txn:{}", record.id), record_json)
-            .await
-            .unwrap_or_else(|e| error!("Redis SET error: {}", e));
-
-        // Add to the user's transaction list
-        let _: () = conn
-            .lpush(format!("user_txns:{}", record.request.reciever_id), &record.id)
-            .await
-            .unwrap_or_else(|e| error!("Redis LPUSH error: {}", e));
-    });
-
-
-    match sender.send(request).await {
-        Ok(_) => HttpResponse::Accepted().json(TransferResponse {
-            success: true,
-            message: "Transfer request accepted and queued for processing.".to_string(),
-            transaction_id: record_id,
-        }),
-        Err(_) => HttpResponse::InternalServerError().json(TransferResponse {
-            success: false,
-            message: "Failed to queue transfer request.".to_string(),
-            transaction_id: record_id,
-        }),
+#[utoipa::path(
+    get,
+    path = "/admin/api-keys",
+    responses(
+        (status = 200, description = "Every API key, revoked ones included, without their secrets", body = [ApiKey])
+    )
+)]
+#[get("/admin/api-keys")]
+pub async fn get_api_keys(redis_pool: Data<Pool>) -> impl Responder {
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match auth::list(&mut conn).await {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(e) => {
+            error!("Failed to list API keys: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
+}
 
+#[utoipa::path(
+    post,
+    path = "/admin/api-keys/{id}/revoke",
+    params(("id" = String, Path, description = "ID of the API key")),
+    responses(
+        (status = 200, description = "The key no longer authenticates; transfers it submitted keep its ID", body = ApiKey),
+        (status = 404)
+    )
+)]
+#[post("/admin/api-keys/{id}/revoke")]
+pub async fn revoke_api_key(path: Path<String>, redis_pool: Data<Pool>) -> impl Responder {
+    let key_id = path.into_inner();
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match auth::revoke(&mut conn, &key_id).await {
+        Ok(Some(key)) => HttpResponse::Ok().json(key),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Failed to revoke API key {}: {}", key_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
 }
 
 #[utoipa::path(
     get,
-    path = "/transaction/{id}",
-    params(("id" = String, Path, description = "Unique ID of the transaction")),
+    path = "/admin/screening",
     responses(
-        (status = 200, body = TransactionRecord),
-        (status = 404)
+        (status = 200, description = "The receiver allow and deny lists, from Settings.toml and from Redis", body = ScreeningListsResponse)
     )
 )]
-#[get("/transaction/{id}")]
-pub async fn get_transaction_by_id(
-    path: Path<String>,
+#[get("/admin/screening")]
+pub async fn get_screening_lists(
     redis_pool: Data<Pool>,
+    screener: Data<Screener>,
 ) -> impl Responder {
-    let tx_id = path.into_inner();
     let mut conn = match redis_pool.get().await {
         Ok(conn) => conn,
         Err(e) => {
@@ -121,34 +1641,46 @@ pub async fn get_transaction_by_id(
             return HttpResponse::InternalServerError().finish();
         }
     };
-    match conn.get::<_, String>(format!("txn:{}", tx_id)).await {
-        Ok(record_json) => match serde_json::from_str::<TransactionRecord>(&record_json) {
-            Ok(record) => HttpResponse::Ok().json(record),
-            Err(_) => HttpResponse::InternalServerError().finish(),
-        },
-        Err(_) => HttpResponse::NotFound().finish(),
+    match screening::managed_lists(&mut conn).await {
+        Ok(managed) => HttpResponse::Ok().json(ScreeningListsResponse {
+            configured: screener.configured_lists(),
+            managed,
+        }),
+        Err(e) => {
+            error!("Failed to read the screening lists: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
 }
 
 #[utoipa::path(
-    get,
-    path = "/transactions/{receiver_id}",
-    params(
-        ("receiver_id" = String, Path, description = "The NEAR account ID of the receiver"),
-        ("offset" = Option<u64>, Query, description = "Pagination offset, default 0"),
-        ("limit" = Option<u64>, Query, description = "Pagination limit, default 10")
-    ),
-    responses((status = 200, body = [TransactionRecord]))
+    post,
+    path = "/admin/screening/{list}",
+    request_body = UpdateScreeningListRequest,
+    params(("list" = ScreeningList, Path, description = "`allow` or `deny`")),
+    responses(
+        (status = 200, description = "The account IDs were added to or removed from the Redis list; takes effect for the next transfer", body = ScreeningLists),
+        (status = 400, description = "Nothing to add or remove")
+    )
 )]
-#[get("/transactions/{receiver_id}")]
-pub async fn get_transactions_by_receiver(
-    path: Path<String>,
-    query: Query<Pagination>,
+#[post("/admin/screening/{list}")]
+pub async fn update_screening_list(
+    path: Path<ScreeningList>,
+    payload: Json<UpdateScreeningListRequest>,
     redis_pool: Data<Pool>,
 ) -> impl Responder {
-    let receiver_id = path.into_inner();
-    let offset = query.offset.unwrap_or(0);
-    let limit = query.limit.unwrap_or(10);
+    let list = path.into_inner();
+    let request = payload.into_inner();
+    let clean = |ids: Vec<String>| -> Vec<String> {
+        ids.into_iter()
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+            .collect()
+    };
+    let (add, remove) = (clean(request.add), clean(request.remove));
+    if add.is_empty() && remove.is_empty() {
+        return HttpResponse::BadRequest().body("Give at least one account ID to add or remove.");
+    }
     let mut conn = match redis_pool.get().await {
         Ok(conn) => conn,
         Err(e) => {
@@ -156,45 +1688,50 @@ pub async fn get_transactions_by_receiver(
             return HttpResponse::InternalServerError().finish();
         }
     };
-    let user_txn_key = format!("user_txns:{}", receiver_id);
-    let txn_ids: Vec<String> = match conn.lrange(&user_txn_key, offset, offset + limit - 1).await {
-        Ok(ids) => ids,
-        Err(_) => return HttpResponse::NotFound().finish(),
+    let result = match screening::update_list(&mut conn, list, &add, &remove).await {
+        Ok(()) => screening::managed_lists(&mut conn).await,
+        Err(e) => Err(e),
     };
-    if txn_ids.is_empty() {
-        return HttpResponse::Ok().json(Vec::<TransactionRecord>::new());
+    match result {
+        Ok(lists) => HttpResponse::Ok().json(lists),
+        Err(e) => {
+            error!("Failed to update the {:?} screening list: {}", list, e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
-    let keys: Vec<String> = txn_ids.into_iter().map(|id| format!("txn:{}", id)).collect();
-    let records_json: Vec<Option<String>> = match conn.mget(keys).await {
-        Ok(records) => records,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    let records: Vec<TransactionRecord> = records_json
-        .into_iter()
-        .filter_map(|opt_json_str| {
-            opt_json_str.and_then(|json_str| serde_json::from_str(&json_str).ok())
-        })
-        .collect();
-    HttpResponse::Ok().json(records)
+}
+
+// Whether `key` may see and remove `webhook`: admins any, others their own.
+fn manages_webhook(key: Option<&ApiKey>, webhook: &Webhook) -> bool {
+    owns(key, webhook.api_key_id.as_deref())
 }
 
 #[utoipa::path(
-    get,
-    path = "/transactions",
-    params(
-        ("receiver_id" = String, Path, description = "The NEAR account ID of the receiver"),
-        ("offset" = Option<u64>, Query, description = "Pagination offset, default 0"),
-        ("limit" = Option<u64>, Query, description = "Pagination limit, default 10")
-    ),
-    responses((status = 200, body = PaginatedTransactionResponse))
+    post,
+    path = "/webhooks",
+    request_body = CreateWebhookRequest,
+    responses(
+        (status = 201, description = "The webhook was registered; its signing secret is only shown in this response", body = CreateWebhookResponse),
+        (status = 400, description = "The URL isn't an http or https URL"),
+        (status = 403, description = "A global webhook needs the admin scope")
+    )
 )]
-#[get("/transactions")]
-pub async fn get_all_transactions(
-    query: Query<ScanPagination>,
+#[post("/webhooks")]
+pub async fn create_webhook(
+    http_request: HttpRequest,
+    payload: Json<CreateWebhookRequest>,
     redis_pool: Data<Pool>,
 ) -> impl Responder {
-    let cursor = query.cursor.unwrap_or(0);
-    let count = query.count.unwrap_or(10);
+    let request = payload.into_inner();
+    let Some(key) = auth::key(&http_request) else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    if request.global && !key.allows(ApiKeyScope::Admin) {
+        return HttpResponse::Forbidden().body("A global webhook needs the admin scope.");
+    }
+    if let Err(message) = webhooks::check_url(&request.url) {
+        return HttpResponse::BadRequest().body(message);
+    }
     let mut conn = match redis_pool.get().await {
         Ok(conn) => conn,
         Err(e) => {
@@ -202,72 +1739,138 @@ pub async fn get_all_transactions(
             return HttpResponse::InternalServerError().finish();
         }
     };
-    let (next_cursor, keys): (u64, Vec<String>) = match redis::cmd("SCAN")
-        .arg(cursor)
-        .arg("MATCH")
-        .arg("txn:*")
-        .arg("COUNT")
-        .arg(count)
-        .query_async(&mut conn)
-        .await
-    {
-        Ok(res) => res,
+    let api_key_id = (!request.global).then_some(key.id);
+    match webhooks::create(&mut conn, request.url, api_key_id).await {
+        Ok((webhook, secret)) => {
+            HttpResponse::Created().json(CreateWebhookResponse { webhook, secret })
+        }
+        Err(e) => {
+            error!("Failed to create webhook: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    responses(
+        (status = 200, description = "The calling key's webhooks, or every webhook for an admin key", body = [Webhook])
+    )
+)]
+#[get("/webhooks")]
+pub async fn get_webhooks(http_request: HttpRequest, redis_pool: Data<Pool>) -> impl Responder {
+    let key = auth::key(&http_request);
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
         Err(e) => {
-            error!("Redis SCAN error: {}", e);
+            error!("Could not get Redis connection: {}", e);
             return HttpResponse::InternalServerError().finish();
         }
     };
-    if keys.is_empty() {
-        return HttpResponse::Ok().json(PaginatedTransactionResponse {
-            next_cursor,
-            records: Vec::new(),
-        });
+    match webhooks::list(&mut conn).await {
+        Ok(webhooks) => {
+            let visible: Vec<Webhook> = webhooks
+                .into_iter()
+                .filter(|webhook| manages_webhook(key.as_ref(), webhook))
+                .collect();
+            HttpResponse::Ok().json(visible)
+        }
+        Err(e) => {
+            error!("Failed to list webhooks: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
-    let records_json: Vec<Option<String>> = match conn.mget(keys).await {
-        Ok(records) => records,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
+}
+
+#[utoipa::path(
+    get,
+    path = "/webhooks/callback-secret",
+    responses(
+        (status = 200, description = "The secret signing deliveries to the callback_url of the calling key's transfers", body = CallbackSecretResponse),
+        (status = 404, description = "WEBHOOK_SECRET is not set, so callback_url can't be used")
+    )
+)]
+#[get("/webhooks/callback-secret")]
+pub async fn get_callback_secret(
+    http_request: HttpRequest,
+    settings: Data<Settings>,
+) -> impl Responder {
+    let Some(key) = auth::key(&http_request) else {
+        return HttpResponse::Unauthorized().finish();
     };
-    let records: Vec<TransactionRecord> = records_json
-        .into_iter()
-        .filter_map(|opt_json_str| {
-            opt_json_str.and_then(|json_str| serde_json::from_str(&json_str).ok())
-        })
-        .collect();
-    HttpResponse::Ok().json(PaginatedTransactionResponse {
-        next_cursor,
-        records,
+    let Some(webhook_secret) = &settings.webhook_secret else {
+        return HttpResponse::NotFound().body("callback_url can't be used: WEBHOOK_SECRET is not set");
+    };
+    HttpResponse::Ok().json(CallbackSecretResponse {
+        secret: webhooks::callback_secret(webhook_secret, &key.id),
     })
 }
 
-
-
+#[utoipa::path(
+    post,
+    path = "/webhooks/{id}/remove",
+    params(("id" = String, Path, description = "ID of the webhook")),
+    responses(
+        (status = 200, description = "The webhook was removed; its pending deliveries fail", body = Webhook),
+        (status = 404, description = "No such webhook, or it belongs to another API key")
+    )
+)]
+#[post("/webhooks/{id}/remove")]
+pub async fn remove_webhook(
+    http_request: HttpRequest,
+    path: Path<String>,
+    redis_pool: Data<Pool>,
+) -> impl Responder {
+    let webhook_id = path.into_inner();
+    let key = auth::key(&http_request);
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match webhooks::get(&mut conn, &webhook_id).await {
+        Ok(Some(webhook)) if manages_webhook(key.as_ref(), &webhook) => {}
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Failed to read webhook {}: {}", webhook_id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    match webhooks::remove(&mut conn, &webhook_id).await {
+        Ok(Some(webhook)) => HttpResponse::Ok().json(webhook),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Failed to remove webhook {}: {}", webhook_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
 
 #[utoipa::path(
     get,
-    path = "/transactions/status/{status}",
+    path = "/webhooks/{id}/deliveries",
     params(
-        ("status" = String, Path, description = "The status to filter by (Queued, Success, Failure)"),
-        ("offset" = Option<u64>, Query, description = "Pagination offset, default 0"),
-        ("limit" = Option<u64>, Query, description = "Pagination limit, default 10")
+        ("id" = String, Path, description = "ID of the webhook"),
+        DeliveryLogQuery
     ),
     responses(
-        (status = 200, description = "A paginated list of transaction records matching the status", body = [TransactionRecord])
+        (status = 200, description = "The webhook's most recent deliveries, newest first, with every attempt", body = [WebhookDelivery]),
+        (status = 404, description = "No such webhook, or it belongs to another API key")
     )
 )]
-#[get("/transactions/status/{status}")]
-pub async fn get_transactions_by_status(
+#[get("/webhooks/{id}/deliveries")]
+pub async fn get_webhook_deliveries(
+    http_request: HttpRequest,
     path: Path<String>,
-    query: Query<Pagination>,
+    query: Query<DeliveryLogQuery>,
     redis_pool: Data<Pool>,
 ) -> impl Responder {
-    let status_str = path.into_inner();
-    let status_to_filter = match status_str.to_lowercase().as_str() {
-        "queued" => TransactionStatus::Queued,
-        "success" => TransactionStatus::Success,
-        "failure" => TransactionStatus::Failure,
-        _ => return HttpResponse::BadRequest().body("Invalid status provided. Use one of: Queued, Success, Failure."),
-    };
-
+    let webhook_id = path.into_inner();
+    let limit = query.limit.unwrap_or(100).min(1000);
+    let key = auth::key(&http_request);
     let mut conn = match redis_pool.get().await {
         Ok(conn) => conn,
         Err(e) => {
@@ -275,60 +1878,76 @@ pub async fn get_transactions_by_status(
             return HttpResponse::InternalServerError().finish();
         }
     };
-
-    let mut all_keys = Vec::new();
-    let mut cursor = 0;
-
-    // Loop through all keys using SCAN
-    loop {
-        let (next_cursor, keys): (u64, Vec<String>) = match redis::cmd("SCAN")
-            .arg(cursor)
-            .arg("MATCH")
-            .arg("txn:*")
-            .arg("COUNT")
-            .arg(100) // Fetch keys in batches of 100
-            .query_async(&mut conn)
-            .await
-        {
-            Ok(res) => res,
-            Err(e) => {
-                error!("Redis SCAN error: {}", e);
-                return HttpResponse::InternalServerError().finish();
-            }
-        };
-        all_keys.extend(keys);
-        if next_cursor == 0 {
-            break;
+    match webhooks::get(&mut conn, &webhook_id).await {
+        Ok(Some(webhook)) if manages_webhook(key.as_ref(), &webhook) => {}
+        Ok(_) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Failed to read webhook {}: {}", webhook_id, e);
+            return HttpResponse::InternalServerError().finish();
         }
-        cursor = next_cursor;
     }
-
-    if all_keys.is_empty() {
-        return HttpResponse::Ok().json(Vec::<TransactionRecord>::new());
+    match webhooks::deliveries_for_webhook(&mut conn, &webhook_id, limit).await {
+        Ok(deliveries) => HttpResponse::Ok().json(deliveries),
+        Err(e) => {
+            error!("Failed to read the deliveries of webhook {}: {}", webhook_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
+}
 
-    let records_json: Vec<Option<String>> = match conn.mget(all_keys).await {
-        Ok(records) => records,
+#[utoipa::path(
+    get,
+    path = "/transaction/{id}/deliveries",
+    params(("id" = String, Path, description = "Unique ID of the transaction")),
+    responses(
+        (status = 200, description = "Deliveries of the transfer's status changes to the calling key's webhooks and, for the key that submitted it, its callback_url, oldest first; every delivery for an admin key", body = [WebhookDelivery])
+    )
+)]
+#[get("/transaction/{id}/deliveries")]
+pub async fn get_transaction_deliveries(
+    http_request: HttpRequest,
+    path: Path<String>,
+    redis_pool: Data<Pool>,
+) -> impl Responder {
+    let tx_id = path.into_inner();
+    let key = auth::key(&http_request);
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Could not get Redis connection: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let webhooks = match webhooks::list(&mut conn).await {
+        Ok(webhooks) => webhooks,
         Err(e) => {
-            error!("Redis MGET error: {}", e);
+            error!("Failed to list webhooks: {}", e);
             return HttpResponse::InternalServerError().finish();
         }
     };
+    match webhooks::deliveries_for_transaction(&mut conn, &tx_id).await {
+        Ok(deliveries) => {
+            let visible: Vec<WebhookDelivery> = deliveries
+                .into_iter()
+                .filter(|delivery| sees_delivery(key.as_ref(), delivery, &webhooks))
+                .collect();
+            HttpResponse::Ok().json(visible)
+        }
+        Err(e) => {
+            error!("Failed to read the deliveries of transaction {}: {}", tx_id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
 
-    // THE FIX: Get pagination parameters and apply them after filtering
-    let offset = query.offset.unwrap_or(0);
-    let limit = query.limit.unwrap_or(10);
-
-    let records: Vec<TransactionRecord> = records_json
-        .into_iter()
-        .filter_map(|opt_json_str| {
-            opt_json_str.and_then(|json_str| serde_json::from_str(&json_str).ok())
-        })
-        .filter(|record: &TransactionRecord| std::mem::discriminant(&record.status) == std::mem::discriminant(&status_to_filter))
-        // Apply pagination to the final filtered list
-        .skip(offset as usize)
-        .take(limit as usize)
-        .collect();
-
-    HttpResponse::Ok().json(records)
-}
\ No newline at end of file
+// Whether `key` may see `delivery`: admins any, others the deliveries to
+// their own webhooks and the callback_url of the transfers they submitted.
+fn sees_delivery(key: Option<&ApiKey>, delivery: &WebhookDelivery, webhooks: &[Webhook]) -> bool {
+    match &delivery.webhook_id {
+        Some(webhook_id) => webhooks
+            .iter()
+            .find(|webhook| &webhook.id == webhook_id)
+            .is_some_and(|webhook| manages_webhook(key, webhook)),
+        None => owns(key, delivery.event.record.api_key_id.as_deref()),
+    }
+}
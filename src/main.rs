@@ -1,21 +1,28 @@
-use actix_web::{App, HttpServer, middleware::Logger, web};
+use actix_web::{App, HttpServer, middleware::{Logger, from_fn}, web};
 use deadpool_redis::{Config, Runtime};
-use futures::future::join_all;
-use log::{error, info};
-use near_api::near_primitives::account::AccessKeyPermission;
-use near_api::near_primitives::views::FinalExecutionStatus;
-use near_api::{signer::generate_secret_key, *};
+use log::{error, info, warn};
+use near_api::*;
 use nearn_ft::{
-    ApiDoc, config::Settings, ft_transfer, get_all_transactions, get_transaction_by_id,
-    get_transactions_by_receiver, types::TokenTransferRequest, worker::run_worker,get_transactions_by_status
+    ApiDoc, config::Settings, ft_transfer, storage::StorageRegistry, get_all_transactions, get_transaction_by_id,
+    get_transactions_by_receiver, worker::run_worker,get_transactions_by_status,
+    cancel_transaction, bulk_transfer, get_campaign, get_campaign_transactions, pause_campaign,
+    resume_campaign, cancel_campaign, get_token, get_balance, balance::BalanceTracker,
+    get_admin_keys, keypool::KeyPool, resize_key_pool, autoscale::PoolManager,
+    auth, create_api_key, get_api_keys, revoke_api_key,
+    get_screening_lists, update_screening_list, screening::Screener, accounts::AccountChecker,
+    create_webhook, get_webhooks, get_callback_secret, remove_webhook, get_webhook_deliveries, get_transaction_deliveries,
+    webhooks::{self, NotifyingStore},
+    token::{DecimalsMismatchMode, fetch_metadata},
+    keystore::{IncompletePoolMode, KeyStore, add_pool_keys, restore_pool_keys},
+    store::{self, TransactionStore},
 };
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::mpsc;
 use url::Url;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
-pub mod types;
+
+const BULK_PAYLOAD_LIMIT: usize = 32 * 1024 * 1024;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -23,7 +30,7 @@ async fn main() -> std::io::Result<()> {
         .format_timestamp_millis()
         .init();
 
-    let settings: Settings = Settings::new().expect("Failed to load settings from Settings.toml");
+    let mut settings: Settings = Settings::new().expect("Failed to load settings from Settings.toml");
 
     // --- Create Redis Connection Pool ---
     let redis_cfg = Config::from_url(&settings.redis_url);
@@ -32,6 +39,16 @@ async fn main() -> std::io::Result<()> {
         .expect("Failed to create Redis pool");
     info!("Redis connection pool created.");
 
+    // --- Open the transaction store ---
+    let transaction_store: Arc<dyn TransactionStore> = store::connect(&settings, redis_pool.clone())
+        .await
+        .expect("Failed to open the transaction store");
+    info!("Transaction records are kept in {:?}.", settings.transaction_store);
+    // Every status change written from here on is delivered to webhooks.
+    let transaction_store: Arc<dyn TransactionStore> =
+        Arc::new(NotifyingStore::new(transaction_store, redis_pool.clone(), &settings));
+    tokio::spawn(webhooks::run_deliveries(redis_pool.clone(), settings.clone()));
+
     let network_config = NetworkConfig {
         network_name: settings.network.clone(),
         // Iterate over the URLs, parse them, create an RPCEndpoint for each,
@@ -53,97 +70,277 @@ async fn main() -> std::io::Result<()> {
         staking_pools_factory_account_id: None,
     };
 
+    // --- Check the configured decimals against the token contract ---
+    let ft_contract_id = near_sdk::AccountId::from_str(&settings.ft_contract_id)
+        .expect("Invalid ft_contract_id in settings");
+    let token_metadata = match fetch_metadata(ft_contract_id.clone(), &network_config).await {
+        Ok(metadata) => {
+            info!(
+                "Token {} ({}) has {} decimals.",
+                metadata.name, metadata.symbol, metadata.decimals
+            );
+            if metadata.decimals != settings.ft_decimals {
+                match settings.decimals_mismatch {
+                    DecimalsMismatchMode::Fail => panic!(
+                        "ft_decimals is {} but {} reports {} decimals",
+                        settings.ft_decimals, settings.ft_contract_id, metadata.decimals
+                    ),
+                    DecimalsMismatchMode::Warn => {
+                        warn!(
+                            "ft_decimals is {} but {} reports {}; using {}.",
+                            settings.ft_decimals,
+                            settings.ft_contract_id,
+                            metadata.decimals,
+                            metadata.decimals
+                        );
+                        settings.ft_decimals = metadata.decimals;
+                    }
+                }
+            }
+            Some(metadata)
+        }
+        Err(e) => match settings.decimals_mismatch {
+            DecimalsMismatchMode::Fail => panic!("Failed to fetch ft_metadata: {}", e),
+            DecimalsMismatchMode::Warn => {
+                warn!(
+                    "Failed to fetch ft_metadata, using ft_decimals = {}: {}",
+                    settings.ft_decimals, e
+                );
+                None
+            }
+        },
+    };
+
     let master_signer: signer::secret_key::SecretKeySigner =
         Signer::from_seed_phrase(&settings.master_key, None)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            .map_err(std::io::Error::other)
             .expect("Failed to create master signer from seed phrase");
 
     let master_signer: Arc<Signer> = Signer::new(master_signer)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-        .expect("Failed to create master signer")
-        .into();
+        .map_err(std::io::Error::other)
+        .expect("Failed to create master signer");
 
+    // --- Reuse the pool keys of previous runs ---
+    let account_id =
+        near_sdk::AccountId::from_str(&settings.account_id).expect("Invalid account_id in settings");
+    let key_store = Arc::new(KeyStore::new(&settings.key_pool_secret));
+    let mut key_conn = redis_pool
+        .get()
+        .await
+        .expect("Failed to get Redis connection for the key pool");
+    let reused_keys = match restore_pool_keys(
+        &key_store,
+        &mut key_conn,
+        &account_id,
+        settings.num_pool_keys,
+        &master_signer,
+        &network_config,
+    )
+    .await
+    {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Failed to restore stored pool keys: {}", e);
+            Vec::new()
+        }
+    };
+    let missing_keys = settings.num_pool_keys.saturating_sub(reused_keys.len());
     info!(
-        "Generating and adding {} keys to the pool...",
-        settings.num_pool_keys
+        "Reusing {} stored pool keys; generating and adding {} more...",
+        reused_keys.len(),
+        missing_keys
     );
 
-    let key_futures = (0..settings.num_pool_keys).map(|_| {
-        let settings = settings.clone();
-        let network_config = network_config.clone();
-        let master_signer = Arc::clone(&master_signer);
-
-        tokio::spawn(async move {
-            let new_secret_key = generate_secret_key().expect("Failed to generate secret key");
-            let new_public_key = new_secret_key.public_key();
-
-            /*let allowance = (settings.key_allowance_near * 1_000_000_000_000_000_000_000_000.0) as u128;
-
-            let ft_contract_id = AccountId::from_str(&settings.ft_contract_id).unwrap();*/
-
-            let result = Account(near_sdk::AccountId::from_str(&settings.account_id).unwrap())
-                .add_key(AccessKeyPermission::FullAccess, new_public_key.clone())
-                .with_signer(Arc::clone(&master_signer))
-                .send_to(&network_config)
-                .await;
-
-            match result {
-                Ok(res) => {
-                    if matches!(res.status, FinalExecutionStatus::SuccessValue(_)) {
-                        info!("Successfully added key: {}", new_public_key);
-                        let new_signer = Signer::from_secret_key(new_secret_key);
-                        master_signer
-                            .add_signer_to_pool(new_signer)
-                            .await
-                            .expect("Failed to add signer to pool");
-                    } else {
-                        error!("Failed to add key {}: {:?}", &new_public_key, res.status);
-                    }
-                }
-                Err(e) => {
-                    error!("Error adding key {}: {}", &new_public_key, e);
-                }
-            }
-        })
-    });
+    let added_keys = match add_pool_keys(
+        &key_store,
+        &mut key_conn,
+        &account_id,
+        missing_keys,
+        &master_signer,
+        &network_config,
+    )
+    .await
+    {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Failed to add pool keys: {}", e);
+            Vec::new()
+        }
+    };
+    drop(key_conn);
 
-    join_all(key_futures).await;
+    let key_pool = Arc::new(KeyPool::new(
+        account_id.clone(),
+        network_config.clone(),
+        settings.key_quarantine_failures,
+        settings.key_quarantine_secs,
+        settings.concurrency,
+    ));
+    for secret_key in reused_keys.iter().chain(added_keys.iter()) {
+        key_pool
+            .add(secret_key.clone())
+            .await
+            .expect("Failed to add signer to pool");
+    }
+    let pool_manager = Arc::new(PoolManager::new(
+        Arc::clone(&key_pool),
+        Arc::clone(&key_store),
+        Arc::clone(&master_signer),
+        account_id.clone(),
+        network_config.clone(),
+        redis_pool.clone(),
+        settings.clone(),
+    ));
+    if settings.autoscale {
+        info!(
+            "Autoscaling the key pool between {} and {} keys.",
+            settings.min_pool_keys, settings.max_pool_keys
+        );
+        tokio::spawn(Arc::clone(&pool_manager).run_autoscaler());
+    }
+    if settings.key_rotation_secs > 0 {
+        info!("Rotating the pool keys every {} seconds.", settings.key_rotation_secs);
+        tokio::spawn(Arc::clone(&pool_manager).run_rotation());
+    }
 
-    info!("Key pool successfully populated.");
+    let recovering_pool = Arc::clone(&key_pool);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            recovering_pool.recover_quarantined().await;
+        }
+    });
 
-    let (tx, rx) = mpsc::channel::<(String, TokenTransferRequest)>(1000);
+    let pool_size = reused_keys.len() + added_keys.len();
+    if pool_size < settings.num_pool_keys {
+        match settings.incomplete_pool {
+            IncompletePoolMode::Fail => panic!(
+                "Only {} of {} pool keys could be provisioned",
+                pool_size, settings.num_pool_keys
+            ),
+            IncompletePoolMode::Degraded => warn!(
+                "Running degraded with {} of {} pool keys.",
+                pool_size, settings.num_pool_keys
+            ),
+        }
+    } else {
+        info!("Key pool ready with {} keys.", pool_size);
+    }
 
     let worker_settings = settings.clone();
     /*let account_id = AccountId::from_str(&settings.account_id).unwrap();
     let ft_contract_id = AccountId::from_str(&settings.ft_contract_id).unwrap();*/
     let worker_signer = Arc::clone(&master_signer);
     let worker_redis_pool = redis_pool.clone();
+    let storage_registry = Arc::new(StorageRegistry::new(
+        ft_contract_id.clone(),
+        network_config.clone(),
+    ));
+    let account_checker = Arc::new(AccountChecker::new(
+        network_config.clone(),
+        std::time::Duration::from_secs(settings.receiver_exists_cache_secs),
+    ));
+
+    // --- Track the sender's token balance ---
+    let balance_tracker = Arc::new(BalanceTracker::new(
+        ft_contract_id,
+        near_sdk::AccountId::from_str(&settings.account_id).expect("Invalid account_id in settings"),
+        network_config.clone(),
+    ));
+    match balance_tracker.restore_reservations(transaction_store.as_ref()).await {
+        Ok(reserved) => info!("Reserved {} for transfers left pending.", reserved),
+        Err(e) => error!("Failed to restore balance reservations: {}", e),
+    }
+    let refresher = Arc::clone(&balance_tracker);
+    let balance_refresh = std::time::Duration::from_secs(settings.balance_refresh_secs.max(1));
+    tokio::spawn(async move {
+        loop {
+            match refresher.refresh().await {
+                Ok(balance) => info!("Sender token balance: {}", balance),
+                Err(e) => error!("Failed to refresh sender balance: {}", e),
+            }
+            tokio::time::sleep(balance_refresh).await;
+        }
+    });
+    let worker_balance = Arc::clone(&balance_tracker);
+    let worker_key_pool = Arc::clone(&key_pool);
+    let worker_store = Arc::clone(&transaction_store);
 
     tokio::spawn(async move {
         run_worker(
-            rx,
             worker_signer,
             worker_settings,
             network_config,
             worker_redis_pool,
+            worker_store,
+            storage_registry,
+            worker_balance,
+            worker_key_pool,
         )
         .await;
     });
 
+    // --- Screen receivers before transfers are accepted ---
+    let screener = Arc::new(
+        Screener::new(&settings).expect("Failed to build the screening hook client"),
+    );
+    if screener.has_hook() {
+        info!(
+            "Screening receivers with the hook at {}.",
+            settings.screening_hook_url.as_deref().unwrap_or_default()
+        );
+    }
+
+    if settings.admin_api_key.is_none() {
+        warn!("ADMIN_API_KEY is not set; only API keys created earlier can call the API.");
+    }
+
     info!("🚀 Server starting at port 8080");
     info!("📚 Swagger UI available at /");
 
     HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(tx.clone()))
             .app_data(web::Data::new(settings.clone()))
+            // Bulk airdrop bodies are far larger than the 256 KiB default.
+            .app_data(web::PayloadConfig::new(BULK_PAYLOAD_LIMIT))
             .app_data(web::Data::new(redis_pool.clone()))
+            .app_data(web::Data::from(Arc::clone(&transaction_store)))
+            .app_data(web::Data::new(token_metadata.clone()))
+            .app_data(web::Data::from(Arc::clone(&balance_tracker)))
+            .app_data(web::Data::from(Arc::clone(&key_pool)))
+            .app_data(web::Data::from(Arc::clone(&pool_manager)))
+            .app_data(web::Data::from(Arc::clone(&screener)))
+            .app_data(web::Data::from(Arc::clone(&account_checker)))
+            // Registered before the logger so rejected requests are logged too.
+            .wrap(from_fn(auth::require_api_key))
             .wrap(Logger::new("%r %T"))
             .service(ft_transfer)
             .service(get_transaction_by_id)
             .service(get_transactions_by_receiver)
             .service(get_all_transactions)
             .service(get_transactions_by_status)
+            .service(cancel_transaction)
+            .service(bulk_transfer)
+            .service(get_campaign)
+            .service(get_campaign_transactions)
+            .service(pause_campaign)
+            .service(resume_campaign)
+            .service(cancel_campaign)
+            .service(get_token)
+            .service(get_balance)
+            .service(get_admin_keys)
+            .service(resize_key_pool)
+            .service(create_api_key)
+            .service(get_api_keys)
+            .service(revoke_api_key)
+            .service(get_screening_lists)
+            .service(update_screening_list)
+            .service(create_webhook)
+            .service(get_webhooks)
+            .service(get_callback_secret)
+            .service(remove_webhook)
+            .service(get_webhook_deliveries)
+            .service(get_transaction_deliveries)
             .service(SwaggerUi::new("/{_:.*}").url("/api-docs/openapi.json", ApiDoc::openapi()))
     })
     .bind(("0.0.0.0", 8080))?
@@ -0,0 +1,408 @@
+use crate::config::Settings;
+use crate::store::{StoreResult, TransactionStore};
+use crate::types::{TransactionRecord, TransactionStatus};
+use chrono::{Duration, Utc};
+use deadpool_redis::Connection;
+use redis::{AsyncCommands, ExistenceCheck, RedisResult, SetExpiry, SetOptions};
+
+// Records read per page while summing a receiver's recent transfers.
+const HISTORY_PAGE_SIZE: usize = 100;
+/// How long a receiver stays locked by a single transfer, or by a bulk
+/// request while its campaign is validated and stored, if the holder dies
+/// before releasing it.
+pub const TRANSFER_LOCK_TTL_MS: u64 = 10_000;
+pub const CAMPAIGN_LOCK_TTL_MS: u64 = 10 * 60 * 1_000;
+// How long a dedupe key claimed for a transfer whose record isn't stored yet
+// is kept, so a holder that dies in between doesn't keep it forever.
+const PENDING_DEDUPE_TTL_MS: u64 = 10 * 60 * 1_000;
+const LOCK_ATTEMPTS: usize = 40;
+const LOCK_RETRY_MS: u64 = 50;
+
+// Deletes KEYS[1] only while it still holds ARGV[1].
+const RELEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+// Deletes each of KEYS only while it still holds ARGV[1].
+const RELEASE_ALL_SCRIPT: &str = r"
+for i = 1, #KEYS do
+    if redis.call('GET', KEYS[i]) == ARGV[1] then
+        redis.call('DEL', KEYS[i])
+    end
+end
+return 0
+";
+
+// Replaces KEYS[1] with ARGV[2], expiring in ARGV[3] ms, only while it
+// still holds ARGV[1].
+const REPLACE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+    return 1
+end
+return 0
+";
+
+// Keeps KEYS[i] for good while it still holds ARGV[i].
+const CONFIRM_SCRIPT: &str = r"
+for i = 1, #KEYS do
+    if redis.call('GET', KEYS[i]) == ARGV[i] then
+        redis.call('PERSIST', KEYS[i])
+    end
+end
+return 0
+";
+
+fn lock_key(receiver_id: &str) -> String {
+    format!("receiver_lock:{}", receiver_id)
+}
+
+// Transfer ID that paid out `dedupe_key` to the receiver.
+fn payout_key(receiver_id: &str, dedupe_key: &str) -> String {
+    format!("payout_dedupe:{}:{}", receiver_id, dedupe_key)
+}
+
+/// Whether the per-receiver caps are switched on.
+pub fn caps_enabled(settings: &Settings) -> bool {
+    Caps::from_settings(settings).enabled()
+}
+
+// The per-receiver caps; 0 switches one off.
+#[derive(Debug, Clone, Copy)]
+struct Caps {
+    window_secs: u64,
+    max_transfers: u64,
+    max_amount: u128,
+}
+
+impl Caps {
+    fn from_settings(settings: &Settings) -> Self {
+        Self {
+            window_secs: settings.receiver_window_secs,
+            max_transfers: settings.receiver_max_transfers,
+            max_amount: settings.receiver_max_amount,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_transfers > 0 || self.max_amount > 0
+    }
+}
+
+// Transfers that did or still may send tokens.
+fn counts_as_paid(record: &TransactionRecord) -> bool {
+    !matches!(
+        record.status,
+        TransactionStatus::Failure | TransactionStatus::Cancelled | TransactionStatus::Expired
+    )
+}
+
+/// What a receiver was sent within the last `receiver_window_secs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReceiverUsage {
+    pub transfers: u64,
+    pub amount: u128,
+}
+
+impl ReceiverUsage {
+    pub fn add(&mut self, amount: u128) {
+        self.transfers += 1;
+        self.amount = self.amount.saturating_add(amount);
+    }
+}
+
+/// Sums the receiver's transfers within the window from its history,
+/// leaving out the ones that ended without paying.
+pub async fn usage(
+    store: &dyn TransactionStore,
+    settings: &Settings,
+    receiver_id: &str,
+) -> StoreResult<ReceiverUsage> {
+    usage_within(store, Caps::from_settings(settings).window_secs, receiver_id).await
+}
+
+async fn usage_within(
+    store: &dyn TransactionStore,
+    window_secs: u64,
+    receiver_id: &str,
+) -> StoreResult<ReceiverUsage> {
+    let since = Utc::now() - Duration::seconds(window_secs as i64);
+    let mut usage = ReceiverUsage::default();
+    let mut offset = 0;
+    // Receiver listings are newest first, so stop at the first older record.
+    loop {
+        let page = store
+            .list_by_receiver(receiver_id, offset, HISTORY_PAGE_SIZE)
+            .await?;
+        for record in &page {
+            if record.created_at < since {
+                return Ok(usage);
+            }
+            if counts_as_paid(record) {
+                usage.add(record.raw_amount());
+            }
+        }
+        if page.len() < HISTORY_PAGE_SIZE {
+            return Ok(usage);
+        }
+        offset += page.len();
+    }
+}
+
+/// Checks that sending `amount` on top of `usage` keeps the receiver within
+/// `receiver_max_transfers` and `receiver_max_amount`, saying why not
+/// otherwise.
+pub fn check_caps(
+    settings: &Settings,
+    receiver_id: &str,
+    usage: &ReceiverUsage,
+    amount: u128,
+) -> Result<(), String> {
+    check_within(&Caps::from_settings(settings), receiver_id, usage, amount)
+}
+
+fn check_within(
+    caps: &Caps,
+    receiver_id: &str,
+    usage: &ReceiverUsage,
+    amount: u128,
+) -> Result<(), String> {
+    if caps.max_transfers > 0 && usage.transfers >= caps.max_transfers {
+        return Err(format!(
+            "{} already received {} transfers in the last {}s, the most allowed.",
+            receiver_id, usage.transfers, caps.window_secs
+        ));
+    }
+    if caps.max_amount > 0 && usage.amount.saturating_add(amount) > caps.max_amount {
+        return Err(format!(
+            "{} already received {} in the last {}s; {} more would exceed the limit of {}.",
+            receiver_id, usage.amount, caps.window_secs, amount, caps.max_amount
+        ));
+    }
+    Ok(())
+}
+
+/// Takes the receiver's lock for `holder` for up to `ttl_ms`, so its caps
+/// are checked and the transfer stored before another transfer to it is
+/// checked. Waits up to about two seconds; returns `false` if the lock
+/// stayed taken.
+pub async fn lock(
+    conn: &mut Connection,
+    receiver_id: &str,
+    holder: &str,
+    ttl_ms: u64,
+) -> RedisResult<bool> {
+    for _ in 0..LOCK_ATTEMPTS {
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::PX(ttl_ms));
+        let set: Option<String> = conn
+            .set_options(lock_key(receiver_id), holder, options)
+            .await?;
+        if set.is_some() {
+            return Ok(true);
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(LOCK_RETRY_MS)).await;
+    }
+    Ok(false)
+}
+
+pub async fn unlock(conn: &mut Connection, receiver_id: &str, holder: &str) -> RedisResult<()> {
+    redis::Script::new(RELEASE_SCRIPT)
+        .key(lock_key(receiver_id))
+        .arg(holder)
+        .invoke_async(conn)
+        .await
+}
+
+/// Releases every receiver lock `holder` took.
+pub async fn unlock_all(
+    conn: &mut Connection,
+    receiver_ids: &[&str],
+    holder: &str,
+) -> RedisResult<()> {
+    if receiver_ids.is_empty() {
+        return Ok(());
+    }
+    let script = redis::Script::new(RELEASE_ALL_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    for receiver_id in receiver_ids {
+        invocation.key(lock_key(receiver_id));
+    }
+    invocation.arg(holder).invoke_async(conn).await
+}
+
+// Whether the transfer holding a dedupe key keeps it: it did or may still
+// pay, or its record isn't stored yet while the key is still `pending`.
+// A key whose record is gone after it was stored can be taken over.
+fn holder_keeps(record: Option<&TransactionRecord>, pending: bool) -> bool {
+    match record {
+        Some(record) => counts_as_paid(record),
+        None => pending,
+    }
+}
+
+/// Binds `key` for `receiver_id` to `transaction_id`. If it is already
+/// bound, the transfer it points to wins unless that transfer ended without
+/// paying, in which case the key is taken over. Returns the ID of the
+/// transfer that already holds the key when it can't be claimed.
+///
+/// The claim expires unless `confirm_dedupe_keys` is called once the
+/// transfer's record is stored.
+pub async fn claim_dedupe_key(
+    conn: &mut Connection,
+    store: &dyn TransactionStore,
+    receiver_id: &str,
+    key: &str,
+    transaction_id: &str,
+) -> StoreResult<Option<String>> {
+    let redis_key = payout_key(receiver_id, key);
+    loop {
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::PX(PENDING_DEDUPE_TTL_MS));
+        let set: Option<String> = conn
+            .set_options(&redis_key, transaction_id, options)
+            .await?;
+        if set.is_some() {
+            return Ok(None);
+        }
+
+        let (holder, ttl_ms): (Option<String>, i64) = redis::pipe()
+            .get(&redis_key)
+            .pttl(&redis_key)
+            .query_async(conn)
+            .await?;
+        // Released between SET and GET; try again.
+        let Some(holder) = holder else { continue };
+        let record = store.get(&holder).await?;
+        if holder_keeps(record.as_ref(), ttl_ms >= 0) {
+            return Ok(Some(holder));
+        }
+        let replaced: bool = redis::Script::new(REPLACE_SCRIPT)
+            .key(&redis_key)
+            .arg(&holder)
+            .arg(transaction_id)
+            .arg(PENDING_DEDUPE_TTL_MS)
+            .invoke_async(conn)
+            .await?;
+        if replaced {
+            return Ok(None);
+        }
+    }
+}
+
+/// Keeps the dedupe keys claimed for stored transfers, given as
+/// `(receiver_id, key, transaction_id)`, for good.
+pub async fn confirm_dedupe_keys(
+    conn: &mut Connection,
+    claims: &[(&str, &str, &str)],
+) -> RedisResult<()> {
+    if claims.is_empty() {
+        return Ok(());
+    }
+    let script = redis::Script::new(CONFIRM_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    for (receiver_id, key, transaction_id) in claims {
+        invocation.key(payout_key(receiver_id, key)).arg(*transaction_id);
+    }
+    invocation.invoke_async(conn).await
+}
+
+/// Gives up a key claimed for a transfer that was not accepted after all.
+pub async fn release_dedupe_key(
+    conn: &mut Connection,
+    receiver_id: &str,
+    key: &str,
+    transaction_id: &str,
+) -> RedisResult<()> {
+    redis::Script::new(RELEASE_SCRIPT)
+        .key(payout_key(receiver_id, key))
+        .arg(transaction_id)
+        .invoke_async(conn)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::types::TokenTransferRequest;
+
+    fn record(receiver: &str, amount: u128, age_secs: i64) -> TransactionRecord {
+        let request: TokenTransferRequest = serde_json::from_value(serde_json::json!({
+            "reciever_id": receiver,
+            "amount": amount.to_string(),
+        }))
+        .unwrap();
+        let mut record = TransactionRecord::new("sender.near".to_string(), request, 0);
+        record.created_at = Utc::now() - Duration::seconds(age_secs);
+        record
+    }
+
+    fn ended(mut record: TransactionRecord, status: TransactionStatus) -> TransactionRecord {
+        record.transition(TransactionStatus::Batched).unwrap();
+        record.transition(TransactionStatus::Submitted).unwrap();
+        record.transition(status).unwrap();
+        record
+    }
+
+    fn caps(max_transfers: u64, max_amount: u128) -> Caps {
+        Caps {
+            window_secs: 3_600,
+            max_transfers,
+            max_amount,
+        }
+    }
+
+    #[actix_web::test]
+    async fn usage_counts_paying_transfers_within_the_window() {
+        let store = MemoryStore::default();
+        let records = [
+            record("bob.near", 100, 7_200),
+            record("bob.near", 10, 1_800),
+            ended(record("bob.near", 1_000, 900), TransactionStatus::Failure),
+            ended(record("bob.near", 20, 600), TransactionStatus::Success),
+            record("alice.near", 5, 60),
+        ];
+        store.create(&records).await.unwrap();
+
+        let usage = usage_within(&store, 3_600, "bob.near").await.unwrap();
+        assert_eq!((usage.transfers, usage.amount), (2, 30));
+        let usage = usage_within(&store, 10_000, "bob.near").await.unwrap();
+        assert_eq!((usage.transfers, usage.amount), (3, 130));
+        let usage = usage_within(&store, 3_600, "carol.near").await.unwrap();
+        assert_eq!((usage.transfers, usage.amount), (0, 0));
+    }
+
+    #[test]
+    fn reaching_a_cap_exactly_is_allowed() {
+        let usage = ReceiverUsage {
+            transfers: 2,
+            amount: 70,
+        };
+        assert!(check_within(&caps(3, 100), "bob.near", &usage, 30).is_ok());
+        assert!(check_within(&caps(3, 100), "bob.near", &usage, 31).is_err());
+        assert!(check_within(&caps(2, 0), "bob.near", &usage, 1).is_err());
+        assert!(check_within(&caps(0, 0), "bob.near", &usage, u128::MAX).is_ok());
+    }
+
+    #[test]
+    fn dedupe_keys_are_taken_over_only_from_transfers_that_did_not_pay() {
+        let queued = record("bob.near", 10, 0);
+        let paid = ended(record("bob.near", 10, 0), TransactionStatus::Success);
+        let failed = ended(record("bob.near", 10, 0), TransactionStatus::Failure);
+        for pending in [true, false] {
+            assert!(holder_keeps(Some(&queued), pending));
+            assert!(holder_keeps(Some(&paid), pending));
+            assert!(!holder_keeps(Some(&failed), pending));
+        }
+        // A holder still being accepted keeps the key; one whose stored
+        // record is gone doesn't.
+        assert!(holder_keeps(None, true));
+        assert!(!holder_keeps(None, false));
+    }
+}
@@ -0,0 +1,197 @@
+use crate::campaign;
+use deadpool_redis::Connection;
+use redis::{AsyncCommands, Direction, RedisResult};
+
+// Transfers waiting to be picked up by the worker. Producers LPUSH, the worker
+// pops from the right, so the list behaves as a FIFO.
+pub const QUEUE_KEY: &str = "transfer_queue";
+
+// Transfers the worker has taken off the queue but whose outcome has not been
+// written back yet. Entries are only removed once the record is updated.
+pub const PROCESSING_KEY: &str = "transfer_processing";
+
+/// Every transfer in the processing list, newest first. Read once at
+/// startup: these were taken by a previous process that died before writing
+/// their outcome.
+pub async fn in_flight(conn: &mut Connection) -> RedisResult<Vec<String>> {
+    conn.lrange(PROCESSING_KEY, 0, -1).await
+}
+
+/// Moves transfers that were never sent from the processing list back onto
+/// the queue, ahead of the ones waiting there and oldest first.
+pub async fn requeue(conn: &mut Connection, ids: &[String]) -> RedisResult<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for id in ids {
+        pipe.lrem(PROCESSING_KEY, 1, id).ignore();
+        pipe.rpush(QUEUE_KEY, id).ignore();
+    }
+    pipe.query_async(conn).await
+}
+
+/// Blocks until at least one transfer is available, then keeps collecting
+/// until `batch_size` is reached or no new transfer shows up within
+/// `batch_timeout_secs`. Every returned ID has been atomically moved to the
+/// processing list and must later be passed to [`ack`].
+pub async fn next_batch(
+    conn: &mut Connection,
+    batch_size: usize,
+    batch_timeout_secs: u64,
+) -> RedisResult<Vec<String>> {
+    let mut batch = Vec::with_capacity(batch_size);
+
+    // Wait for the first item in short slices so a dropped connection is noticed.
+    loop {
+        let first: Option<String> = conn
+            .blmove(QUEUE_KEY, PROCESSING_KEY, Direction::Right, Direction::Left, 5.0)
+            .await?;
+        if let Some(id) = first {
+            batch.push(id);
+            break;
+        }
+    }
+
+    while batch.len() < batch_size {
+        let next: Option<String> = conn
+            .blmove(
+                QUEUE_KEY,
+                PROCESSING_KEY,
+                Direction::Right,
+                Direction::Left,
+                // A zero timeout would make BLMOVE block forever.
+                (batch_timeout_secs as f64).max(0.1),
+            )
+            .await?;
+        match next {
+            Some(id) => batch.push(id),
+            None => break,
+        }
+    }
+
+    Ok(batch)
+}
+
+/// Queues transfers whose records are already in the transaction store.
+pub async fn enqueue(conn: &mut Connection, ids: &[String]) -> RedisResult<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    conn.lpush(QUEUE_KEY, ids).await
+}
+
+/// Removes transfers from the processing list once their outcome is stored.
+pub async fn ack(conn: &mut Connection, ids: &[String]) -> RedisResult<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let mut pipe = redis::pipe();
+    for id in ids {
+        pipe.lrem(PROCESSING_KEY, 1, id).ignore();
+    }
+    pipe.query_async(conn).await
+}
+
+// Transfers waiting out a retry backoff, scored by the Unix time in
+// milliseconds at which they may be queued again.
+pub const RETRY_KEY: &str = "transfer_retry";
+
+/// Parks a transfer in the retry set until `ready_at_ms`, taking it off the
+/// processing list in the same MULTI. Its `Retrying` record is stored first.
+pub async fn schedule_retry(conn: &mut Connection, id: &str, ready_at_ms: i64) -> RedisResult<()> {
+    redis::pipe()
+        .atomic()
+        .zadd(RETRY_KEY, id, ready_at_ms)
+        .ignore()
+        .lrem(PROCESSING_KEY, 1, id)
+        .ignore()
+        .query_async(conn)
+        .await
+}
+
+/// Moves retries whose backoff has elapsed back onto the queue. Runs as a
+/// script so an ID is never in both places, or in neither.
+pub async fn promote_due_retries(conn: &mut Connection, now_ms: i64) -> RedisResult<usize> {
+    let script = redis::Script::new(
+        r"
+        local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, 500)
+        for _, id in ipairs(ids) do
+            redis.call('ZREM', KEYS[1], id)
+            redis.call('LPUSH', KEYS[2], id)
+        end
+        return #ids
+        ",
+    );
+    script
+        .key(RETRY_KEY)
+        .key(QUEUE_KEY)
+        .arg(now_ms)
+        .invoke_async(conn)
+        .await
+}
+
+/// Takes a transfer out of the queue, the retry set or, for a transfer of a
+/// campaign, the campaign's paused list before the worker picks it up.
+/// Returns `false` if it was in none of them, i.e. it is already being
+/// processed or finished.
+pub async fn withdraw(
+    conn: &mut Connection,
+    id: &str,
+    campaign_id: Option<&str>,
+) -> RedisResult<bool> {
+    let mut pipe = redis::pipe();
+    pipe.atomic().lrem(QUEUE_KEY, 1, id).zrem(RETRY_KEY, id);
+    if let Some(campaign_id) = campaign_id {
+        pipe.lrem(campaign::paused_key(campaign_id), 1, id);
+    }
+    let removed: Vec<usize> = pipe.query_async(conn).await?;
+    Ok(removed.iter().sum::<usize>() > 0)
+}
+
+// Signed batch transactions by hash, from before they are broadcast until
+// their outcome is stored, so they can be looked up and sent again as is.
+pub const SUBMITTED_KEY: &str = "transfer_submitted";
+
+// Submitted transfers whose outcome is unknown, scored by the Unix time in
+// milliseconds at which their batch is looked up. They stay in the
+// processing list meanwhile.
+pub const UNRESOLVED_KEY: &str = "transfer_unresolved";
+
+/// Stores a signed batch under its hash before it is broadcast.
+pub async fn save_submitted(conn: &mut Connection, hash: &str, batch_json: &str) -> RedisResult<()> {
+    conn.hset(SUBMITTED_KEY, hash, batch_json).await
+}
+
+pub async fn load_submitted(conn: &mut Connection, hash: &str) -> RedisResult<Option<String>> {
+    conn.hget(SUBMITTED_KEY, hash).await
+}
+
+/// Drops a signed batch once the outcome of its transfers is stored.
+pub async fn forget_submitted(conn: &mut Connection, hash: &str) -> RedisResult<()> {
+    conn.hdel(SUBMITTED_KEY, hash).await
+}
+
+/// Has submitted transfers looked up at `ready_at_ms`.
+pub async fn park_unresolved(conn: &mut Connection, ids: &[String], ready_at_ms: i64) -> RedisResult<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let members: Vec<(i64, &String)> = ids.iter().map(|id| (ready_at_ms, id)).collect();
+    conn.zadd_multiple(UNRESOLVED_KEY, &members).await
+}
+
+/// Takes the unresolved transfers that are due for a lookup.
+pub async fn take_due_unresolved(conn: &mut Connection, now_ms: i64) -> RedisResult<Vec<String>> {
+    let script = redis::Script::new(
+        r"
+        local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, 100)
+        for _, id in ipairs(ids) do
+            redis.call('ZREM', KEYS[1], id)
+        end
+        return ids
+        ",
+    );
+    script.key(UNRESOLVED_KEY).arg(now_ms).invoke_async(conn).await
+}
@@ -0,0 +1,368 @@
+use crate::amount;
+use crate::config::Settings;
+use crate::types::ApiKey;
+use chrono::Utc;
+use deadpool_redis::Connection;
+use redis::RedisResult;
+use std::sync::LazyLock;
+
+// Adds `increment` to every counter unless one of them would go over its
+// limit, in which case nothing is changed. Counts are decimal strings since
+// token amounts don't fit in Redis integers.
+// KEYS[i] counter; ARGV[3i-2] increment, ARGV[3i-1] limit, ARGV[3i] TTL in ms.
+// Returns {0, '0'} if every counter was increased, or {i, used} for the
+// first counter that would have gone over. Run after `amount::LUA_ADD`.
+const CONSUME_SCRIPT: &str = r"
+local function greater(a, b)
+    if #a ~= #b then
+        return #a > #b
+    end
+    return a > b
+end
+
+for i = 1, #KEYS do
+    local used = redis.call('GET', KEYS[i]) or '0'
+    if greater(add(used, ARGV[3 * i - 2]), ARGV[3 * i - 1]) then
+        return {i, used}
+    end
+end
+for i = 1, #KEYS do
+    local used = redis.call('GET', KEYS[i]) or '0'
+    redis.call('SET', KEYS[i], add(used, ARGV[3 * i - 2]), 'PX', ARGV[3 * i])
+end
+return {0, '0'}
+";
+
+static CONSUME: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(&format!("{}{}", amount::LUA_ADD, CONSUME_SCRIPT)));
+
+// Takes ARGV[i] back off KEYS[i], never going below zero. Counters that
+// already expired are left alone.
+const REFUND_SCRIPT: &str = r"
+local function sub(a, b)
+    if #a < #b or (#a == #b and a <= b) then
+        return '0'
+    end
+    local digits = {}
+    local borrow = 0
+    local i, j = #a, #b
+    while i > 0 do
+        local da = tonumber(string.sub(a, i, i)) - borrow
+        local db = j > 0 and tonumber(string.sub(b, j, j)) or 0
+        if da < db then
+            da = da + 10
+            borrow = 1
+        else
+            borrow = 0
+        end
+        table.insert(digits, 1, tostring(da - db))
+        i = i - 1
+        j = j - 1
+    end
+    local result = string.gsub(table.concat(digits), '^0+', '')
+    if result == '' then
+        return '0'
+    end
+    return result
+end
+
+for i = 1, #KEYS do
+    local used = redis.call('GET', KEYS[i])
+    if used then
+        redis.call('SET', KEYS[i], sub(used, ARGV[i]), 'KEEPTTL')
+    end
+end
+return 1
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitKind {
+    RequestsPerSecond,
+    AmountPerHour,
+    AmountPerDay,
+}
+
+impl LimitKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LimitKind::RequestsPerSecond => "requests_per_second",
+            LimitKind::AmountPerHour => "amount_per_hour",
+            LimitKind::AmountPerDay => "amount_per_day",
+        }
+    }
+
+    fn window_ms(&self) -> i64 {
+        match self {
+            LimitKind::RequestsPerSecond => 1_000,
+            LimitKind::AmountPerHour => 60 * 60 * 1_000,
+            LimitKind::AmountPerDay => 24 * 60 * 60 * 1_000,
+        }
+    }
+
+    fn is_amount(&self) -> bool {
+        *self != LimitKind::RequestsPerSecond
+    }
+}
+
+/// A cap on requests or on the raw amount sent within a fixed window.
+#[derive(Debug, Clone)]
+pub struct Limit {
+    /// The API key it applies to, or `None` for the limit shared by all.
+    pub key_id: Option<String>,
+    pub kind: LimitKind,
+    pub max: u128,
+}
+
+impl Limit {
+    /// `key:amount_per_hour`, `global:requests_per_second` and so on.
+    pub fn scope(&self) -> String {
+        let subject = if self.key_id.is_some() {
+            "key"
+        } else {
+            "global"
+        };
+        format!("{}:{}", subject, self.kind.name())
+    }
+
+    // Counter of the window `now_ms` falls in, and the ms until it ends.
+    fn counter(&self, now_ms: i64) -> (String, i64) {
+        let window_ms = self.kind.window_ms();
+        let bucket = now_ms / window_ms;
+        let subject = match &self.key_id {
+            Some(key_id) => format!("key:{}", key_id),
+            None => "global".to_string(),
+        };
+        (
+            format!("rate:{}:{}:{}", subject, self.kind.name(), bucket),
+            (bucket + 1) * window_ms - now_ms,
+        )
+    }
+}
+
+/// The limit a request ran into and how long until its window resets.
+#[derive(Debug, Clone)]
+pub struct Exceeded {
+    pub limit: Limit,
+    /// Usage of the current window before this request.
+    pub used: u128,
+    pub retry_after_secs: u64,
+}
+
+/// Amount counters increased by `spend`, so they can be given back.
+pub struct Spent {
+    counters: Vec<String>,
+    amount: u128,
+}
+
+/// The limits that apply to a request made with `key`: the key's own
+/// limits (from the key, falling back to the configured defaults) and the
+/// global ones. Limits set to 0 are left out.
+pub fn limits_for(settings: &Settings, key: Option<&ApiKey>) -> Vec<Limit> {
+    let key_defaults = [
+        settings.key_requests_per_second as u128,
+        settings.key_amount_per_hour,
+        settings.key_amount_per_day,
+    ];
+    let global = [
+        settings.global_requests_per_second as u128,
+        settings.global_amount_per_hour,
+        settings.global_amount_per_day,
+    ];
+    build_limits(key_defaults, global, key)
+}
+
+const KINDS: [LimitKind; 3] = [
+    LimitKind::RequestsPerSecond,
+    LimitKind::AmountPerHour,
+    LimitKind::AmountPerDay,
+];
+
+// `limits_for` with the configured maxima given in `KINDS` order.
+fn build_limits(key_defaults: [u128; 3], global: [u128; 3], key: Option<&ApiKey>) -> Vec<Limit> {
+    let parse = |amount: &Option<String>| amount.as_deref().and_then(|a| a.parse::<u128>().ok());
+    let mut limits = Vec::new();
+    if let Some(key) = key {
+        let overrides = key.limits.as_ref();
+        let per_key = [
+            overrides
+                .and_then(|limits| limits.requests_per_second)
+                .map(u128::from),
+            overrides.and_then(|limits| parse(&limits.amount_per_hour)),
+            overrides.and_then(|limits| parse(&limits.amount_per_day)),
+        ];
+        limits.extend(
+            KINDS
+                .into_iter()
+                .zip(per_key.into_iter().zip(key_defaults))
+                .map(|(kind, (max, default))| Limit {
+                    key_id: Some(key.id.clone()),
+                    kind,
+                    max: max.unwrap_or(default),
+                }),
+        );
+    }
+    limits.extend(KINDS.into_iter().zip(global).map(|(kind, max)| Limit {
+        key_id: None,
+        kind,
+        max,
+    }));
+    limits.retain(|limit| limit.max > 0);
+    limits
+}
+
+async fn consume(
+    conn: &mut Connection,
+    limits: Vec<&Limit>,
+    increment: u128,
+) -> RedisResult<Result<Vec<String>, Exceeded>> {
+    if limits.is_empty() {
+        return Ok(Ok(Vec::new()));
+    }
+    let now_ms = Utc::now().timestamp_millis();
+    let mut invocation = CONSUME.prepare_invoke();
+    let mut counters = Vec::with_capacity(limits.len());
+    let mut resets = Vec::with_capacity(limits.len());
+    for limit in &limits {
+        let (counter, ttl_ms) = limit.counter(now_ms);
+        invocation
+            .key(&counter)
+            .arg(increment.to_string())
+            .arg(limit.max.to_string())
+            .arg(ttl_ms);
+        counters.push(counter);
+        resets.push(ttl_ms);
+    }
+    let (index, used): (usize, String) = invocation.invoke_async(conn).await?;
+    if index == 0 {
+        return Ok(Ok(counters));
+    }
+    Ok(Err(Exceeded {
+        limit: limits[index - 1].clone(),
+        used: used.parse().unwrap_or(0),
+        retry_after_secs: (resets[index - 1] as u64).div_ceil(1_000).max(1),
+    }))
+}
+
+/// Counts one request against the request-rate limits.
+pub async fn check_request(
+    conn: &mut Connection,
+    limits: &[Limit],
+) -> RedisResult<Result<(), Exceeded>> {
+    let limits = limits
+        .iter()
+        .filter(|limit| !limit.kind.is_amount())
+        .collect();
+    Ok(consume(conn, limits, 1).await?.map(|_| ()))
+}
+
+/// Counts `amount` against the amount quotas, unless that would take any of
+/// them over its limit.
+pub async fn spend(
+    conn: &mut Connection,
+    limits: &[Limit],
+    amount: u128,
+) -> RedisResult<Result<Spent, Exceeded>> {
+    let limits = limits
+        .iter()
+        .filter(|limit| limit.kind.is_amount())
+        .collect();
+    Ok(consume(conn, limits, amount)
+        .await?
+        .map(|counters| Spent { counters, amount }))
+}
+
+/// Gives back an amount whose transfer was not accepted after all.
+pub async fn refund(conn: &mut Connection, spent: &Spent) -> RedisResult<()> {
+    if spent.counters.is_empty() {
+        return Ok(());
+    }
+    let script = redis::Script::new(REFUND_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    for counter in &spent.counters {
+        invocation.key(counter).arg(spent.amount.to_string());
+    }
+    invocation.invoke_async(conn).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ClientLimits;
+
+    fn key(limits: Option<ClientLimits>) -> ApiKey {
+        ApiKey {
+            id: "key-a".to_string(),
+            name: "test".to_string(),
+            scopes: Vec::new(),
+            created_at: Utc::now(),
+            revoked_at: None,
+            limits,
+        }
+    }
+
+    fn maxima(limits: &[Limit]) -> Vec<(String, u128)> {
+        limits
+            .iter()
+            .map(|limit| (limit.scope(), limit.max))
+            .collect()
+    }
+
+    #[test]
+    fn requests_without_a_key_get_only_the_global_limits() {
+        let limits = build_limits([5, 100, 1_000], [50, 0, 10_000], None);
+        assert_eq!(
+            maxima(&limits),
+            [
+                ("global:requests_per_second".to_string(), 50),
+                ("global:amount_per_day".to_string(), 10_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn key_limits_override_the_defaults_and_zero_turns_them_off() {
+        let key = key(Some(ClientLimits {
+            requests_per_second: Some(0),
+            amount_per_hour: Some("340282366920938463463374607431768211455".to_string()),
+            amount_per_day: Some("not a number".to_string()),
+        }));
+        let limits = build_limits([5, 100, 1_000], [0, 0, 0], Some(&key));
+        assert_eq!(
+            maxima(&limits),
+            [
+                ("key:amount_per_hour".to_string(), u128::MAX),
+                ("key:amount_per_day".to_string(), 1_000),
+            ]
+        );
+        assert!(limits.iter().all(|limit| limit.key_id.as_deref() == Some("key-a")));
+    }
+
+    #[test]
+    fn counters_are_per_window_and_expire_at_its_end() {
+        let limit = Limit {
+            key_id: Some("key-a".to_string()),
+            kind: LimitKind::AmountPerHour,
+            max: 1,
+        };
+        let hour = 60 * 60 * 1_000;
+        assert_eq!(
+            limit.counter(3 * hour),
+            ("rate:key:key-a:amount_per_hour:3".to_string(), hour)
+        );
+        assert_eq!(
+            limit.counter(4 * hour - 1),
+            ("rate:key:key-a:amount_per_hour:3".to_string(), 1)
+        );
+        assert_eq!(limit.counter(4 * hour).0, "rate:key:key-a:amount_per_hour:4");
+
+        let global = Limit {
+            key_id: None,
+            kind: LimitKind::RequestsPerSecond,
+            max: 1,
+        };
+        assert_eq!(
+            global.counter(12_345),
+            ("rate:global:requests_per_second:12".to_string(), 655)
+        );
+    }
+}
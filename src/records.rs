@@ -0,0 +1,214 @@
+use crate::amount;
+use crate::campaign;
+use crate::types::{TransactionRecord, TransactionStatus};
+use deadpool_redis::Connection;
+use redis::{AsyncCommands, Pipeline, RedisResult, Script};
+use std::sync::LazyLock;
+
+// Set once the status index has been built from the existing `txn:*` keys.
+const STATUS_INDEX_READY_KEY: &str = "txns_by_status:ready";
+
+/// Sorted set of the IDs of every transaction in `status`, scored by
+/// `created_at` in milliseconds.
+pub fn status_index_key(status: TransactionStatus) -> String {
+    format!("txns_by_status:{}", status)
+}
+
+// Writes a record and moves it between the status indexes, but only if the
+// record currently stored is in one of the allowed statuses (or, for '', is
+// missing). The check and the write happen in one script, so concurrent
+// writers can't overwrite each other's transitions. Terminal campaign
+// transfers are added to the campaign's totals exactly once; amounts are
+// added as decimal strings since Redis integers stop at 2^63 and token
+// amounts don't.
+// KEYS[1] record, KEYS[2..9] status indexes in `TransactionStatus::ALL`
+// order; for campaign transfers also KEYS[10..17] the campaign's status
+// indexes, KEYS[18] its settled set and KEYS[19] its stats hash.
+// ARGV[1] record JSON, ARGV[2] position of the new status in ALL (1-based),
+// ARGV[3] score, ARGV[4] ID, ARGV[5] stats field or '', ARGV[6] amount,
+// ARGV[7..] allowed stored statuses.
+// Returns 1 if the record was written, 0 if it was left alone. Run after
+// `amount::LUA_ADD`.
+const SAVE_SCRIPT: &str = r"
+local stored = ''
+local current = redis.call('GET', KEYS[1])
+if current then
+    stored = cjson.decode(current)['status']
+end
+local allowed = false
+for i = 7, #ARGV do
+    if ARGV[i] == stored then
+        allowed = true
+        break
+    end
+end
+if not allowed then
+    return 0
+end
+
+redis.call('SET', KEYS[1], ARGV[1])
+local position = tonumber(ARGV[2])
+local campaign = #KEYS > 9
+for i = 1, 8 do
+    if i == position then
+        redis.call('ZADD', KEYS[1 + i], ARGV[3], ARGV[4])
+        if campaign then
+            redis.call('ZADD', KEYS[9 + i], ARGV[3], ARGV[4])
+        end
+    else
+        redis.call('ZREM', KEYS[1 + i], ARGV[4])
+        if campaign then
+            redis.call('ZREM', KEYS[9 + i], ARGV[4])
+        end
+    end
+end
+if campaign and ARGV[5] ~= '' and redis.call('SADD', KEYS[18], ARGV[4]) == 1 then
+    local total = redis.call('HGET', KEYS[19], ARGV[5]) or '0'
+    redis.call('HSET', KEYS[19], ARGV[5], add(total, ARGV[6]))
+end
+return 1
+";
+
+static SAVE: LazyLock<Script> =
+    LazyLock::new(|| Script::new(&format!("{}{}", amount::LUA_ADD, SAVE_SCRIPT)));
+
+/// Loads the script `save_to_pipe` queues by its hash, so it is sent once
+/// instead of with every record. Call before sending the pipeline.
+pub async fn load_save_script(conn: &mut Connection) -> RedisResult<()> {
+    SAVE.load_async(conn).await?;
+    Ok(())
+}
+
+/// Queues the script that stores `record` if the stored copy is in one of
+/// the `allowed` statuses (`None` meaning no record yet), moving it to the
+/// index of its current status and, for campaign transfers, to the
+/// campaign's index and amount totals. Its reply is 1 if it was written.
+/// The script must have been loaded with `load_save_script`.
+pub fn save_to_pipe(
+    pipe: &mut Pipeline,
+    record: &TransactionRecord,
+    allowed: &[Option<TransactionStatus>],
+) {
+    let position = TransactionStatus::ALL
+        .iter()
+        .position(|status| *status == record.status)
+        .unwrap()
+        + 1;
+    let mut keys = vec![format!("txn:{}", record.id)];
+    keys.extend(TransactionStatus::ALL.map(status_index_key));
+    let mut field = "";
+    if let Some(campaign_id) = &record.campaign_id {
+        keys.extend(
+            TransactionStatus::ALL.map(|status| campaign::status_index_key(campaign_id, status)),
+        );
+        keys.push(campaign::settled_key(campaign_id));
+        keys.push(campaign::stats_key(campaign_id));
+        if record.status.is_terminal() {
+            field = if record.status == TransactionStatus::Success {
+                "amount_sent"
+            } else {
+                "amount_unsent"
+            };
+        }
+    }
+
+    pipe.cmd("EVALSHA")
+        .arg(SAVE.get_hash())
+        .arg(keys.len())
+        .arg(keys)
+        .arg(serde_json::to_string(record).unwrap())
+        .arg(position)
+        .arg(record.created_at.timestamp_millis())
+        .arg(&record.id)
+        .arg(field)
+        .arg(record.raw_amount().to_string());
+    for status in allowed {
+        pipe.arg(status.map(|status| status.to_string()).unwrap_or_default());
+    }
+}
+
+/// Stores each record if its stored copy is in one of the statuses given
+/// with it, in one MULTI. Returns the IDs of the records left alone.
+pub async fn save(
+    conn: &mut Connection,
+    writes: &[(&TransactionRecord, Vec<Option<TransactionStatus>>)],
+) -> RedisResult<Vec<String>> {
+    if writes.is_empty() {
+        return Ok(Vec::new());
+    }
+    load_save_script(conn).await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for (record, allowed) in writes {
+        save_to_pipe(&mut pipe, record, allowed);
+    }
+    let written: Vec<bool> = pipe.query_async(conn).await?;
+    Ok(writes
+        .iter()
+        .zip(written)
+        .filter(|(_, written)| !written)
+        .map(|((record, _), _)| record.id.clone())
+        .collect())
+}
+
+/// IDs of the transactions in `status`, oldest first, so pages stay stable
+/// while new transfers arrive.
+pub async fn ids_by_status(
+    conn: &mut Connection,
+    status: TransactionStatus,
+    offset: isize,
+    limit: isize,
+) -> RedisResult<Vec<String>> {
+    if limit <= 0 {
+        return Ok(Vec::new());
+    }
+    conn.zrange(status_index_key(status), offset, offset + limit - 1)
+        .await
+}
+
+/// Indexes records written before the status index existed. Runs once; later
+/// starts see the ready flag and return immediately.
+pub async fn build_status_index(conn: &mut Connection) -> RedisResult<usize> {
+    if conn.exists(STATUS_INDEX_READY_KEY).await? {
+        return Ok(0);
+    }
+
+    let mut indexed = 0;
+    let mut cursor = 0;
+    loop {
+        let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg("txn:*")
+            .arg("COUNT")
+            .arg(500)
+            .query_async(conn)
+            .await?;
+        if !keys.is_empty() {
+            let records_json: Vec<Option<String>> = conn.mget(&keys).await?;
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for record in records_json
+                .into_iter()
+                .flatten()
+                .filter_map(|json| serde_json::from_str::<TransactionRecord>(&json).ok())
+            {
+                pipe.zadd(
+                    status_index_key(record.status),
+                    &record.id,
+                    record.created_at.timestamp_millis(),
+                )
+                .ignore();
+                indexed += 1;
+            }
+            pipe.query_async::<()>(conn).await?;
+        }
+        if next_cursor == 0 {
+            break;
+        }
+        cursor = next_cursor;
+    }
+
+    conn.set::<_, _, ()>(STATUS_INDEX_READY_KEY, 1).await?;
+    Ok(indexed)
+}
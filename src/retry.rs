@@ -0,0 +1,232 @@
+use near_api::errors::{ExecuteTransactionError, QueryError, RetryError, SignerError};
+use near_api::near_primitives::errors::{InvalidTxError, TxExecutionError};
+use near_jsonrpc_client::errors::{JsonRpcError, JsonRpcServerError};
+use near_jsonrpc_client::methods::query::{RpcQueryError, RpcQueryRequest};
+use near_jsonrpc_client::methods::tx::RpcTransactionError;
+use rand::Rng;
+use std::time::Duration;
+
+/// Whether a send error proves the transaction never reached the chain: the
+/// network rejected it as invalid, or it never left this service. After any
+/// other error (a timeout, a lost connection, an overloaded node) it may
+/// still have gone through, so its outcome has to be looked up first.
+pub fn is_rejected_send_error(error: &ExecuteTransactionError) -> bool {
+    match error {
+        ExecuteTransactionError::TransactionError(RetryError::Critical(
+            JsonRpcError::ServerError(JsonRpcServerError::HandlerError(
+                RpcTransactionError::InvalidTransaction { .. },
+            )),
+        )) => true,
+        ExecuteTransactionError::TransactionError(RetryError::NoRpcEndpoints) => true,
+        ExecuteTransactionError::TransactionError(_) => false,
+        #[allow(deprecated)]
+        ExecuteTransactionError::CriticalTransactionError(_) => false,
+        // Raised before anything is broadcast.
+        _ => true,
+    }
+}
+
+/// Rejections worth sending again in a new transaction: the transfer itself
+/// is fine, but the nonce, the block hash or a congested shard got in the
+/// way, or the nonce or block hash couldn't be fetched to sign it.
+pub fn is_retryable_send_error(error: &ExecuteTransactionError) -> bool {
+    match error {
+        ExecuteTransactionError::TransactionError(RetryError::Critical(
+            JsonRpcError::ServerError(JsonRpcServerError::HandlerError(
+                RpcTransactionError::InvalidTransaction { context },
+            )),
+        )) => is_retryable_invalid_tx(context),
+        // Failing to fetch the nonce or block hash is an RPC hiccup.
+        ExecuteTransactionError::PreQueryError(_) => true,
+        ExecuteTransactionError::SignerError(SignerError::FetchNonceError(_)) => true,
+        _ => false,
+    }
+}
+
+/// Execution failures reported in the final status that are worth retrying.
+/// Action errors are never retried: the contract rejected the transfer.
+pub fn is_retryable_execution_error(error: &TxExecutionError) -> bool {
+    match error {
+        TxExecutionError::InvalidTxError(error) => is_retryable_invalid_tx(error),
+        TxExecutionError::ActionError(_) => false,
+    }
+}
+
+/// Send errors caused by the access key the batch was signed with rather
+/// than by the batch: a stale nonce, or a key that is gone from the account.
+pub fn is_key_send_error(error: &ExecuteTransactionError) -> bool {
+    match error {
+        ExecuteTransactionError::TransactionError(RetryError::Critical(
+            JsonRpcError::ServerError(JsonRpcServerError::HandlerError(
+                RpcTransactionError::InvalidTransaction { context },
+            )),
+        )) => is_key_invalid_tx(context),
+        ExecuteTransactionError::SignerError(SignerError::FetchNonceError(error)) => {
+            is_unknown_access_key(error)
+        }
+        _ => false,
+    }
+}
+
+/// The final-status counterpart of `is_key_send_error`.
+pub fn is_key_execution_error(error: &TxExecutionError) -> bool {
+    match error {
+        TxExecutionError::InvalidTxError(error) => is_key_invalid_tx(error),
+        TxExecutionError::ActionError(_) => false,
+    }
+}
+
+/// Whether a `view_access_key` query failed because the key doesn't exist.
+pub fn is_unknown_access_key(error: &QueryError<RpcQueryRequest>) -> bool {
+    match error {
+        QueryError::JsonRpcError(error) => matches!(
+            error.as_ref(),
+            RetryError::Critical(JsonRpcError::ServerError(JsonRpcServerError::HandlerError(
+                RpcQueryError::UnknownAccessKey { .. }
+            )))
+        ),
+        _ => false,
+    }
+}
+
+/// Whether a `view_account` query failed because the account doesn't exist.
+pub fn is_unknown_account(error: &QueryError<RpcQueryRequest>) -> bool {
+    match error {
+        QueryError::JsonRpcError(error) => matches!(
+            error.as_ref(),
+            RetryError::Critical(JsonRpcError::ServerError(JsonRpcServerError::HandlerError(
+                RpcQueryError::UnknownAccount { .. }
+            )))
+        ),
+        _ => false,
+    }
+}
+
+fn is_key_invalid_tx(error: &InvalidTxError) -> bool {
+    matches!(
+        error,
+        InvalidTxError::InvalidNonce { .. } | InvalidTxError::InvalidAccessKeyError(_)
+    )
+}
+
+fn is_retryable_invalid_tx(error: &InvalidTxError) -> bool {
+    matches!(
+        error,
+        InvalidTxError::InvalidNonce { .. }
+            | InvalidTxError::Expired
+            | InvalidTxError::ShardCongested { .. }
+            | InvalidTxError::ShardStuck { .. }
+    )
+}
+
+/// Exponential backoff with jitter for the given attempt (starting at 1):
+/// the delay doubles from `base_ms` up to `max_ms`, and a random value in
+/// the upper half of that window is picked so retried batches spread out.
+pub fn backoff_delay(attempt: u32, base_ms: u64, max_ms: u64) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(31);
+    let ceiling = base_ms.saturating_mul(1u64 << exponent).min(max_ms).max(1);
+    let delay = rand::thread_rng().gen_range(ceiling / 2..=ceiling);
+    Duration::from_millis(delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_api::near_primitives::errors::{ActionError, ActionErrorKind};
+
+    fn rejected(context: InvalidTxError) -> ExecuteTransactionError {
+        ExecuteTransactionError::TransactionError(RetryError::Critical(JsonRpcError::ServerError(
+            JsonRpcServerError::HandlerError(RpcTransactionError::InvalidTransaction { context }),
+        )))
+    }
+
+    fn invalid_nonce() -> InvalidTxError {
+        InvalidTxError::InvalidNonce {
+            tx_nonce: 5,
+            ak_nonce: 7,
+        }
+    }
+
+    #[test]
+    fn first_attempt_waits_between_half_and_all_of_the_base() {
+        for _ in 0..100 {
+            let delay = backoff_delay(1, 1_000, 60_000).as_millis();
+            assert!((500..=1_000).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        for _ in 0..100 {
+            let delay = backoff_delay(3, 1_000, 60_000).as_millis();
+            assert!((2_000..=4_000).contains(&delay), "{}", delay);
+            let delay = backoff_delay(10, 1_000, 60_000).as_millis();
+            assert!((30_000..=60_000).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn huge_attempts_stay_at_the_cap() {
+        for attempt in [32, 64, u32::MAX] {
+            let delay = backoff_delay(attempt, 1_000, 60_000).as_millis();
+            assert!((30_000..=60_000).contains(&delay), "{}", delay);
+        }
+        let delay = backoff_delay(u32::MAX, u64::MAX, u64::MAX).as_millis();
+        assert!(delay >= (u64::MAX / 2) as u128);
+    }
+
+    #[test]
+    fn zero_base_still_waits_at_most_a_millisecond() {
+        for attempt in [0, 1, 40] {
+            assert!(backoff_delay(attempt, 0, 60_000) <= Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn stale_nonces_and_expired_transactions_are_retried() {
+        for context in [invalid_nonce(), InvalidTxError::Expired] {
+            assert!(is_rejected_send_error(&rejected(context.clone())));
+            assert!(is_retryable_send_error(&rejected(context.clone())));
+            assert!(is_retryable_execution_error(&TxExecutionError::InvalidTxError(context)));
+        }
+        assert!(is_key_send_error(&rejected(invalid_nonce())));
+        assert!(!is_key_send_error(&rejected(InvalidTxError::Expired)));
+    }
+
+    #[test]
+    fn other_rejections_are_final() {
+        let error = rejected(InvalidTxError::InvalidSignature);
+        assert!(is_rejected_send_error(&error));
+        assert!(!is_retryable_send_error(&error));
+    }
+
+    #[test]
+    fn action_errors_are_not_retried() {
+        let error = TxExecutionError::ActionError(ActionError {
+            index: Some(0),
+            kind: ActionErrorKind::AccountDoesNotExist {
+                account_id: "bob.near".parse().unwrap(),
+            },
+        });
+        assert!(!is_retryable_execution_error(&error));
+        assert!(!is_key_execution_error(&error));
+    }
+
+    #[test]
+    fn having_no_rpc_endpoint_means_nothing_was_sent() {
+        let error = ExecuteTransactionError::TransactionError(RetryError::NoRpcEndpoints);
+        assert!(is_rejected_send_error(&error));
+        assert!(!is_retryable_send_error(&error));
+    }
+
+    #[test]
+    fn timeouts_leave_the_outcome_unknown() {
+        let error = ExecuteTransactionError::TransactionError(RetryError::RetriesExhausted(
+            JsonRpcError::ServerError(JsonRpcServerError::HandlerError(
+                RpcTransactionError::TimeoutError,
+            )),
+        ));
+        assert!(!is_rejected_send_error(&error));
+        assert!(!is_retryable_send_error(&error));
+    }
+}
@@ -0,0 +1,384 @@
+use crate::config::Settings;
+use crate::types::{
+    ScreeningDecision, ScreeningList, ScreeningLists, ScreeningVerdict, TransactionRecord,
+};
+use crate::validation;
+use async_trait::async_trait;
+use chrono::Utc;
+use deadpool_redis::Connection;
+use near_api::near_primitives::account::id::AccountType;
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::Duration;
+
+// Sets of account IDs added through the admin API, on top of the lists in
+// Settings.toml.
+const ALLOW_KEY: &str = "screening:allow";
+const DENY_KEY: &str = "screening:deny";
+
+fn list_key(list: ScreeningList) -> &'static str {
+    match list {
+        ScreeningList::Allow => ALLOW_KEY,
+        ScreeningList::Deny => DENY_KEY,
+    }
+}
+
+/// What a screening hook is asked about a transfer.
+#[derive(Serialize, Debug)]
+pub struct HookRequest<'a> {
+    pub transaction_id: &'a str,
+    pub sender_id: &'a str,
+    pub receiver_id: &'a str,
+    /// Raw amount.
+    pub amount: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub campaign_id: Option<&'a str>,
+}
+
+/// A screening hook's answer.
+#[derive(Deserialize, Debug)]
+pub struct HookVerdict {
+    pub allow: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// An outside check run on every receiver the static rules let through.
+#[async_trait]
+pub trait ScreeningHook: Send + Sync {
+    async fn screen(
+        &self,
+        request: &HookRequest<'_>,
+    ) -> Result<HookVerdict, Box<dyn Error + Send + Sync>>;
+}
+
+/// POSTs the `HookRequest` as JSON to a screening service, which answers
+/// with a `HookVerdict`.
+pub struct HttpHook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpHook {
+    pub fn new(url: String, timeout: Duration) -> reqwest::Result<Self> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(Self { client, url })
+    }
+}
+
+#[async_trait]
+impl ScreeningHook for HttpHook {
+    async fn screen(
+        &self,
+        request: &HookRequest<'_>,
+    ) -> Result<HookVerdict, Box<dyn Error + Send + Sync>> {
+        let verdict = self
+            .client
+            .post(&self.url)
+            .json(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(verdict)
+    }
+}
+
+// `*` matches any run of characters, dots included; anything else only
+// itself. So `*.near` matches `alice.near` and `app.alice.near`.
+fn matches_pattern(pattern: &str, account_id: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = account_id.as_bytes();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and where in the text it started matching.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the `*` take one more character and try again.
+            star = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn verdict(decision: ScreeningDecision, rule: &str, reason: Option<String>) -> ScreeningVerdict {
+    ScreeningVerdict {
+        decision,
+        rule: rule.to_string(),
+        reason,
+        checked_at: Utc::now(),
+    }
+}
+
+/// Decides whether a receiver may be sent tokens. Checks run in this order
+/// and the first one that decides wins:
+///
+/// 1. the deny lists, from Settings.toml and Redis;
+/// 2. the allow lists, which skip every check below;
+/// 3. `screening_deny_implicit` and `screening_deny_eth_implicit`;
+/// 4. `screening_deny_patterns`;
+/// 5. `screening_allow_patterns`, when set the receiver must match one;
+/// 6. the hook, if there is one.
+pub struct Screener {
+    allow: HashSet<String>,
+    deny: HashSet<String>,
+    allow_patterns: Vec<String>,
+    deny_patterns: Vec<String>,
+    deny_implicit: bool,
+    deny_eth_implicit: bool,
+    hook: Option<Box<dyn ScreeningHook>>,
+    hook_fail_open: bool,
+}
+
+impl Screener {
+    /// Builds the screener from the settings, with an `HttpHook` if
+    /// `screening_hook_url` is set.
+    pub fn new(settings: &Settings) -> reqwest::Result<Self> {
+        let hook: Option<Box<dyn ScreeningHook>> = match &settings.screening_hook_url {
+            Some(url) => Some(Box::new(HttpHook::new(
+                url.clone(),
+                Duration::from_millis(settings.screening_hook_timeout_ms),
+            )?)),
+            None => None,
+        };
+        Ok(Self {
+            allow: settings.screening_allow.iter().cloned().collect(),
+            deny: settings.screening_deny.iter().cloned().collect(),
+            allow_patterns: settings.screening_allow_patterns.clone(),
+            deny_patterns: settings.screening_deny_patterns.clone(),
+            deny_implicit: settings.screening_deny_implicit,
+            deny_eth_implicit: settings.screening_deny_eth_implicit,
+            hook,
+            hook_fail_open: settings.screening_hook_fail_open,
+        })
+    }
+
+    /// Replaces the hook, e.g. with one that isn't reached over HTTP.
+    pub fn with_hook(mut self, hook: Box<dyn ScreeningHook>) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    pub fn has_hook(&self) -> bool {
+        self.hook.is_some()
+    }
+
+    /// The lists from Settings.toml.
+    pub fn configured_lists(&self) -> ScreeningLists {
+        let mut allow: Vec<String> = self.allow.iter().cloned().collect();
+        let mut deny: Vec<String> = self.deny.iter().cloned().collect();
+        allow.sort();
+        deny.sort();
+        ScreeningLists { allow, deny }
+    }
+
+    /// Screens the receiver of `record`. The inner error says why no verdict
+    /// could be reached: the hook failed and `screening_hook_fail_open` is off.
+    pub async fn screen(
+        &self,
+        conn: &mut Connection,
+        record: &TransactionRecord,
+    ) -> RedisResult<Result<ScreeningVerdict, String>> {
+        let receiver_id = record.request.reciever_id.as_str();
+        let listed = listed(conn, &[receiver_id]).await?;
+        Ok(self.screen_listed(record, listed[0]).await)
+    }
+
+    /// `screen` with the receiver's membership of the Redis deny and allow
+    /// lists already read by `listed`, so many receivers can be screened
+    /// with one round trip to Redis.
+    pub async fn screen_listed(
+        &self,
+        record: &TransactionRecord,
+        (denied, allowed): (bool, bool),
+    ) -> Result<ScreeningVerdict, String> {
+        let receiver_id = record.request.reciever_id.as_str();
+        if denied || self.deny.contains(receiver_id) {
+            return Ok(verdict(ScreeningDecision::Deny, "deny_list", None));
+        }
+        if allowed || self.allow.contains(receiver_id) {
+            return Ok(verdict(ScreeningDecision::Allow, "allow_list", None));
+        }
+        let account_type = validation::account_type(receiver_id);
+        if self.deny_implicit && account_type == Some(AccountType::NearImplicitAccount) {
+            return Ok(verdict(
+                ScreeningDecision::Deny,
+                "implicit_account",
+                Some("Implicit accounts are not accepted.".to_string()),
+            ));
+        }
+        if self.deny_eth_implicit && account_type == Some(AccountType::EthImplicitAccount) {
+            return Ok(verdict(
+                ScreeningDecision::Deny,
+                "implicit_account",
+                Some("ETH-implicit accounts are not accepted.".to_string()),
+            ));
+        }
+        if let Some(pattern) = self
+            .deny_patterns
+            .iter()
+            .find(|pattern| matches_pattern(pattern, receiver_id))
+        {
+            return Ok(verdict(
+                ScreeningDecision::Deny,
+                "deny_pattern",
+                Some(format!("Matches {}.", pattern)),
+            ));
+        }
+        if !self.allow_patterns.is_empty()
+            && !self
+                .allow_patterns
+                .iter()
+                .any(|pattern| matches_pattern(pattern, receiver_id))
+        {
+            return Ok(verdict(
+                ScreeningDecision::Deny,
+                "allow_pattern",
+                Some(format!(
+                    "Matches none of {}.",
+                    self.allow_patterns.join(", ")
+                )),
+            ));
+        }
+
+        let Some(hook) = &self.hook else {
+            return Ok(verdict(ScreeningDecision::Allow, "default", None));
+        };
+        let request = HookRequest {
+            transaction_id: &record.id,
+            sender_id: &record.sender_id,
+            receiver_id,
+            amount: &record.request.amount,
+            api_key_id: record.api_key_id.as_deref(),
+            campaign_id: record.campaign_id.as_deref(),
+        };
+        match hook.screen(&request).await {
+            Ok(answer) => {
+                let decision = if answer.allow {
+                    ScreeningDecision::Allow
+                } else {
+                    ScreeningDecision::Deny
+                };
+                Ok(verdict(decision, "hook", answer.reason))
+            }
+            Err(e) if self.hook_fail_open => Ok(verdict(
+                ScreeningDecision::Allow,
+                "hook_unavailable",
+                Some(e.to_string()),
+            )),
+            Err(e) => Err(format!("The screening hook failed: {}", e)),
+        }
+    }
+}
+
+/// Whether each receiver is on the Redis deny and allow lists, read in one
+/// pipeline.
+pub async fn listed(conn: &mut Connection, receiver_ids: &[&str]) -> RedisResult<Vec<(bool, bool)>> {
+    if receiver_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut pipe = redis::pipe();
+    for receiver_id in receiver_ids {
+        pipe.sismember(DENY_KEY, receiver_id)
+            .sismember(ALLOW_KEY, receiver_id);
+    }
+    let flags: Vec<bool> = pipe.query_async(conn).await?;
+    Ok(flags
+        .chunks(2)
+        .map(|flags| (flags[0], flags[1]))
+        .collect())
+}
+
+/// Why a transfer to `receiver_id` was turned away.
+pub fn rejection_message(receiver_id: &str, verdict: &ScreeningVerdict) -> String {
+    match &verdict.reason {
+        Some(reason) => format!(
+            "{} was rejected by screening ({}): {}",
+            receiver_id, verdict.rule, reason
+        ),
+        None => format!(
+            "{} was rejected by screening ({}).",
+            receiver_id, verdict.rule
+        ),
+    }
+}
+
+/// The lists kept in Redis.
+pub async fn managed_lists(conn: &mut Connection) -> RedisResult<ScreeningLists> {
+    let (mut allow, mut deny): (Vec<String>, Vec<String>) = redis::pipe()
+        .smembers(ALLOW_KEY)
+        .smembers(DENY_KEY)
+        .query_async(conn)
+        .await?;
+    allow.sort();
+    deny.sort();
+    Ok(ScreeningLists { allow, deny })
+}
+
+/// Adds and removes account IDs on one of the Redis lists.
+pub async fn update_list(
+    conn: &mut Connection,
+    list: ScreeningList,
+    add: &[String],
+    remove: &[String],
+) -> RedisResult<()> {
+    let key = list_key(list);
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if !add.is_empty() {
+        pipe.sadd(key, add).ignore();
+    }
+    if !remove.is_empty() {
+        pipe.srem(key, remove).ignore();
+    }
+    pipe.query_async(conn).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_wildcard_suffix_matches_sub_accounts_but_not_the_parent() {
+        assert!(matches_pattern("*.near", "alice.near"));
+        assert!(matches_pattern("*.near", "app.alice.near"));
+        assert!(matches_pattern("*.near", ".near"));
+        assert!(!matches_pattern("*.near", "near"));
+        assert!(!matches_pattern("*.near", "alice.testnet"));
+        assert!(matches_pattern("near", "near"));
+        assert!(!matches_pattern("near", "alice.near"));
+    }
+
+    #[test]
+    fn a_lone_wildcard_matches_everything() {
+        for pattern in ["*", "**", "***"] {
+            assert!(matches_pattern(pattern, "alice.near"));
+            assert!(matches_pattern(pattern, ""));
+        }
+    }
+
+    #[test]
+    fn several_wildcards_backtrack() {
+        assert!(matches_pattern("*.*.near", "app.alice.near"));
+        assert!(!matches_pattern("*.*.near", "alice.near"));
+        assert!(matches_pattern("scam*.*", "scammer.near"));
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(!matches_pattern("a*b*c", "aXbYbZ"));
+        assert!(matches_pattern("*a*", "banana"));
+    }
+}
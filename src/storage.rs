@@ -0,0 +1,89 @@
+use log::warn;
+use near_api::{Contract, NetworkConfig, StorageDeposit};
+use near_sdk::AccountId;
+use near_sdk::json_types::U128;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use tokio::sync::{OnceCell, RwLock};
+
+// What the worker does with a receiver that has no NEP-145 storage balance on
+// the FT contract.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageRegistrationMode {
+    /// Prepend a `storage_deposit` paying the contract's minimum balance.
+    Auto,
+    /// Fail only the affected transfer.
+    Fail,
+}
+
+#[derive(Deserialize)]
+struct StorageBalanceBounds {
+    min: U128,
+}
+
+/// Caches which receivers are registered with the FT contract so the worker
+/// only has to call `storage_balance_of` once per receiver.
+pub struct StorageRegistry {
+    ft_contract_id: AccountId,
+    network_config: NetworkConfig,
+    registered: RwLock<HashSet<AccountId>>,
+    min_deposit: OnceCell<u128>,
+}
+
+impl StorageRegistry {
+    pub fn new(ft_contract_id: AccountId, network_config: NetworkConfig) -> Self {
+        Self {
+            ft_contract_id,
+            network_config,
+            registered: RwLock::new(HashSet::new()),
+            min_deposit: OnceCell::new(),
+        }
+    }
+
+    /// Returns whether `account_id` has a storage balance on the contract.
+    /// Only positive answers are cached, since unregistered accounts can
+    /// register themselves at any time.
+    pub async fn is_registered(
+        &self,
+        account_id: &AccountId,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        if self.registered.read().await.contains(account_id) {
+            return Ok(true);
+        }
+
+        let balance = StorageDeposit::on_contract(self.ft_contract_id.clone())
+            .view_account_storage(account_id.clone())?
+            .fetch_from(&self.network_config)
+            .await?;
+
+        let registered = balance.data.is_some();
+        if registered {
+            self.mark_registered(account_id).await;
+        }
+        Ok(registered)
+    }
+
+    pub async fn mark_registered(&self, account_id: &AccountId) {
+        self.registered.write().await.insert(account_id.clone());
+    }
+
+    /// The deposit required to register an account, from `storage_balance_bounds().min`.
+    pub async fn min_deposit(&self) -> Result<u128, Box<dyn std::error::Error + Send + Sync>> {
+        self.min_deposit
+            .get_or_try_init(|| async {
+                let bounds = Contract(self.ft_contract_id.clone())
+                    .call_function("storage_balance_bounds", json!({}))?
+                    .read_only::<StorageBalanceBounds>()
+                    .fetch_from(&self.network_config)
+                    .await?;
+                if bounds.data.min.0 == 0 {
+                    warn!("FT contract reports a zero storage_balance_bounds minimum.");
+                }
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(bounds.data.min.0)
+            })
+            .await
+            .copied()
+    }
+}
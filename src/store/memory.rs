@@ -0,0 +1,280 @@
+use super::{CampaignTotals, StoreResult, TransactionStore};
+use crate::types::{TransactionRecord, TransactionStatus};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+#[derive(Default)]
+struct MemoryState {
+    records: HashMap<String, TransactionRecord>,
+    /// IDs in the order they were created.
+    order: Vec<String>,
+}
+
+/// Keeps records in process memory. Everything is lost on restart, so this
+/// is for tests and local experiments only.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: RwLock<MemoryState>,
+}
+
+impl MemoryStore {
+    // Records in creation order (or newest first) matching `filter`, paginated.
+    fn select<F>(
+        &self,
+        newest_first: bool,
+        offset: usize,
+        limit: usize,
+        filter: F,
+    ) -> Vec<TransactionRecord>
+    where
+        F: Fn(&TransactionRecord) -> bool,
+    {
+        let state = self.state.read().unwrap();
+        let records = state.order.iter().filter_map(|id| state.records.get(id));
+        let matching: Box<dyn Iterator<Item = &TransactionRecord>> = if newest_first {
+            Box::new(records.rev())
+        } else {
+            Box::new(records)
+        };
+        matching
+            .filter(|record| filter(record))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[async_trait]
+impl TransactionStore for MemoryStore {
+    async fn create(&self, records: &[TransactionRecord]) -> StoreResult<()> {
+        let mut state = self.state.write().unwrap();
+        for record in records {
+            if !state.records.contains_key(&record.id) {
+                state.records.insert(record.id.clone(), record.clone());
+                state.order.push(record.id.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn save_if(
+        &self,
+        writes: &[(&TransactionRecord, Vec<TransactionStatus>)],
+    ) -> StoreResult<Vec<String>> {
+        let mut state = self.state.write().unwrap();
+        let mut left_alone = Vec::new();
+        for (record, allowed) in writes {
+            match state.records.get_mut(&record.id) {
+                Some(stored) if allowed.contains(&stored.status) => *stored = (*record).clone(),
+                _ => left_alone.push(record.id.clone()),
+            }
+        }
+        Ok(left_alone)
+    }
+
+    async fn get(&self, id: &str) -> StoreResult<Option<TransactionRecord>> {
+        Ok(self.state.read().unwrap().records.get(id).cloned())
+    }
+
+    async fn get_many(&self, ids: &[String]) -> StoreResult<Vec<TransactionRecord>> {
+        let state = self.state.read().unwrap();
+        Ok(ids
+            .iter()
+            .filter_map(|id| state.records.get(id))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_by_receiver(
+        &self,
+        receiver_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        Ok(self.select(true, offset, limit, |record| {
+            record.request.reciever_id == receiver_id
+        }))
+    }
+
+    async fn list_by_status(
+        &self,
+        status: TransactionStatus,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        Ok(self.select(false, offset, limit, |record| record.status == status))
+    }
+
+    async fn list_by_campaign(
+        &self,
+        campaign_id: &str,
+        status: Option<TransactionStatus>,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        Ok(self.select(false, offset, limit, |record| {
+            record.campaign_id.as_deref() == Some(campaign_id)
+                && status.is_none_or(|status| record.status == status)
+        }))
+    }
+
+    async fn list_all(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> StoreResult<(u64, Vec<TransactionRecord>)> {
+        // An empty page would hand back the same cursor forever.
+        let count = count.max(1);
+        let records = self.select(false, cursor as usize, count, |_| true);
+        let next_cursor = if records.len() < count {
+            0
+        } else {
+            cursor + records.len() as u64
+        };
+        Ok((next_cursor, records))
+    }
+
+    async fn campaign_totals(&self, campaign_id: &str) -> StoreResult<CampaignTotals> {
+        let state = self.state.read().unwrap();
+        let mut totals = CampaignTotals::default();
+        for status in TransactionStatus::ALL {
+            totals.counts.insert(status, 0);
+        }
+        for record in state
+            .records
+            .values()
+            .filter(|record| record.campaign_id.as_deref() == Some(campaign_id))
+        {
+            *totals.counts.entry(record.status).or_default() += 1;
+            match record.status {
+                TransactionStatus::Success => {
+                    totals.amount_sent = totals.amount_sent.saturating_add(record.raw_amount())
+                }
+                status if status.is_terminal() => {
+                    totals.amount_unsent = totals.amount_unsent.saturating_add(record.raw_amount())
+                }
+                _ => {}
+            }
+        }
+        Ok(totals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TokenTransferRequest;
+
+    fn record() -> TransactionRecord {
+        let request: TokenTransferRequest =
+            serde_json::from_value(serde_json::json!({"reciever_id": "bob.near", "amount": "10"}))
+                .unwrap();
+        TransactionRecord::new("sender.near".to_string(), request, 0)
+    }
+
+    fn moved(record: &TransactionRecord, status: TransactionStatus) -> TransactionRecord {
+        let mut record = record.clone();
+        record.transition(status).unwrap();
+        record
+    }
+
+    async fn stored_status(store: &MemoryStore, id: &str) -> TransactionStatus {
+        store.get(id).await.unwrap().unwrap().status
+    }
+
+    #[actix_web::test]
+    async fn save_if_writes_only_from_the_allowed_statuses() {
+        let store = MemoryStore::default();
+        let queued = record();
+        store.create(std::slice::from_ref(&queued)).await.unwrap();
+
+        let batched = moved(&queued, TransactionStatus::Batched);
+        let left_alone = store
+            .save_if(&[(&batched, vec![TransactionStatus::Retrying])])
+            .await
+            .unwrap();
+        assert_eq!(left_alone, vec![queued.id.clone()]);
+        assert_eq!(stored_status(&store, &queued.id).await, TransactionStatus::Queued);
+
+        let left_alone = store
+            .save_if(&[(&batched, vec![TransactionStatus::Queued])])
+            .await
+            .unwrap();
+        assert!(left_alone.is_empty());
+        assert_eq!(stored_status(&store, &queued.id).await, TransactionStatus::Batched);
+    }
+
+    #[actix_web::test]
+    async fn save_if_leaves_unknown_records_alone() {
+        let store = MemoryStore::default();
+        let missing = record();
+        let left_alone = store
+            .save_if(&[(&missing, TransactionStatus::ALL.to_vec())])
+            .await
+            .unwrap();
+        assert_eq!(left_alone, vec![missing.id.clone()]);
+        assert!(store.get(&missing.id).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn update_never_undoes_a_finished_transfer() {
+        let store = MemoryStore::default();
+        let queued = record();
+        store.create(std::slice::from_ref(&queued)).await.unwrap();
+        let batched = moved(&queued, TransactionStatus::Batched);
+        let submitted = moved(&batched, TransactionStatus::Submitted);
+        let succeeded = moved(&submitted, TransactionStatus::Success);
+
+        assert!(store.update(std::slice::from_ref(&succeeded)).await.unwrap().is_empty());
+        // A stale write of an earlier step is refused.
+        let left_alone = store.update(&[submitted, batched]).await.unwrap();
+        assert_eq!(left_alone, vec![queued.id.clone(), queued.id.clone()]);
+        assert_eq!(stored_status(&store, &queued.id).await, TransactionStatus::Success);
+    }
+
+    #[actix_web::test]
+    async fn a_submitted_transfer_is_not_claimed_again() {
+        let store = MemoryStore::default();
+        let queued = record();
+        store.create(std::slice::from_ref(&queued)).await.unwrap();
+        let batched = moved(&queued, TransactionStatus::Batched);
+        let submitted = moved(&batched, TransactionStatus::Submitted);
+        store.update(std::slice::from_ref(&submitted)).await.unwrap();
+
+        // A worker that read the record before it was submitted.
+        let left_alone = store
+            .update_from(&[(TransactionStatus::Queued, batched)])
+            .await
+            .unwrap();
+        assert_eq!(left_alone, vec![queued.id.clone()]);
+        assert_eq!(stored_status(&store, &queued.id).await, TransactionStatus::Submitted);
+
+        let retrying = moved(&submitted, TransactionStatus::Retrying);
+        assert!(store.update(std::slice::from_ref(&retrying)).await.unwrap().is_empty());
+        assert_eq!(stored_status(&store, &queued.id).await, TransactionStatus::Retrying);
+    }
+
+    #[actix_web::test]
+    async fn update_from_lets_only_one_writer_move_a_record_on() {
+        let store = MemoryStore::default();
+        let queued = record();
+        store.create(std::slice::from_ref(&queued)).await.unwrap();
+
+        let batched = moved(&queued, TransactionStatus::Batched);
+        let cancelled = moved(&queued, TransactionStatus::Cancelled);
+        let claimed = store
+            .update_from(&[(TransactionStatus::Queued, batched)])
+            .await
+            .unwrap();
+        assert!(claimed.is_empty());
+
+        let left_alone = store
+            .update_from(&[(TransactionStatus::Queued, cancelled)])
+            .await
+            .unwrap();
+        assert_eq!(left_alone, vec![queued.id.clone()]);
+        assert_eq!(stored_status(&store, &queued.id).await, TransactionStatus::Batched);
+    }
+}
@@ -0,0 +1,156 @@
+mod memory;
+mod postgres;
+mod redis_store;
+
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+pub use redis_store::RedisStore;
+
+use crate::config::Settings;
+use crate::types::{TransactionRecord, TransactionStatus};
+use async_trait::async_trait;
+use deadpool_redis::Pool;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+pub type StoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// Where transaction records are kept. The queue, idempotency keys and
+// campaigns stay in Redis whichever backend is chosen.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreBackend {
+    Redis,
+    /// Durable history in Postgres, including every status change.
+    Postgres,
+    /// Kept in process memory and lost on restart; meant for tests.
+    Memory,
+}
+
+/// Per-status counts of a campaign's transfers and the amounts of the
+/// finished ones.
+#[derive(Debug, Default)]
+pub struct CampaignTotals {
+    pub counts: BTreeMap<TransactionStatus, u64>,
+    /// Raw amount of the transfers that succeeded.
+    pub amount_sent: u128,
+    /// Raw amount of the transfers that failed, were cancelled or expired.
+    pub amount_unsent: u128,
+}
+
+/// Storage of transaction records and the indexes the API reads them by.
+///
+/// Receiver listings are newest first; status and campaign listings oldest
+/// first, so pages stay stable while new transfers arrive.
+#[async_trait]
+pub trait TransactionStore: Send + Sync {
+    /// Stores newly accepted records.
+    async fn create(&self, records: &[TransactionRecord]) -> StoreResult<()>;
+
+    /// Writes each record if the stored copy is in one of the statuses given
+    /// with it. The check and the write are one atomic step, so concurrent
+    /// writers can't overwrite each other's changes. Returns the IDs of the
+    /// records that were left alone.
+    async fn save_if(
+        &self,
+        writes: &[(&TransactionRecord, Vec<TransactionStatus>)],
+    ) -> StoreResult<Vec<String>>;
+
+    /// Stores records after a status change, unless the stored copy is in a
+    /// status that can't lead to the new one, e.g. it already finished.
+    /// Returns the IDs of the records that were left alone.
+    async fn update(&self, records: &[TransactionRecord]) -> StoreResult<Vec<String>> {
+        let writes: Vec<_> = records
+            .iter()
+            .map(|record| (record, writable_from(record.status)))
+            .collect();
+        self.save_if(&writes).await
+    }
+
+    /// Stores each record only if its stored status is still the one it is
+    /// paired with, so two writers can't both move the same record on.
+    /// Returns the IDs of the records that were left alone.
+    async fn update_from(
+        &self,
+        updates: &[(TransactionStatus, TransactionRecord)],
+    ) -> StoreResult<Vec<String>> {
+        let writes: Vec<_> = updates
+            .iter()
+            .map(|(from, record)| (record, vec![*from]))
+            .collect();
+        self.save_if(&writes).await
+    }
+
+    async fn get(&self, id: &str) -> StoreResult<Option<TransactionRecord>>;
+
+    /// The records of `ids` that exist, in the order of `ids`.
+    async fn get_many(&self, ids: &[String]) -> StoreResult<Vec<TransactionRecord>>;
+
+    async fn list_by_receiver(
+        &self,
+        receiver_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>>;
+
+    async fn list_by_status(
+        &self,
+        status: TransactionStatus,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>>;
+
+    /// A campaign's records in submission order, or only those in `status`.
+    async fn list_by_campaign(
+        &self,
+        campaign_id: &str,
+        status: Option<TransactionStatus>,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>>;
+
+    /// Walks every record. Start with cursor 0 and pass back the returned
+    /// cursor until it is 0 again; `count` is a hint, not a guarantee, and
+    /// at least 1.
+    async fn list_all(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> StoreResult<(u64, Vec<TransactionRecord>)>;
+
+    async fn campaign_totals(&self, campaign_id: &str) -> StoreResult<CampaignTotals>;
+}
+
+/// Statuses a stored record may be in to be overwritten by one in `next`.
+pub fn writable_from(next: TransactionStatus) -> Vec<TransactionStatus> {
+    TransactionStatus::ALL
+        .into_iter()
+        .filter(|status| status.can_reach(next))
+        .collect()
+}
+
+/// Opens the backend selected by `transaction_store` and prepares it
+/// (indexes, tables) for use.
+pub async fn connect(
+    settings: &Settings,
+    redis_pool: Pool,
+) -> StoreResult<Arc<dyn TransactionStore>> {
+    match settings.transaction_store {
+        StoreBackend::Redis => {
+            let store = RedisStore::new(redis_pool);
+            store.init().await?;
+            Ok(Arc::new(store))
+        }
+        StoreBackend::Postgres => {
+            let database_url = settings
+                .database_url
+                .as_deref()
+                .ok_or("transaction_store is postgres but DATABASE_URL is not set")?;
+            let store = PostgresStore::connect(database_url).await?;
+            store.migrate().await?;
+            Ok(Arc::new(store))
+        }
+        StoreBackend::Memory => Ok(Arc::new(MemoryStore::default())),
+    }
+}
@@ -0,0 +1,302 @@
+use super::{CampaignTotals, StoreResult, TransactionStore};
+use crate::types::{TransactionRecord, TransactionStatus};
+use async_trait::async_trait;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::types::Json;
+use sqlx::{Postgres, Transaction};
+use std::collections::HashMap;
+
+const MAX_CONNECTIONS: u32 = 20;
+
+// Run in order at startup; every statement is idempotent.
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS transactions (
+        id TEXT PRIMARY KEY,
+        receiver_id TEXT NOT NULL,
+        status TEXT NOT NULL,
+        campaign_id TEXT,
+        amount NUMERIC(39, 0) NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL,
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        record JSONB NOT NULL
+    )",
+    "CREATE INDEX IF NOT EXISTS transactions_receiver_idx
+        ON transactions (receiver_id, created_at DESC)",
+    "CREATE INDEX IF NOT EXISTS transactions_status_idx
+        ON transactions (status, created_at)",
+    "CREATE INDEX IF NOT EXISTS transactions_campaign_idx
+        ON transactions (campaign_id, status, created_at)",
+    // Append-only: one row per write, so the full history of a transfer can
+    // be audited even after its record has moved on.
+    "CREATE TABLE IF NOT EXISTS transaction_events (
+        id BIGSERIAL PRIMARY KEY,
+        transaction_id TEXT NOT NULL REFERENCES transactions (id),
+        status TEXT NOT NULL,
+        recorded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        record JSONB NOT NULL
+    )",
+    "CREATE INDEX IF NOT EXISTS transaction_events_transaction_idx
+        ON transaction_events (transaction_id, id)",
+];
+
+/// Records in a `transactions` table, with every write also appended to
+/// `transaction_events`.
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub async fn connect(database_url: &str) -> StoreResult<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(MAX_CONNECTIONS)
+            .connect(database_url)
+            .await?;
+        Ok(Self { pool })
+    }
+
+    /// Creates the tables and indexes if they don't exist yet.
+    pub async fn migrate(&self) -> StoreResult<()> {
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    async fn append_event(
+        tx: &mut Transaction<'_, Postgres>,
+        record: &TransactionRecord,
+    ) -> StoreResult<()> {
+        sqlx::query(
+            "INSERT INTO transaction_events (transaction_id, status, record) VALUES ($1, $2, $3)",
+        )
+        .bind(&record.id)
+        .bind(record.status.to_string())
+        .bind(Json(record))
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    async fn fetch(
+        &self,
+        sql: &str,
+        binds: Vec<String>,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        let mut query = sqlx::query_scalar::<_, Json<TransactionRecord>>(sql);
+        for value in binds {
+            query = query.bind(value);
+        }
+        let records = query
+            .bind(offset as i64)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(records.into_iter().map(|Json(record)| record).collect())
+    }
+}
+
+#[async_trait]
+impl TransactionStore for PostgresStore {
+    async fn create(&self, records: &[TransactionRecord]) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        for record in records {
+            sqlx::query(
+                "INSERT INTO transactions
+                    (id, receiver_id, status, campaign_id, amount, created_at, record)
+                 VALUES ($1, $2, $3, $4, $5::numeric, $6, $7)",
+            )
+            .bind(&record.id)
+            .bind(&record.request.reciever_id)
+            .bind(record.status.to_string())
+            .bind(&record.campaign_id)
+            .bind(record.raw_amount().to_string())
+            .bind(record.created_at)
+            .bind(Json(record))
+            .execute(&mut *tx)
+            .await?;
+            Self::append_event(&mut tx, record).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn save_if(
+        &self,
+        writes: &[(&TransactionRecord, Vec<TransactionStatus>)],
+    ) -> StoreResult<Vec<String>> {
+        let mut left_alone = Vec::new();
+        if writes.is_empty() {
+            return Ok(left_alone);
+        }
+        let mut tx = self.pool.begin().await?;
+        for (record, allowed) in writes {
+            let allowed: Vec<String> = allowed.iter().map(|status| status.to_string()).collect();
+            let updated = sqlx::query(
+                "UPDATE transactions SET status = $2, record = $3, updated_at = now()
+                 WHERE id = $1 AND status = ANY($4)",
+            )
+            .bind(&record.id)
+            .bind(record.status.to_string())
+            .bind(Json(*record))
+            .bind(&allowed)
+            .execute(&mut *tx)
+            .await?;
+            if updated.rows_affected() == 0 {
+                left_alone.push(record.id.clone());
+                continue;
+            }
+            Self::append_event(&mut tx, record).await?;
+        }
+        tx.commit().await?;
+        Ok(left_alone)
+    }
+
+    async fn get(&self, id: &str) -> StoreResult<Option<TransactionRecord>> {
+        let record = sqlx::query_scalar::<_, Json<TransactionRecord>>(
+            "SELECT record FROM transactions WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record.map(|Json(record)| record))
+    }
+
+    async fn get_many(&self, ids: &[String]) -> StoreResult<Vec<TransactionRecord>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let records = sqlx::query_scalar::<_, Json<TransactionRecord>>(
+            "SELECT record FROM transactions WHERE id = ANY($1)",
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+        // Back into the order that was asked for.
+        let positions: HashMap<&str, usize> = ids
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(position, id)| (id.as_str(), position))
+            .collect();
+        let mut records: Vec<TransactionRecord> =
+            records.into_iter().map(|Json(record)| record).collect();
+        records.sort_by_key(|record| positions.get(record.id.as_str()).copied());
+        Ok(records)
+    }
+
+    async fn list_by_receiver(
+        &self,
+        receiver_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        self.fetch(
+            "SELECT record FROM transactions WHERE receiver_id = $1
+             ORDER BY created_at DESC, id DESC OFFSET $2 LIMIT $3",
+            vec![receiver_id.to_string()],
+            offset,
+            limit,
+        )
+        .await
+    }
+
+    async fn list_by_status(
+        &self,
+        status: TransactionStatus,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        self.fetch(
+            "SELECT record FROM transactions WHERE status = $1
+             ORDER BY created_at, id OFFSET $2 LIMIT $3",
+            vec![status.to_string()],
+            offset,
+            limit,
+        )
+        .await
+    }
+
+    async fn list_by_campaign(
+        &self,
+        campaign_id: &str,
+        status: Option<TransactionStatus>,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        match status {
+            Some(status) => {
+                self.fetch(
+                    "SELECT record FROM transactions WHERE campaign_id = $1 AND status = $2
+                     ORDER BY created_at, id OFFSET $3 LIMIT $4",
+                    vec![campaign_id.to_string(), status.to_string()],
+                    offset,
+                    limit,
+                )
+                .await
+            }
+            None => {
+                self.fetch(
+                    "SELECT record FROM transactions WHERE campaign_id = $1
+                     ORDER BY created_at, id OFFSET $2 LIMIT $3",
+                    vec![campaign_id.to_string()],
+                    offset,
+                    limit,
+                )
+                .await
+            }
+        }
+    }
+
+    async fn list_all(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> StoreResult<(u64, Vec<TransactionRecord>)> {
+        // An empty page would hand back the same cursor forever.
+        let count = count.max(1);
+        let records = self
+            .fetch(
+                "SELECT record FROM transactions ORDER BY created_at, id OFFSET $1 LIMIT $2",
+                Vec::new(),
+                cursor as usize,
+                count,
+            )
+            .await?;
+        let next_cursor = if records.len() < count {
+            0
+        } else {
+            cursor + records.len() as u64
+        };
+        Ok((next_cursor, records))
+    }
+
+    async fn campaign_totals(&self, campaign_id: &str) -> StoreResult<CampaignTotals> {
+        let rows = sqlx::query_as::<_, (String, i64, Option<String>)>(
+            "SELECT status, COUNT(*), SUM(amount)::text FROM transactions
+             WHERE campaign_id = $1 GROUP BY status",
+        )
+        .bind(campaign_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut totals = CampaignTotals::default();
+        for status in TransactionStatus::ALL {
+            totals.counts.insert(status, 0);
+        }
+        for (status, count, amount) in rows {
+            let Ok(status) = status.parse::<TransactionStatus>() else {
+                continue;
+            };
+            totals.counts.insert(status, count as u64);
+            let amount: u128 = amount.and_then(|a| a.parse().ok()).unwrap_or(0);
+            match status {
+                TransactionStatus::Success => totals.amount_sent += amount,
+                status if status.is_terminal() => totals.amount_unsent += amount,
+                _ => {}
+            }
+        }
+        Ok(totals)
+    }
+}
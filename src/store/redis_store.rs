@@ -0,0 +1,231 @@
+use super::{CampaignTotals, StoreResult, TransactionStore};
+use crate::campaign;
+use crate::records;
+use crate::types::{TransactionRecord, TransactionStatus};
+use async_trait::async_trait;
+use deadpool_redis::{Connection, Pool};
+use log::{error, info};
+use redis::AsyncCommands;
+
+// Records are written in chunks so a large airdrop doesn't hold Redis in a
+// single huge MULTI.
+const CREATE_CHUNK_SIZE: usize = 1_000;
+
+/// Records as `txn:{id}` JSON strings, indexed by `user_txns:{receiver}`,
+/// the status sorted sets and the per-campaign keys of `campaign`.
+pub struct RedisStore {
+    pool: Pool,
+}
+
+impl RedisStore {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    /// Indexes records written before the status index existed.
+    pub async fn init(&self) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        match records::build_status_index(&mut conn).await? {
+            0 => {}
+            n => info!("Indexed {} existing transactions by status.", n),
+        }
+        Ok(())
+    }
+
+    async fn load(conn: &mut Connection, ids: &[String]) -> StoreResult<Vec<TransactionRecord>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys: Vec<String> = ids.iter().map(|id| format!("txn:{}", id)).collect();
+        let records_json: Vec<Option<String>> = conn.mget(keys).await?;
+        Ok(records_json
+            .into_iter()
+            .filter_map(|opt_json_str| {
+                opt_json_str.and_then(|json_str| serde_json::from_str(&json_str).ok())
+            })
+            .collect())
+    }
+}
+
+// Inclusive end of a LRANGE/ZRANGE page.
+fn range_end(offset: usize, limit: usize) -> isize {
+    (offset + limit) as isize - 1
+}
+
+#[async_trait]
+impl TransactionStore for RedisStore {
+    async fn create(&self, records: &[TransactionRecord]) -> StoreResult<()> {
+        let mut conn = self.pool.get().await?;
+        records::load_save_script(&mut conn).await?;
+        for chunk in records.chunks(CREATE_CHUNK_SIZE) {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for record in chunk {
+                records::save_to_pipe(&mut pipe, record, &[None]);
+                pipe.lpush(
+                    format!("user_txns:{}", record.request.reciever_id),
+                    &record.id,
+                )
+                .ignore();
+                if let Some(campaign_id) = &record.campaign_id {
+                    pipe.rpush(campaign::campaign_txns_key(campaign_id), &record.id)
+                        .ignore();
+                }
+            }
+            // The script leaves a record that is already stored alone; IDs
+            // are fresh, so one that is means a bug rather than a retry.
+            let written: Vec<bool> = pipe.query_async(&mut conn).await?;
+            let existing: Vec<&str> = chunk
+                .iter()
+                .zip(written)
+                .filter(|(_, written)| !written)
+                .map(|(record, _)| record.id.as_str())
+                .collect();
+            if !existing.is_empty() {
+                error!(
+                    "Did not store {} new transactions that already exist: {}",
+                    existing.len(),
+                    existing.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn save_if(
+        &self,
+        writes: &[(&TransactionRecord, Vec<TransactionStatus>)],
+    ) -> StoreResult<Vec<String>> {
+        let writes: Vec<(&TransactionRecord, Vec<Option<TransactionStatus>>)> = writes
+            .iter()
+            .map(|(record, allowed)| (*record, allowed.iter().copied().map(Some).collect()))
+            .collect();
+        let mut conn = self.pool.get().await?;
+        Ok(records::save(&mut conn, &writes).await?)
+    }
+
+    async fn get(&self, id: &str) -> StoreResult<Option<TransactionRecord>> {
+        let mut conn = self.pool.get().await?;
+        let record_json: Option<String> = conn.get(format!("txn:{}", id)).await?;
+        match record_json {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_many(&self, ids: &[String]) -> StoreResult<Vec<TransactionRecord>> {
+        let mut conn = self.pool.get().await?;
+        Self::load(&mut conn, ids).await
+    }
+
+    async fn list_by_receiver(
+        &self,
+        receiver_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.pool.get().await?;
+        let ids: Vec<String> = conn
+            .lrange(
+                format!("user_txns:{}", receiver_id),
+                offset as isize,
+                range_end(offset, limit),
+            )
+            .await?;
+        Self::load(&mut conn, &ids).await
+    }
+
+    async fn list_by_status(
+        &self,
+        status: TransactionStatus,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        let mut conn = self.pool.get().await?;
+        let ids =
+            records::ids_by_status(&mut conn, status, offset as isize, limit as isize).await?;
+        Self::load(&mut conn, &ids).await
+    }
+
+    async fn list_by_campaign(
+        &self,
+        campaign_id: &str,
+        status: Option<TransactionStatus>,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.pool.get().await?;
+        let ids: Vec<String> = match status {
+            Some(status) => {
+                conn.zrange(
+                    campaign::status_index_key(campaign_id, status),
+                    offset as isize,
+                    range_end(offset, limit),
+                )
+                .await?
+            }
+            None => {
+                conn.lrange(
+                    campaign::campaign_txns_key(campaign_id),
+                    offset as isize,
+                    range_end(offset, limit),
+                )
+                .await?
+            }
+        };
+        Self::load(&mut conn, &ids).await
+    }
+
+    async fn list_all(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> StoreResult<(u64, Vec<TransactionRecord>)> {
+        let mut conn = self.pool.get().await?;
+        let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg("txn:*")
+            .arg("COUNT")
+            .arg(count.max(1))
+            .query_async(&mut conn)
+            .await?;
+        let ids: Vec<String> = keys
+            .iter()
+            .filter_map(|key| key.strip_prefix("txn:"))
+            .map(str::to_string)
+            .collect();
+        Ok((next_cursor, Self::load(&mut conn, &ids).await?))
+    }
+
+    async fn campaign_totals(&self, campaign_id: &str) -> StoreResult<CampaignTotals> {
+        let mut conn = self.pool.get().await?;
+        let mut pipe = redis::pipe();
+        for status in TransactionStatus::ALL {
+            pipe.zcard(campaign::status_index_key(campaign_id, status));
+        }
+        pipe.hget(campaign::stats_key(campaign_id), "amount_sent")
+            .hget(campaign::stats_key(campaign_id), "amount_unsent");
+        let mut replies: Vec<redis::Value> = pipe.query_async(&mut conn).await?;
+
+        let amount_unsent: Option<String> = redis::from_redis_value(&replies.pop().unwrap())?;
+        let amount_sent: Option<String> = redis::from_redis_value(&replies.pop().unwrap())?;
+        let mut totals = CampaignTotals {
+            amount_sent: amount_sent.and_then(|a| a.parse().ok()).unwrap_or(0),
+            amount_unsent: amount_unsent.and_then(|a| a.parse().ok()).unwrap_or(0),
+            ..Default::default()
+        };
+        for (status, reply) in TransactionStatus::ALL.into_iter().zip(replies) {
+            totals
+                .counts
+                .insert(status, redis::from_redis_value(&reply)?);
+        }
+        Ok(totals)
+    }
+}
@@ -0,0 +1,42 @@
+use near_api::{Contract, NetworkConfig};
+use near_sdk::AccountId;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+// What to do when `ft_decimals` in Settings.toml disagrees with the contract.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DecimalsMismatchMode {
+    /// Refuse to start.
+    Fail,
+    /// Log a warning and use the contract's decimals.
+    Warn,
+}
+
+/// NEP-148 metadata of the token, as returned by `ft_metadata`.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct TokenMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference_hash: Option<String>,
+    pub decimals: u8,
+}
+
+pub async fn fetch_metadata(
+    ft_contract_id: AccountId,
+    network_config: &NetworkConfig,
+) -> Result<TokenMetadata, Box<dyn std::error::Error + Send + Sync>> {
+    let metadata = Contract(ft_contract_id)
+        .call_function("ft_metadata", json!({}))?
+        .read_only::<TokenMetadata>()
+        .fetch_from(network_config)
+        .await?;
+    Ok(metadata.data)
+}
@@ -1,22 +1,135 @@
+use crate::amount;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema)]
 pub enum TransactionStatus {
+    /// Accepted and waiting in the transfer queue.
     Queued,
+    /// Taken off the queue by the worker and being assembled into a batch.
+    Batched,
+    /// Signed as part of a batch transaction, whose hash is stored before
+    /// it is broadcast.
+    Submitted,
+    /// Hit a retryable error and waiting out its backoff.
+    Retrying,
     Success,
     Failure,
+    /// Withdrawn before it was sent.
+    Cancelled,
+    /// Sat in the queue longer than `transfer_ttl_secs` and was never sent.
+    Expired,
+}
+
+impl TransactionStatus {
+    pub const ALL: [TransactionStatus; 8] = [
+        TransactionStatus::Queued,
+        TransactionStatus::Batched,
+        TransactionStatus::Submitted,
+        TransactionStatus::Retrying,
+        TransactionStatus::Success,
+        TransactionStatus::Failure,
+        TransactionStatus::Cancelled,
+        TransactionStatus::Expired,
+    ];
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            TransactionStatus::Success
+                | TransactionStatus::Failure
+                | TransactionStatus::Cancelled
+                | TransactionStatus::Expired
+        )
+    }
+
+    /// Whether a record may move from `self` to `next`. Transfers only move
+    /// forward through the lifecycle and never leave a terminal state; the
+    /// only loops are a retried transfer going back into a batch, a batch
+    /// claimed again after a restart, and a submitted transfer resubmitted
+    /// after its batch failed. A submitted transfer never goes back into a
+    /// batch: its transaction may have gone through.
+    pub fn can_transition_to(&self, next: TransactionStatus) -> bool {
+        use TransactionStatus::*;
+        match self {
+            Queued => matches!(next, Batched | Cancelled | Expired),
+            Batched => matches!(next, Batched | Submitted | Failure | Cancelled | Expired),
+            Submitted => matches!(next, Submitted | Retrying | Success | Failure),
+            Retrying => matches!(next, Batched | Failure | Cancelled | Expired),
+            Success | Failure | Cancelled | Expired => false,
+        }
+    }
+
+    /// Whether `next` can be reached from `self` in one or more transitions.
+    /// A record stored in `self` may be overwritten by one in `next` only
+    /// then, so a stale write can't undo a transfer that already finished.
+    pub fn can_reach(&self, next: TransactionStatus) -> bool {
+        let mut reached: Vec<TransactionStatus> = Vec::new();
+        let mut frontier = vec![*self];
+        while let Some(status) = frontier.pop() {
+            for candidate in TransactionStatus::ALL {
+                if status.can_transition_to(candidate) && !reached.contains(&candidate) {
+                    reached.push(candidate);
+                    frontier.push(candidate);
+                }
+            }
+        }
+        reached.contains(&next)
+    }
+}
+
+impl std::fmt::Display for TransactionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::str::FromStr for TransactionStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TransactionStatus::ALL
+            .into_iter()
+            .find(|status| status.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<String> =
+                    TransactionStatus::ALL.iter().map(|status| status.to_string()).collect();
+                format!("Invalid status provided. Use one of: {}.", names.join(", "))
+            })
+    }
+}
+
+// When a record entered a status.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct StatusChange {
+    pub status: TransactionStatus,
+    #[schema(value_type = String)]
+    pub at: DateTime<Utc>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, ToSchema)]
 pub struct TokenTransferRequest {
     #[schema(value_type = String)]
     pub reciever_id: String,
+    /// Raw integer amount. Leave empty when sending `amount_decimal`.
+    #[serde(default)]
     pub amount: String,
+    /// Amount in whole tokens, e.g. `"12.5"`, converted using `ft_decimals`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_decimal: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub memo: Option<String>,
+    /// Pays the receiver at most once per key, e.g. a campaign or reward ID.
+    /// A second transfer with the same key and receiver is rejected unless
+    /// the first one failed, was cancelled or expired.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedupe_key: Option<String>,
+    /// URL that is sent this transfer's status changes, signed with the
+    /// submitting key's secret from `GET /webhooks/callback-secret`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
@@ -25,28 +138,496 @@ pub struct TransactionRecord {
     pub sender_id: String,
     pub status: TransactionStatus,
     pub request: TokenTransferRequest,
+    /// Raw integer amount sent to the contract.
+    #[serde(default)]
+    pub amount_raw: String,
+    /// The same amount in whole tokens.
+    #[serde(default)]
+    pub amount_formatted: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub txn_hash: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error_message: Option<String>,
     #[schema(value_type = String)]
     pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<TransferAttempt>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub status_history: Vec<StatusChange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub campaign_id: Option<String>,
+    /// ID of the API key the transfer was submitted with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<String>,
+    /// How the receiver was screened when the transfer was accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub screening: Option<ScreeningVerdict>,
+}
+
+// One submission of a transfer to the network and what came of it.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct TransferAttempt {
+    pub attempt: u32,
+    #[schema(value_type = String)]
+    pub at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txn_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub retryable: bool,
 }
 
 impl TransactionRecord {
-    pub fn new(sender_id: String, request: TokenTransferRequest) -> Self {
+    /// Creates a queued record for a validated request whose `amount` has
+    /// already been normalized to the raw value.
+    pub fn new(sender_id: String, request: TokenTransferRequest, ft_decimals: u8) -> Self {
+        let created_at = Utc::now();
+        let amount_raw = request.amount.parse::<u128>().unwrap_or(0);
         Self {
             id: Uuid::new_v4().to_string(),
             sender_id,
             status: TransactionStatus::Queued,
             request,
+            amount_raw: amount_raw.to_string(),
+            amount_formatted: amount::format_amount(amount_raw, ft_decimals),
             txn_hash: None,
             error_message: None,
+            created_at,
+            attempts: Vec::new(),
+            status_history: vec![StatusChange {
+                status: TransactionStatus::Queued,
+                at: created_at,
+            }],
+            campaign_id: None,
+            api_key_id: None,
+            screening: None,
+        }
+    }
+
+    /// Moves the record to `next`, stamping the time of the change. Fails
+    /// without touching the record if the lifecycle doesn't allow it.
+    pub fn transition(&mut self, next: TransactionStatus) -> Result<(), String> {
+        if !self.status.can_transition_to(next) {
+            return Err(format!(
+                "Transaction {} cannot move from {} to {}",
+                self.id, self.status, next
+            ));
+        }
+        self.status = next;
+        self.status_history.push(StatusChange {
+            status: next,
+            at: Utc::now(),
+        });
+        Ok(())
+    }
+
+    /// The raw amount of the transfer.
+    pub fn raw_amount(&self) -> u128 {
+        self.request.amount.parse::<u128>().unwrap_or(0)
+    }
+
+    /// Appends an entry to the attempts history and returns its number.
+    pub fn record_attempt(
+        &mut self,
+        txn_hash: Option<String>,
+        error: Option<String>,
+        retryable: bool,
+    ) -> u32 {
+        let attempt = self.attempts.len() as u32 + 1;
+        self.attempts.push(TransferAttempt {
+            attempt,
+            at: Utc::now(),
+            txn_hash,
+            error,
+            retryable,
+        });
+        attempt
+    }
+}
+
+// --- CAMPAIGN STRUCTS ---
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+pub enum CampaignStatus {
+    /// Transfers are sent as the worker reaches them.
+    #[default]
+    Active,
+    /// Queued transfers are held back until the campaign is resumed.
+    Paused,
+    /// Transfers not yet sent are cancelled instead of being sent.
+    Cancelled,
+}
+
+// A named group of transfers submitted together through POST /transfers/bulk.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct Campaign {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub status: CampaignStatus,
+    #[schema(value_type = String)]
+    pub created_at: DateTime<Utc>,
+    pub total_transfers: u64,
+    /// Sum of the raw amounts of every accepted transfer.
+    pub total_amount: String,
+    /// The API key the campaign was submitted with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<String>,
+}
+
+impl Campaign {
+    pub fn new(name: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            name,
+            status: CampaignStatus::Active,
             created_at: Utc::now(),
+            total_transfers: 0,
+            total_amount: "0".to_string(),
+            api_key_id: None,
         }
     }
 }
 
+// Aggregate progress of a campaign, computed from its per-status indexes.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct CampaignProgress {
+    pub campaign: Campaign,
+    /// Number of the campaign's transfers currently in each status.
+    pub counts: std::collections::BTreeMap<String, u64>,
+    /// Raw amount delivered by successful transfers.
+    pub amount_sent: String,
+    /// Raw amount of transfers that ended without sending (failed, cancelled or expired).
+    pub amount_unsent: String,
+    /// Raw amount still to be sent by pending transfers.
+    pub amount_remaining: String,
+    /// Whether every transfer has reached a final status.
+    pub completed: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct CampaignTransactionsQuery {
+    /// Only return transfers in this status.
+    pub status: Option<String>,
+    pub offset: Option<isize>,
+    pub limit: Option<isize>,
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+pub struct BulkTransferRequest {
+    pub name: String,
+    pub transfers: Vec<TokenTransferRequest>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct BulkTransferQuery {
+    /// Campaign name, required when the body is CSV.
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct BulkRowResult {
+    /// 1-based position of the row in the submitted list, or the line of the
+    /// CSV file it starts on.
+    pub row: usize,
+    pub reciever_id: String,
+    pub accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct BulkTransferResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub campaign_id: Option<String>,
+    pub accepted: usize,
+    pub rejected: usize,
+    pub results: Vec<BulkRowResult>,
+}
+
+// --- KEY POOL STRUCTS ---
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+pub enum KeyState {
+    /// Handed out to sign batches.
+    #[default]
+    Active,
+    /// Failing; kept out of rotation until it is checked again.
+    Quarantined,
+    /// No longer on the account.
+    Removed,
+    /// Taken out of the pool by a resize and about to be deleted.
+    Retired,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct KeyHealth {
+    pub public_key: String,
+    pub state: KeyState,
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_nonce: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[schema(value_type = Option<String>)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[schema(value_type = Option<String>)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quarantined_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+pub struct ResizePoolRequest {
+    /// Number of keys the pool should have.
+    pub target: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ResizePoolResponse {
+    pub pool_size: usize,
+    /// Number of batches that may now be in flight at once.
+    pub concurrency: usize,
+}
+
+// --- API KEY STRUCTS ---
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    /// Submit and cancel transfers and campaigns, and read them back.
+    Transfer,
+    /// Read transactions, campaigns, the token and the balance. Without
+    /// admin, only a key's own transactions and campaigns are visible.
+    Read,
+    /// Everything, including the /admin endpoints.
+    Admin,
+}
+
+// A client's API key. The secret itself is only returned once, when the key
+// is created; Redis keeps its SHA-256 hash.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    #[schema(value_type = String)]
+    pub created_at: DateTime<Utc>,
+    #[schema(value_type = Option<String>)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// Limits replacing the configured per-key defaults.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<ClientLimits>,
+}
+
+// Per-key rate limits and quotas. A missing field keeps the default from
+// Settings.toml; 0 removes that limit for the key.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+pub struct ClientLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_second: Option<u64>,
+    /// Raw amount the key may send per hour.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_per_hour: Option<String>,
+    /// Raw amount the key may send per day.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_per_day: Option<String>,
+}
+
+impl ApiKey {
+    /// Admin grants every scope, and Transfer grants Read too so a client
+    /// can follow the transfers it submits.
+    pub fn allows(&self, scope: ApiKeyScope) -> bool {
+        self.scopes.iter().any(|granted| {
+            *granted == scope
+                || *granted == ApiKeyScope::Admin
+                || (*granted == ApiKeyScope::Transfer && scope == ApiKeyScope::Read)
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+pub struct CreateApiKeyRequest {
+    /// Who or what the key is for, e.g. the client's name.
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    #[serde(default)]
+    pub limits: Option<ClientLimits>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct CreateApiKeyResponse {
+    pub key: ApiKey,
+    /// The key to send in the `X-API-Key` header. It can't be shown again.
+    pub secret: String,
+}
+
+// --- SCREENING STRUCTS ---
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScreeningDecision {
+    Allow,
+    Deny,
+}
+
+// The outcome of screening a receiver and the check that decided it.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ScreeningVerdict {
+    pub decision: ScreeningDecision,
+    /// `deny_list`, `allow_list`, `implicit_account`, `deny_pattern`,
+    /// `allow_pattern`, `hook`, `hook_unavailable` or `default`.
+    pub rule: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[schema(value_type = String)]
+    pub checked_at: DateTime<Utc>,
+}
+
+impl ScreeningVerdict {
+    pub fn is_allowed(&self) -> bool {
+        self.decision == ScreeningDecision::Allow
+    }
+}
+
+// One of the screening lists kept in Redis.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScreeningList {
+    /// Receivers let through without the pattern rules and the hook.
+    Allow,
+    /// Receivers always turned away.
+    Deny,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+pub struct ScreeningLists {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ScreeningListsResponse {
+    /// From Settings.toml; only changed by editing it and restarting.
+    pub configured: ScreeningLists,
+    /// Kept in Redis and changed through POST /admin/screening/{list}.
+    pub managed: ScreeningLists,
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+pub struct UpdateScreeningListRequest {
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+// --- WEBHOOK STRUCTS ---
+
+// A URL that is sent the status changes of every transfer (when global) or
+// of the transfers submitted with one API key. Its signing secret is only
+// returned when it is created.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// The API key whose transfers it receives; missing for global webhooks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<String>,
+    #[schema(value_type = String)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    /// Receive every transfer's events instead of only the calling key's.
+    /// Needs the admin scope.
+    #[serde(default)]
+    pub global: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct CreateWebhookResponse {
+    pub webhook: Webhook,
+    /// Key for checking the `X-Webhook-Signature` header. It can't be shown again.
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct CallbackSecretResponse {
+    /// Key for checking the `X-Webhook-Signature` header of deliveries to the
+    /// `callback_url` of this key's transfers.
+    pub secret: String,
+}
+
+// The JSON body POSTed to a webhook when a transfer changes status.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct WebhookEvent {
+    pub id: String,
+    /// Always `transfer.status_changed`.
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub transaction_id: String,
+    pub status: TransactionStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_status: Option<TransactionStatus>,
+    #[schema(value_type = String)]
+    pub occurred_at: DateTime<Utc>,
+    pub record: TransactionRecord,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum DeliveryStatus {
+    /// Waiting for its first attempt or for a retry.
+    Pending,
+    /// The URL answered with a 2xx status.
+    Delivered,
+    /// Every attempt failed, or the webhook was removed.
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct DeliveryAttempt {
+    pub attempt: u32,
+    #[schema(value_type = String)]
+    pub at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// One event on its way to one URL.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct WebhookDelivery {
+    pub id: String,
+    /// The registered webhook, or missing for a request's `callback_url`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_id: Option<String>,
+    pub url: String,
+    pub status: DeliveryStatus,
+    pub event: WebhookEvent,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<DeliveryAttempt>,
+    #[schema(value_type = String)]
+    pub created_at: DateTime<Utc>,
+    #[schema(value_type = Option<String>)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_attempt_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct DeliveryLogQuery {
+    /// How many of the most recent deliveries to return (default 100, at most 1000).
+    pub limit: Option<usize>,
+}
+
 // --- PAGINATION AND RESPONSE STRUCTS ---
 
 #[derive(Deserialize, ToSchema, IntoParams)]
@@ -68,8 +649,94 @@ pub struct TransferResponse {
     pub transaction_id: String,
 }
 
+// Sender balance as seen by the service. `balance` and `available` are
+// missing until `ft_balance_of` has been read once.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct BalanceResponse {
+    pub account_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<String>,
+    /// Raw amount held for accepted transfers that haven't finished.
+    pub reserved: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available: Option<String>,
+    #[schema(value_type = Option<String>)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refreshed_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct PaginatedTransactionResponse {
     pub next_cursor: u64,
     pub records: Vec<TransactionRecord>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TransactionStatus::*;
+
+    #[test]
+    fn terminal_statuses_are_never_left() {
+        for from in TransactionStatus::ALL.into_iter().filter(|status| status.is_terminal()) {
+            for next in TransactionStatus::ALL {
+                assert!(!from.can_transition_to(next), "{} -> {}", from, next);
+                assert!(!from.can_reach(next), "{} ->* {}", from, next);
+            }
+        }
+    }
+
+    #[test]
+    fn submitted_transfers_never_go_back_into_a_batch() {
+        assert!(!Submitted.can_transition_to(Batched));
+        assert!(!Submitted.can_transition_to(Cancelled));
+        assert!(!Submitted.can_transition_to(Expired));
+        assert!(Submitted.can_transition_to(Submitted));
+        assert!(Submitted.can_transition_to(Success));
+        // Only through a retry, once the batch provably failed.
+        assert!(Submitted.can_reach(Batched));
+        assert!(Submitted.can_transition_to(Retrying) && Retrying.can_transition_to(Batched));
+    }
+
+    #[test]
+    fn transfers_can_be_withdrawn_only_before_they_are_sent() {
+        assert!(Queued.can_transition_to(Cancelled));
+        assert!(Batched.can_transition_to(Cancelled));
+        assert!(Retrying.can_transition_to(Cancelled));
+        assert!(!Submitted.can_transition_to(Cancelled));
+        assert!(!Queued.can_transition_to(Submitted));
+        assert!(!Queued.can_transition_to(Success));
+    }
+
+    #[test]
+    fn can_reach_follows_every_path() {
+        assert!(Queued.can_reach(Success));
+        assert!(Queued.can_reach(Retrying));
+        assert!(Retrying.can_reach(Retrying));
+        assert!(Batched.can_reach(Batched));
+        assert!(!Queued.can_reach(Queued));
+        assert!(!Batched.can_reach(Queued));
+        for status in TransactionStatus::ALL {
+            assert!(Queued.can_reach(status) || status == Queued);
+        }
+    }
+
+    #[test]
+    fn transfer_keys_may_read_and_admin_keys_may_do_anything() {
+        let key = |scopes: Vec<ApiKeyScope>| ApiKey {
+            id: "key-a".to_string(),
+            name: "test".to_string(),
+            scopes,
+            created_at: Utc::now(),
+            revoked_at: None,
+            limits: None,
+        };
+        let transfer = key(vec![ApiKeyScope::Transfer]);
+        assert!(transfer.allows(ApiKeyScope::Transfer) && transfer.allows(ApiKeyScope::Read));
+        assert!(!transfer.allows(ApiKeyScope::Admin));
+        let read = key(vec![ApiKeyScope::Read]);
+        assert!(!read.allows(ApiKeyScope::Transfer) && !read.allows(ApiKeyScope::Admin));
+        let admin = key(vec![ApiKeyScope::Admin]);
+        assert!(admin.allows(ApiKeyScope::Transfer) && admin.allows(ApiKeyScope::Read));
+    }
+}
@@ -0,0 +1,206 @@
+use crate::amount;
+use crate::types::TokenTransferRequest;
+use near_api::near_primitives::account::id::{AccountIdRef, AccountType, ParseErrorKind};
+use near_sdk::AccountId;
+use std::str::FromStr;
+
+fn is_separator(c: char) -> bool {
+    matches!(c, '.' | '-' | '_')
+}
+
+fn is_lower_hex(s: &str) -> bool {
+    s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Parses a receiver into an `AccountId`, explaining exactly what is wrong
+/// with it if it isn't one.
+pub fn parse_account_id(id: &str) -> Result<AccountId, String> {
+    if id.trim().is_empty() {
+        return Err("receiver_id must not be empty".to_string());
+    }
+    let error = match AccountId::from_str(id) {
+        Ok(account_id) => return Ok(account_id),
+        Err(error) => error,
+    };
+
+    // Implicit accounts are hex addresses, which are often pasted with
+    // upper-case digits.
+    let lowercase = id.to_ascii_lowercase();
+    if lowercase.len() == 64 && is_lower_hex(&lowercase) {
+        return Err(format!(
+            "receiver_id {:?} looks like an implicit account but has upper-case hex digits; use {}",
+            id, lowercase
+        ));
+    }
+    if lowercase.len() == 42 && lowercase.strip_prefix("0x").is_some_and(is_lower_hex) {
+        return Err(format!(
+            "receiver_id {:?} looks like an ETH-implicit account but has upper-case hex digits; use {}",
+            id, lowercase
+        ));
+    }
+
+    match error.kind() {
+        ParseErrorKind::TooShort => Err(format!(
+            "receiver_id {:?} is too short; account IDs have at least {} characters",
+            id,
+            AccountId::MIN_LEN
+        )),
+        ParseErrorKind::TooLong => Err(format!(
+            "receiver_id is {} characters long; account IDs have at most {}",
+            id.chars().count(),
+            AccountId::MAX_LEN
+        )),
+        ParseErrorKind::RedundantSeparator => {
+            let chars: Vec<char> = id.chars().collect();
+            let index = (0..chars.len())
+                .find(|&index| {
+                    is_separator(chars[index])
+                        && (index == 0
+                            || index == chars.len() - 1
+                            || is_separator(chars[index - 1]))
+                })
+                .unwrap_or(0);
+            Err(format!(
+                "receiver_id {:?} has a misplaced {:?} at index {}; '.', '-' and '_' can't start or end an account ID or follow each other",
+                id, chars[index], index
+            ))
+        }
+        ParseErrorKind::InvalidChar => {
+            let found = id
+                .chars()
+                .enumerate()
+                .find(|(_, c)| !(c.is_ascii_lowercase() || c.is_ascii_digit() || is_separator(*c)));
+            match found {
+                Some((index, c)) if c.is_ascii_uppercase() => Err(format!(
+                    "receiver_id {:?} has the upper-case {:?} at index {}; account IDs are lower-case",
+                    id, c, index
+                )),
+                Some((index, c)) if c.is_whitespace() => Err(format!(
+                    "receiver_id {:?} has whitespace at index {}",
+                    id, index
+                )),
+                Some((index, c)) => Err(format!(
+                    "receiver_id {:?} has the invalid character {:?} at index {}; only a-z, 0-9, '.', '-' and '_' are allowed",
+                    id, c, index
+                )),
+                None => Err(format!("receiver_id {:?} is invalid: {}", id, error)),
+            }
+        }
+        _ => Err(format!("receiver_id {:?} is invalid: {}", id, error)),
+    }
+}
+
+/// Whether `account_id` is named, NEAR-implicit (64 hex characters) or
+/// ETH-implicit (`0x` and 40 hex characters). `None` if it isn't valid.
+pub fn account_type(account_id: &str) -> Option<AccountType> {
+    AccountIdRef::new(account_id)
+        .ok()
+        .map(|account_id| account_id.get_account_type())
+}
+
+/// Checks a transfer before it is accepted and returns its raw amount. The
+/// amount is taken from `amount` (raw) or `amount_decimal` (in whole tokens
+/// of `ft_decimals` places), and exactly one of the two must be given. It
+/// must not be 0: NEP-141 contracts refuse such a transfer.
+pub fn validate_transfer(request: &TokenTransferRequest, ft_decimals: u8) -> Result<u128, String> {
+    parse_account_id(&request.reciever_id)?;
+    let raw = match (&request.amount_decimal, request.amount.is_empty()) {
+        (Some(_), false) => Err("Give either amount or amount_decimal, not both".to_string()),
+        (Some(decimal), true) => amount::parse_decimal(decimal.trim(), ft_decimals),
+        (None, true) => Err("amount or amount_decimal is required".to_string()),
+        (None, false) => request.amount.parse::<u128>().map_err(|_| {
+            format!(
+                "amount {:?} is not a non-negative integer",
+                request.amount
+            )
+        }),
+    }?;
+    if raw == 0 {
+        return Err("amount must be greater than 0".to_string());
+    }
+    Ok(raw)
+}
+
+/// Validates `request` and rewrites its `amount` to the raw value, so the
+/// rest of the service only ever deals with raw amounts.
+pub fn normalize_transfer(
+    request: &mut TokenTransferRequest,
+    ft_decimals: u8,
+) -> Result<u128, String> {
+    let raw = validate_transfer(request, ft_decimals)?;
+    request.amount = raw.to_string();
+    Ok(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(amount: &str, amount_decimal: Option<&str>) -> TokenTransferRequest {
+        serde_json::from_value(serde_json::json!({
+            "reciever_id": "bob.near",
+            "amount": amount,
+            "amount_decimal": amount_decimal,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn zero_amounts_are_refused() {
+        assert_eq!(validate_transfer(&request("5", None), 6), Ok(5));
+        assert_eq!(validate_transfer(&request("", Some("0.5")), 1), Ok(5));
+        for request in [request("0", None), request("000", None), request("", Some("0.000"))] {
+            assert_eq!(
+                validate_transfer(&request, 6),
+                Err("amount must be greater than 0".to_string())
+            );
+        }
+    }
+
+    fn rejection(id: &str) -> String {
+        parse_account_id(id).unwrap_err()
+    }
+
+    #[test]
+    fn upper_case_implicit_accounts_get_the_lower_case_form() {
+        let message = rejection(&"AB".repeat(32));
+        assert!(message.contains("looks like an implicit account"), "{}", message);
+        assert!(message.ends_with(&format!("use {}", "ab".repeat(32))), "{}", message);
+        let eth_implicit = format!("0x{}", "Cd".repeat(20));
+        let message = rejection(&eth_implicit);
+        assert!(message.contains("ETH-implicit"), "{}", message);
+        assert!(message.ends_with(&format!("use 0x{}", "cd".repeat(20))), "{}", message);
+    }
+
+    #[test]
+    fn redundant_separators_are_pointed_at() {
+        let cases = [("alice..near", 6), ("-alice.near", 0), ("alice.near_", 10), ("a.-b", 2)];
+        for (id, index) in cases {
+            let message = rejection(id);
+            assert!(message.contains(&format!("at index {};", index)), "{}: {}", id, message);
+        }
+    }
+
+    #[test]
+    fn length_limits_are_explained() {
+        assert!(rejection("a").contains("too short"));
+        let long = "a".repeat(65);
+        assert!(rejection(&long).contains("is 65 characters long; account IDs have at most 64"));
+        assert!(parse_account_id(&"a".repeat(64)).is_ok());
+        assert!(rejection("  ").contains("must not be empty"));
+    }
+
+    #[test]
+    fn account_types_are_told_apart() {
+        assert!(matches!(account_type("alice.near"), Some(AccountType::NamedAccount)));
+        assert!(matches!(
+            account_type(&"ab".repeat(32)),
+            Some(AccountType::NearImplicitAccount)
+        ));
+        assert!(matches!(
+            account_type(&format!("0x{}", "cd".repeat(20))),
+            Some(AccountType::EthImplicitAccount)
+        ));
+        assert!(account_type(&"AB".repeat(32)).is_none());
+    }
+}
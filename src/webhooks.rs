@@ -0,0 +1,864 @@
+use crate::config::Settings;
+use crate::retry;
+use crate::store::{CampaignTotals, StoreResult, TransactionStore};
+use crate::types::{
+    DeliveryAttempt, DeliveryStatus, TokenTransferRequest, TransactionRecord, TransactionStatus,
+    Webhook, WebhookDelivery, WebhookEvent,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool_redis::{Connection, Pool};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use redis::{AsyncCommands, RedisResult};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const EVENT_STATUS_CHANGED: &str = "transfer.status_changed";
+pub const DELIVERY_HEADER: &str = "X-Webhook-Id";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+// Webhook ID -> Webhook JSON.
+const WEBHOOKS_KEY: &str = "webhooks";
+// Webhook ID -> signing secret.
+const WEBHOOK_SECRETS_KEY: &str = "webhook_secrets";
+// Deliveries waiting for an attempt, scored by the Unix time in milliseconds
+// at which they are due.
+const DUE_KEY: &str = "webhook_deliveries_due";
+// Deliveries being attempted. Entries are only removed once the outcome is
+// stored, so a crash leaves them here to be put back at the next start.
+const SENDING_KEY: &str = "webhook_deliveries_sending";
+// Deliveries kept in a webhook's log, newest first.
+const WEBHOOK_LOG_LEN: isize = 1_000;
+
+// Moves up to ARGV[2] deliveries due by ARGV[1] from KEYS[1] to KEYS[2].
+const CLAIM_SCRIPT: &str = r"
+local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, id in ipairs(ids) do
+    redis.call('ZREM', KEYS[1], id)
+    redis.call('LPUSH', KEYS[2], id)
+end
+return ids
+";
+
+// Puts every delivery left in KEYS[2] back into KEYS[1], due at ARGV[1].
+const RECOVER_SCRIPT: &str = r"
+local ids = redis.call('LRANGE', KEYS[2], 0, -1)
+for _, id in ipairs(ids) do
+    redis.call('ZADD', KEYS[1], ARGV[1], id)
+end
+redis.call('DEL', KEYS[2])
+return #ids
+";
+
+// KEYS[i] how many of a record's status changes were already turned into
+// deliveries, ARGV[i + 1] how many it has now; ARGV[1] TTL in seconds.
+// Raises each count and returns the ones from before.
+const NOTIFIED_SCRIPT: &str = r"
+local before = {}
+for i = 1, #KEYS do
+    local seen = tonumber(redis.call('GET', KEYS[i]) or '0')
+    local now = tonumber(ARGV[i + 1])
+    if now > seen then
+        redis.call('SET', KEYS[i], now, 'EX', ARGV[1])
+    end
+    before[i] = seen
+end
+return before
+";
+
+fn delivery_key(id: &str) -> String {
+    format!("webhook_delivery:{}", id)
+}
+
+/// List of the deliveries of a transfer's events, oldest first.
+fn transaction_log_key(transaction_id: &str) -> String {
+    format!("webhook_deliveries_by_txn:{}", transaction_id)
+}
+
+/// List of a webhook's most recent deliveries, newest first.
+fn webhook_log_key(webhook_id: &str) -> String {
+    format!("webhook_deliveries_by_hook:{}", webhook_id)
+}
+
+fn notified_key(transaction_id: &str) -> String {
+    format!("webhook_notified:{}", transaction_id)
+}
+
+fn generate_secret() -> String {
+    let bytes: [u8; 32] = rand::random();
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("whsec_{}", hex)
+}
+
+/// Checks that `url` is an absolute http(s) URL on a public host. Loopback,
+/// private, link-local and other reserved addresses are refused so a URL
+/// can't reach the service's own network.
+pub fn check_url(url: &str) -> Result<(), String> {
+    let parsed = match url::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+        Ok(_) => return Err(format!("{} is not an http or https URL", url)),
+        Err(e) => return Err(format!("{} is not a valid URL: {}", url, e)),
+    };
+    let public = match parsed.host() {
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+        Some(url::Host::Ipv4(ip)) => is_public(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_public(IpAddr::V6(ip)),
+        None => false,
+    };
+    if !public {
+        return Err(format!("{} is not on a public host", url));
+    }
+    Ok(())
+}
+
+// Whether `ip` is reachable on the public internet.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space (100.64.0.0/10), the IETF block
+                // (192.0.0.0/24), benchmarking (198.18.0.0/15) and reserved
+                // (240.0.0.0/4).
+                || (a == 100 && b & 0xc0 == 64)
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && b & 0xfe == 18)
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // Documentation (2001:db8::/32).
+                || (first == 0x2001 && ip.segments()[1] == 0xdb8))
+        }
+    }
+}
+
+// Resolves host names for deliveries, refusing names that point at a
+// non-public address, so a public-looking name can't reach the service's
+// own network either.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect();
+            if addrs.iter().any(|addr| !is_public(addr.ip())) {
+                return Err(format!("{} resolves to a non-public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Checks a transfer's `callback_url`, if it has one. Deliveries to it are
+/// signed with the submitting key's callback secret, which is derived from
+/// `WEBHOOK_SECRET`, so it has to be set.
+pub fn check_callback_url(
+    settings: &Settings,
+    request: &TokenTransferRequest,
+) -> Result<(), String> {
+    let Some(url) = &request.callback_url else {
+        return Ok(());
+    };
+    if settings.webhook_secret.is_none() {
+        return Err("callback_url can't be used: WEBHOOK_SECRET is not set".to_string());
+    }
+    check_url(url).map_err(|message| format!("callback_url {}", message))
+}
+
+/// The secret that signs deliveries to the `callback_url` of transfers
+/// submitted with the API key `api_key_id`. Each key gets its own, so one
+/// client can't forge events for another's endpoint.
+pub fn callback_secret(webhook_secret: &str, api_key_id: &str) -> String {
+    format!("whsec_{}", hmac_hex(webhook_secret, &[api_key_id.as_bytes()]))
+}
+
+fn hmac_hex(secret: &str, parts: &[&[u8]]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `{timestamp}.{body}` under `secret`,
+/// as sent in the `X-Webhook-Signature` header.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let timestamp = timestamp.to_string();
+    let hex = hmac_hex(secret, &[timestamp.as_bytes(), b".", body.as_bytes()]);
+    format!("sha256={}", hex)
+}
+
+/// Registers `url`, for the transfers of `api_key_id` or, if `None`, for
+/// every transfer. Returns the webhook with its secret, which is only shown
+/// here.
+pub async fn create(
+    conn: &mut Connection,
+    url: String,
+    api_key_id: Option<String>,
+) -> RedisResult<(Webhook, String)> {
+    let webhook = Webhook {
+        id: Uuid::new_v4().to_string(),
+        url,
+        api_key_id,
+        created_at: Utc::now(),
+    };
+    let secret = generate_secret();
+    redis::pipe()
+        .atomic()
+        .hset(
+            WEBHOOKS_KEY,
+            &webhook.id,
+            serde_json::to_string(&webhook).unwrap(),
+        )
+        .ignore()
+        .hset(WEBHOOK_SECRETS_KEY, &webhook.id, &secret)
+        .ignore()
+        .query_async::<()>(conn)
+        .await?;
+    Ok((webhook, secret))
+}
+
+pub async fn get(conn: &mut Connection, id: &str) -> RedisResult<Option<Webhook>> {
+    let webhook_json: Option<String> = conn.hget(WEBHOOKS_KEY, id).await?;
+    Ok(webhook_json.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+/// Every webhook, oldest first.
+pub async fn list(conn: &mut Connection) -> RedisResult<Vec<Webhook>> {
+    let entries: Vec<String> = conn.hvals(WEBHOOKS_KEY).await?;
+    let mut webhooks: Vec<Webhook> = entries
+        .iter()
+        .filter_map(|json| serde_json::from_str(json).ok())
+        .collect();
+    webhooks.sort_by_key(|webhook| webhook.created_at);
+    Ok(webhooks)
+}
+
+/// Unregisters a webhook. Deliveries still pending for it fail.
+pub async fn remove(conn: &mut Connection, id: &str) -> RedisResult<Option<Webhook>> {
+    let Some(webhook) = get(conn, id).await? else {
+        return Ok(None);
+    };
+    redis::pipe()
+        .atomic()
+        .hdel(WEBHOOKS_KEY, id)
+        .ignore()
+        .hdel(WEBHOOK_SECRETS_KEY, id)
+        .ignore()
+        .query_async::<()>(conn)
+        .await?;
+    Ok(Some(webhook))
+}
+
+async fn load_deliveries(
+    conn: &mut Connection,
+    ids: Vec<String>,
+) -> RedisResult<Vec<WebhookDelivery>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys: Vec<String> = ids.iter().map(|id| delivery_key(id)).collect();
+    let entries: Vec<Option<String>> = conn.mget(keys).await?;
+    Ok(entries
+        .into_iter()
+        .flatten()
+        .filter_map(|json| serde_json::from_str(&json).ok())
+        .collect())
+}
+
+/// Deliveries of a transfer's events to every URL, oldest first.
+pub async fn deliveries_for_transaction(
+    conn: &mut Connection,
+    transaction_id: &str,
+) -> RedisResult<Vec<WebhookDelivery>> {
+    let ids: Vec<String> = conn
+        .lrange(transaction_log_key(transaction_id), 0, -1)
+        .await?;
+    load_deliveries(conn, ids).await
+}
+
+/// A webhook's `limit` most recent deliveries, newest first.
+pub async fn deliveries_for_webhook(
+    conn: &mut Connection,
+    webhook_id: &str,
+    limit: usize,
+) -> RedisResult<Vec<WebhookDelivery>> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+    let ids: Vec<String> = conn
+        .lrange(webhook_log_key(webhook_id), 0, limit as isize - 1)
+        .await?;
+    load_deliveries(conn, ids).await
+}
+
+// Where one record's events go.
+struct Target {
+    webhook_id: Option<String>,
+    url: String,
+}
+
+fn targets_for(webhooks: &[Webhook], record: &TransactionRecord) -> Vec<Target> {
+    let mut targets: Vec<Target> = webhooks
+        .iter()
+        .filter(|webhook| webhook.api_key_id.is_none() || webhook.api_key_id == record.api_key_id)
+        .map(|webhook| Target {
+            webhook_id: Some(webhook.id.clone()),
+            url: webhook.url.clone(),
+        })
+        .collect();
+    if let Some(url) = &record.request.callback_url {
+        targets.push(Target {
+            webhook_id: None,
+            url: url.clone(),
+        });
+    }
+    targets
+}
+
+/// A transaction store that queues a webhook delivery for every status
+/// change written through it, to the global webhooks, those of the API key
+/// the transfer was submitted with and its `callback_url`.
+///
+/// Each entry of a record's `status_history` is delivered once, however
+/// often the record is written. Re-entering the same status (a batch sent
+/// again) is not an event. Deliveries are queued after the write, so a
+/// crash between the two loses those events.
+pub struct NotifyingStore {
+    inner: Arc<dyn TransactionStore>,
+    redis_pool: Pool,
+    log_ttl_secs: u64,
+}
+
+impl NotifyingStore {
+    pub fn new(inner: Arc<dyn TransactionStore>, redis_pool: Pool, settings: &Settings) -> Self {
+        Self {
+            inner,
+            redis_pool,
+            log_ttl_secs: settings.webhook_log_ttl_secs,
+        }
+    }
+
+    // Failing to queue deliveries doesn't undo the write, so it is only
+    // logged.
+    async fn notify(&self, records: &[&TransactionRecord]) {
+        if let Err(e) = self.queue_deliveries(records).await {
+            error!("Failed to queue webhook deliveries: {}", e);
+        }
+    }
+
+    async fn queue_deliveries(&self, records: &[&TransactionRecord]) -> StoreResult<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.redis_pool.get().await?;
+        let webhooks = list(&mut conn).await?;
+        let targeted: Vec<(&TransactionRecord, Vec<Target>)> = records
+            .iter()
+            .map(|record| (*record, targets_for(&webhooks, record)))
+            .filter(|(_, targets)| !targets.is_empty())
+            .collect();
+        if targeted.is_empty() {
+            return Ok(());
+        }
+
+        let script = redis::Script::new(NOTIFIED_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation.arg(self.log_ttl_secs);
+        for (record, _) in &targeted {
+            invocation
+                .key(notified_key(&record.id))
+                .arg(record.status_history.len());
+        }
+        let notified: Vec<usize> = invocation.invoke_async(&mut conn).await?;
+
+        let now = Utc::now();
+        let mut pipe = redis::pipe();
+        for ((record, targets), notified) in targeted.iter().zip(notified) {
+            let history = &record.status_history;
+            for (index, change) in history.iter().enumerate().skip(notified) {
+                let previous_status = index
+                    .checked_sub(1)
+                    .map(|previous| history[previous].status);
+                if previous_status == Some(change.status) {
+                    continue;
+                }
+                let event = WebhookEvent {
+                    id: format!("{}:{}", record.id, index),
+                    event_type: EVENT_STATUS_CHANGED.to_string(),
+                    transaction_id: record.id.clone(),
+                    status: change.status,
+                    previous_status,
+                    occurred_at: change.at,
+                    record: (*record).clone(),
+                };
+                for target in targets {
+                    let delivery = WebhookDelivery {
+                        id: Uuid::new_v4().to_string(),
+                        webhook_id: target.webhook_id.clone(),
+                        url: target.url.clone(),
+                        status: DeliveryStatus::Pending,
+                        event: event.clone(),
+                        attempts: Vec::new(),
+                        created_at: now,
+                        next_attempt_at: Some(now),
+                    };
+                    let log_key = transaction_log_key(&record.id);
+                    pipe.set(
+                        delivery_key(&delivery.id),
+                        serde_json::to_string(&delivery).unwrap(),
+                    )
+                    .ignore()
+                    .rpush(&log_key, &delivery.id)
+                    .ignore()
+                    .expire(&log_key, self.log_ttl_secs as i64)
+                    .ignore()
+                    .zadd(DUE_KEY, &delivery.id, now.timestamp_millis())
+                    .ignore();
+                    if let Some(webhook_id) = &delivery.webhook_id {
+                        pipe.lpush(webhook_log_key(webhook_id), &delivery.id)
+                            .ignore()
+                            .ltrim(webhook_log_key(webhook_id), 0, WEBHOOK_LOG_LEN - 1)
+                            .ignore();
+                    }
+                }
+            }
+        }
+        pipe.query_async::<()>(&mut conn).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TransactionStore for NotifyingStore {
+    async fn create(&self, records: &[TransactionRecord]) -> StoreResult<()> {
+        self.inner.create(records).await?;
+        let created: Vec<&TransactionRecord> = records.iter().collect();
+        self.notify(&created).await;
+        Ok(())
+    }
+
+    async fn save_if(
+        &self,
+        writes: &[(&TransactionRecord, Vec<TransactionStatus>)],
+    ) -> StoreResult<Vec<String>> {
+        let left_alone = self.inner.save_if(writes).await?;
+        let written: Vec<&TransactionRecord> = writes
+            .iter()
+            .map(|(record, _)| *record)
+            .filter(|record| !left_alone.contains(&record.id))
+            .collect();
+        self.notify(&written).await;
+        Ok(left_alone)
+    }
+
+    async fn get(&self, id: &str) -> StoreResult<Option<TransactionRecord>> {
+        self.inner.get(id).await
+    }
+
+    async fn get_many(&self, ids: &[String]) -> StoreResult<Vec<TransactionRecord>> {
+        self.inner.get_many(ids).await
+    }
+
+    async fn list_by_receiver(
+        &self,
+        receiver_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        self.inner
+            .list_by_receiver(receiver_id, offset, limit)
+            .await
+    }
+
+    async fn list_by_status(
+        &self,
+        status: TransactionStatus,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        self.inner.list_by_status(status, offset, limit).await
+    }
+
+    async fn list_by_campaign(
+        &self,
+        campaign_id: &str,
+        status: Option<TransactionStatus>,
+        offset: usize,
+        limit: usize,
+    ) -> StoreResult<Vec<TransactionRecord>> {
+        self.inner
+            .list_by_campaign(campaign_id, status, offset, limit)
+            .await
+    }
+
+    async fn list_all(
+        &self,
+        cursor: u64,
+        count: usize,
+    ) -> StoreResult<(u64, Vec<TransactionRecord>)> {
+        self.inner.list_all(cursor, count).await
+    }
+
+    async fn campaign_totals(&self, campaign_id: &str) -> StoreResult<CampaignTotals> {
+        self.inner.campaign_totals(campaign_id).await
+    }
+}
+
+// Why an attempt failed, and whether trying again could help.
+struct Failure {
+    status_code: Option<u16>,
+    error: String,
+    retryable: bool,
+}
+
+async fn send(
+    client: &reqwest::Client,
+    delivery: &WebhookDelivery,
+    secret: &str,
+) -> Result<u16, Failure> {
+    let body = serde_json::to_string(&delivery.event).unwrap_or_default();
+    let timestamp = Utc::now().timestamp();
+    let response = client
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(DELIVERY_HEADER, &delivery.id)
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, sign(secret, timestamp, &body))
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| Failure {
+            status_code: None,
+            error: e.to_string(),
+            retryable: true,
+        })?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(status.as_u16());
+    }
+    // Other client errors won't change however often the event is sent.
+    let retryable = !status.is_client_error()
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+    Err(Failure {
+        status_code: Some(status.as_u16()),
+        error: format!("Responded with {}", status),
+        retryable,
+    })
+}
+
+// Makes one attempt at a claimed delivery and stores the outcome: delivered,
+// due again after a backoff, or failed for good.
+async fn deliver(redis_pool: &Pool, client: &reqwest::Client, settings: &Settings, id: &str) {
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            // It stays in the sending list and is retried after a restart.
+            error!(
+                "Webhook delivery {} failed to get Redis connection: {}",
+                id, e
+            );
+            return;
+        }
+    };
+    let delivery_json: RedisResult<Option<String>> = conn.get(delivery_key(id)).await;
+    let delivery = match delivery_json {
+        Ok(json) => json.and_then(|json| serde_json::from_str::<WebhookDelivery>(&json).ok()),
+        Err(e) => {
+            error!("Failed to read webhook delivery {}: {}", id, e);
+            return;
+        }
+    };
+    let Some(mut delivery) = delivery else {
+        let _: RedisResult<()> = conn.lrem(SENDING_KEY, 1, id).await;
+        return;
+    };
+
+    let secret: Option<String> = match &delivery.webhook_id {
+        Some(webhook_id) => match conn.hget(WEBHOOK_SECRETS_KEY, webhook_id).await {
+            Ok(secret) => secret,
+            Err(e) => {
+                error!("Failed to read the secret of webhook {}: {}", webhook_id, e);
+                return;
+            }
+        },
+        None => settings
+            .webhook_secret
+            .as_deref()
+            .zip(delivery.event.record.api_key_id.as_deref())
+            .map(|(webhook_secret, api_key_id)| callback_secret(webhook_secret, api_key_id)),
+    };
+    let outcome = match &secret {
+        Some(secret) => send(client, &delivery, secret).await,
+        None => Err(Failure {
+            status_code: None,
+            error: match &delivery.webhook_id {
+                Some(_) => "The webhook was removed.".to_string(),
+                None if settings.webhook_secret.is_none() => "WEBHOOK_SECRET is not set.".to_string(),
+                None => "The transfer wasn't submitted with an API key.".to_string(),
+            },
+            retryable: false,
+        }),
+    };
+
+    let now = Utc::now();
+    let attempt = delivery.attempts.len() as u32 + 1;
+    let (status_code, failure) = match outcome {
+        Ok(status_code) => (Some(status_code), None),
+        Err(failure) => (failure.status_code, Some(failure)),
+    };
+    delivery.attempts.push(DeliveryAttempt {
+        attempt,
+        at: now,
+        status_code,
+        error: failure.as_ref().map(|failure| failure.error.clone()),
+    });
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    let retry_at: Option<DateTime<Utc>> = match &failure {
+        Some(failure) if failure.retryable && attempt < settings.webhook_max_attempts => {
+            let delay = retry::backoff_delay(
+                attempt,
+                settings.webhook_retry_base_delay_ms,
+                settings.webhook_retry_max_delay_ms,
+            );
+            Some(now + chrono::Duration::milliseconds(delay.as_millis() as i64))
+        }
+        _ => None,
+    };
+    delivery.next_attempt_at = retry_at;
+    match (&failure, retry_at) {
+        (None, _) => {
+            delivery.status = DeliveryStatus::Delivered;
+        }
+        (Some(_), Some(retry_at)) => {
+            pipe.zadd(DUE_KEY, id, retry_at.timestamp_millis()).ignore();
+        }
+        (Some(failure), None) => {
+            delivery.status = DeliveryStatus::Failed;
+            warn!(
+                "Giving up on webhook delivery {} to {} after {} attempts: {}",
+                id, delivery.url, attempt, failure.error
+            );
+        }
+    }
+    let delivery_json = serde_json::to_string(&delivery).unwrap();
+    if delivery.status == DeliveryStatus::Pending {
+        pipe.set(delivery_key(id), delivery_json).ignore();
+    } else {
+        // Finished deliveries are kept for the log, then expire.
+        pipe.set_ex(
+            delivery_key(id),
+            delivery_json,
+            settings.webhook_log_ttl_secs,
+        )
+        .ignore();
+    }
+    pipe.lrem(SENDING_KEY, 1, id).ignore();
+    if let Err(e) = pipe.query_async::<()>(&mut conn).await {
+        error!(
+            "Failed to store the outcome of webhook delivery {}: {}",
+            id, e
+        );
+    }
+}
+
+/// Sends queued webhook deliveries as they become due, up to
+/// `webhook_concurrency` at a time, retrying failures with backoff until
+/// `webhook_max_attempts`.
+pub async fn run_deliveries(redis_pool: Pool, settings: Settings) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(settings.webhook_timeout_ms))
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .expect("Failed to build the webhook HTTP client");
+
+    // Deliveries a previous run was sending when it stopped are sent again.
+    match redis_pool.get().await {
+        Ok(mut conn) => {
+            let recovered: RedisResult<usize> = redis::Script::new(RECOVER_SCRIPT)
+                .key(DUE_KEY)
+                .key(SENDING_KEY)
+                .arg(Utc::now().timestamp_millis())
+                .invoke_async(&mut conn)
+                .await;
+            match recovered {
+                Ok(0) => {}
+                Ok(n) => info!("Re-queued {} webhook deliveries left in flight.", n),
+                Err(e) => error!("Failed to recover in-flight webhook deliveries: {}", e),
+            }
+        }
+        Err(e) => error!("Webhook sender failed to get Redis connection: {}", e),
+    }
+
+    let claim_size = settings.webhook_concurrency * 4;
+    loop {
+        let claimed: RedisResult<Vec<String>> = match redis_pool.get().await {
+            Ok(mut conn) => {
+                redis::Script::new(CLAIM_SCRIPT)
+                    .key(DUE_KEY)
+                    .key(SENDING_KEY)
+                    .arg(Utc::now().timestamp_millis())
+                    .arg(claim_size)
+                    .invoke_async(&mut conn)
+                    .await
+            }
+            Err(e) => {
+                error!("Webhook sender failed to get Redis connection: {}", e);
+                Ok(Vec::new())
+            }
+        };
+        let ids = match claimed {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("Failed to claim due webhook deliveries: {}", e);
+                Vec::new()
+            }
+        };
+        if ids.is_empty() {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            continue;
+        }
+        futures::stream::iter(ids)
+            .for_each_concurrent(settings.webhook_concurrency, |id| {
+                let (redis_pool, client, settings) = (&redis_pool, &client, &settings);
+                async move { deliver(redis_pool, client, settings, &id).await }
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(api_key_id: Option<&str>, callback_url: Option<&str>) -> TransactionRecord {
+        let request: TokenTransferRequest = serde_json::from_value(serde_json::json!({
+            "reciever_id": "bob.near",
+            "amount": "10",
+            "callback_url": callback_url,
+        }))
+        .unwrap();
+        let mut record = TransactionRecord::new("sender.near".to_string(), request, 0);
+        record.api_key_id = api_key_id.map(str::to_string);
+        record
+    }
+
+    fn webhook(id: &str, api_key_id: Option<&str>) -> Webhook {
+        Webhook {
+            id: id.to_string(),
+            url: format!("https://example.com/{}", id),
+            api_key_id: api_key_id.map(str::to_string),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn signature_is_the_hmac_of_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_test", 1_700_000_000, r#"{"id":"evt"}"#),
+            "sha256=a94cea056df1fbb92eadafcf2c5cd541dbe0c6ef736e4748202dd53f86694a3e"
+        );
+    }
+
+    #[test]
+    fn callback_secrets_differ_per_key() {
+        let secret = callback_secret("shared", "key-a");
+        assert!(secret.starts_with("whsec_"));
+        assert_eq!(secret, callback_secret("shared", "key-a"));
+        assert_ne!(secret, callback_secret("shared", "key-b"));
+        assert_ne!(secret, callback_secret("other", "key-a"));
+    }
+
+    #[test]
+    fn targets_are_global_and_own_webhooks_and_the_callback() {
+        let webhooks = [
+            webhook("global", None),
+            webhook("mine", Some("key-a")),
+            webhook("theirs", Some("key-b")),
+        ];
+        let targets = targets_for(
+            &webhooks,
+            &record(Some("key-a"), Some("https://client.example/cb")),
+        );
+        let targets: Vec<(Option<&str>, &str)> = targets
+            .iter()
+            .map(|target| (target.webhook_id.as_deref(), target.url.as_str()))
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                (Some("global"), "https://example.com/global"),
+                (Some("mine"), "https://example.com/mine"),
+                (None, "https://client.example/cb"),
+            ]
+        );
+    }
+
+    #[test]
+    fn transfers_without_a_key_reach_only_global_webhooks() {
+        let webhooks = [webhook("global", None), webhook("mine", Some("key-a"))];
+        let targets = targets_for(&webhooks, &record(None, None));
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].webhook_id.as_deref(), Some("global"));
+    }
+
+    #[test]
+    fn urls_must_be_on_public_hosts() {
+        assert!(check_url("https://example.com/hook").is_ok());
+        assert!(check_url("http://93.184.216.34/hook").is_ok());
+        for url in [
+            "ftp://example.com/hook",
+            "http://localhost:8080/hook",
+            "http://api.localhost/hook",
+            "http://127.0.0.1/hook",
+            "http://10.0.0.5/hook",
+            "http://192.168.1.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/hook",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(check_url(url).is_err(), "{}", url);
+        }
+    }
+}
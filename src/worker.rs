@@ -1,170 +1,1349 @@
+use crate::balance::BalanceTracker;
+use crate::campaign;
 use crate::config::Settings;
-use crate::types::{TokenTransferRequest, TransactionRecord, TransactionStatus};
+use crate::keypool::KeyPool;
+use crate::queue;
+use crate::retry;
+use crate::storage::{StorageRegistrationMode, StorageRegistry};
+use crate::store::{StoreResult, TransactionStore};
+use crate::types::{CampaignStatus, TransactionRecord, TransactionStatus};
+use chrono::Utc;
 use deadpool_redis::Pool;
-use log::{error, info};
+use log::{error, info, warn};
+use near_api::advanced::TransactionableOrSigned;
+use near_api::errors::{ExecuteTransactionError, RetryError};
 use near_api::near_primitives::action::{Action, FunctionCallAction};
-use near_api::near_primitives::views::FinalExecutionStatus;
+use near_api::near_primitives::errors::{ActionError, TxExecutionError};
+use near_api::near_primitives::hash::CryptoHash;
+use near_api::near_primitives::transaction::SignedTransaction;
+use near_api::near_primitives::views::{
+    ExecutionStatusView, FinalExecutionOutcomeView, FinalExecutionStatus, TxExecutionStatus,
+};
 use near_api::*;
+use near_jsonrpc_client::errors::{JsonRpcError, JsonRpcServerError};
+use near_jsonrpc_client::methods::tx::{RpcTransactionError, RpcTransactionResponse, TransactionInfo};
+use near_jsonrpc_client::{JsonRpcClient, methods};
 use near_sdk::AccountId;
 use near_sdk::json_types::U128;
-use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{Semaphore, mpsc::Receiver};
-use tokio::time::timeout;
 
+// NEAR caps the gas a single transaction can attach across all its actions,
+// so every call gets the same fixed gas and a transaction holds at most
+// `MAX_ACTIONS_PER_TRANSACTION` of them.
+const MAX_TRANSACTION_GAS: u64 = 300_000_000_000_000;
+const FUNCTION_CALL_GAS: u64 = 30_000_000_000_000;
+const MAX_ACTIONS_PER_TRANSACTION: usize = (MAX_TRANSACTION_GAS / FUNCTION_CALL_GAS) as usize;
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run_worker(
-    // THE FIX: The receiver now gets a tuple of (ID, Request)
-    mut receiver: Receiver<(String, TokenTransferRequest)>,
     signer: Arc<Signer>,
     settings: Settings,
     network_config: NetworkConfig,
-    // THE FIX: Accept the Redis pool
     redis_pool: Pool,
+    store: Arc<dyn TransactionStore>,
+    storage: Arc<StorageRegistry>,
+    balance: Arc<BalanceTracker>,
+    key_pool: Arc<KeyPool>,
 ) {
-    let semaphore = Arc::new(Semaphore::new(settings.concurrency));
+    let ctx = Arc::new(BatchContext {
+        sender_id: AccountId::from_str(&settings.account_id).unwrap(),
+        ft_contract_id: AccountId::from_str(&settings.ft_contract_id).unwrap(),
+        signer,
+        key_pool,
+        settings: settings.clone(),
+        network_config,
+        store,
+        storage,
+        balance,
+    });
 
-    loop {
-        // The batch now stores tuples of (ID, Request)
-        let mut batch: Vec<(String, TokenTransferRequest)> =
-            Vec::with_capacity(settings.batch_size);
+    // The worker keeps one connection for itself since BLMOVE blocks it.
+    let mut queue_conn = loop {
+        match redis_pool.get().await {
+            Ok(conn) => break conn,
+            Err(e) => {
+                error!("Worker failed to get Redis connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    };
 
-        match receiver.recv().await {
-            Some(item) => batch.push(item),
-            None => break,
+    recover_in_flight(&ctx, &mut queue_conn).await;
+
+    // Moves transfers whose retry backoff has elapsed back onto the queue.
+    let retry_pool = redis_pool.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let mut conn = match retry_pool.get().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Retry scheduler failed to get Redis connection: {}", e);
+                    continue;
+                }
+            };
+            match queue::promote_due_retries(&mut conn, Utc::now().timestamp_millis()).await {
+                Ok(0) => {}
+                Ok(n) => info!("Re-queued {} transfers for retry.", n),
+                Err(e) => error!("Failed to re-queue retries: {}", e),
+            }
         }
+    });
 
-        let batch_timeout = Duration::from_secs(settings.batch_timeout_secs);
-        while batch.len() < settings.batch_size {
-            match timeout(batch_timeout, receiver.recv()).await {
-                Ok(Some(item)) => batch.push(item),
-                _ => break,
+    // Looks up batches whose outcome is unknown once their delay has elapsed.
+    let resolve_ctx = Arc::clone(&ctx);
+    let resolve_pool = redis_pool.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let mut conn = match resolve_pool.get().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Resolver failed to get Redis connection: {}", e);
+                    continue;
+                }
+            };
+            let ids = match queue::take_due_unresolved(&mut conn, Utc::now().timestamp_millis()).await {
+                Ok(ids) if ids.is_empty() => continue,
+                Ok(ids) => ids,
+                Err(e) => {
+                    error!("Failed to read unresolved transfers: {}", e);
+                    continue;
+                }
+            };
+            match resolve_ctx.store.get_many(&ids).await {
+                Ok(records) => {
+                    let records = records
+                        .into_iter()
+                        .filter(|record| record.status == TransactionStatus::Submitted)
+                        .collect();
+                    let _permit = resolve_ctx.key_pool.acquire_permit().await;
+                    resolve(&resolve_ctx, &mut conn, records).await;
+                }
+                // They are still in the processing list, so the next start looks them up.
+                Err(e) => error!("Failed to load unresolved transfers: {}", e),
             }
         }
+    });
+
+    loop {
+        let ids = match queue::next_batch(
+            &mut queue_conn,
+            settings.batch_size,
+            settings.batch_timeout_secs,
+        )
+        .await
+        {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!("Failed to read from transfer queue: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let batch = match ctx.store.get_many(&ids).await {
+            Ok(batch) => batch,
+            Err(e) => {
+                // The IDs stay in the processing list and are retried on the next start.
+                error!("Failed to load queued records: {}", e);
+                continue;
+            }
+        };
+
+        // IDs whose record disappeared can't be sent; drop them from the queue.
+        if batch.len() < ids.len() {
+            let missing: Vec<String> = ids
+                .iter()
+                .filter(|id| !batch.iter().any(|record| &record.id == *id))
+                .cloned()
+                .collect();
+            warn!("Dropping {} queued IDs without a record.", missing.len());
+            if let Err(e) = queue::ack(&mut queue_conn, &missing).await {
+                error!("Failed to ack missing records: {}", e);
+            }
+        }
+
+        let batch = claim_for_batch(
+            &mut queue_conn,
+            ctx.store.as_ref(),
+            batch,
+            settings.transfer_ttl_secs,
+            &ctx.balance,
+        )
+        .await;
 
         if !batch.is_empty() {
-            let permit = semaphore.clone().acquire_owned().await.unwrap();
-            let signer = Arc::clone(&signer);
-            let settings = settings.clone();
-            let network_config = network_config.clone();
+            let permit = ctx.key_pool.acquire_permit().await;
+            let ctx = Arc::clone(&ctx);
             let redis_pool = redis_pool.clone(); // Clone pool for the task
 
             tokio::spawn(async move {
                 let _permit = permit;
-                let transfers_count = batch.len();
-
-                let sender_id = AccountId::from_str(&settings.account_id).unwrap();
-                let ft_contract_id = AccountId::from_str(&settings.ft_contract_id).unwrap();
-
-                let mut transaction =
-                    Transaction::construct(sender_id.clone(), ft_contract_id.clone());
-
-                let deposit = 1;
-                let gas = 30_000_000_000_000;
-
-                // The loop now destructures the tuple
-                for (_id, transfer) in &batch {
-                    let amount_raw = transfer.amount.parse::<u128>().unwrap_or(0);
-                    if amount_raw > 0 {
-                        let ft_transfer_action =
-                            Action::FunctionCall(Box::new(FunctionCallAction {
-                                method_name: "ft_transfer".to_string(),
-                                args: json!({
-                                    "receiver_id": transfer.reciever_id,
-                                    "amount": U128(amount_raw),
-                                    "memo": transfer.memo,
-                                })
-                                .to_string()
-                                .into_bytes(),
-                                gas,
-                                deposit,
-                            }));
-                        transaction = transaction.add_action(ft_transfer_action);
+                process_batch(batch, ctx, redis_pool).await;
+            });
+        }
+    }
+}
+
+/// Called once at startup, before the worker starts: anything still in the
+/// processing list was taken by a previous process that died before writing
+/// its outcome. Transfers it never sent go back onto the queue; submitted
+/// ones may have gone through, so their batch is looked up by hash first.
+async fn recover_in_flight(ctx: &BatchContext, conn: &mut deadpool_redis::Connection) {
+    let ids = match queue::in_flight(conn).await {
+        Ok(ids) if ids.is_empty() => return,
+        Ok(ids) => ids,
+        Err(e) => {
+            error!("Failed to recover in-flight transfers: {}", e);
+            return;
+        }
+    };
+    let submitted: Vec<String> = match ctx.store.get_many(&ids).await {
+        Ok(records) => records
+            .into_iter()
+            .filter(|record| record.status == TransactionStatus::Submitted)
+            .map(|record| record.id)
+            .collect(),
+        Err(e) => {
+            // Re-queuing them all is still safe: a submitted transfer
+            // handed to the worker is parked for a lookup, not sent.
+            error!("Failed to load in-flight transfers: {}", e);
+            Vec::new()
+        }
+    };
+    let unsent: Vec<String> = ids.into_iter().filter(|id| !submitted.contains(id)).collect();
+
+    match queue::park_unresolved(conn, &submitted, Utc::now().timestamp_millis()).await {
+        Ok(_) if submitted.is_empty() => {}
+        Ok(_) => info!("Looking up {} transfers submitted by a previous run.", submitted.len()),
+        Err(e) => error!("Failed to park submitted transfers: {}", e),
+    }
+    match queue::requeue(conn, &unsent).await {
+        Ok(_) if unsent.is_empty() => {}
+        Ok(_) => info!("Re-queued {} transfers left in flight by a previous run.", unsent.len()),
+        Err(e) => error!("Failed to re-queue in-flight transfers: {}", e),
+    }
+}
+
+// Everything a batch task needs besides the records themselves. `signer`
+// is the master key, used only when every pool key is quarantined.
+struct BatchContext {
+    signer: Arc<Signer>,
+    key_pool: Arc<KeyPool>,
+    settings: Settings,
+    network_config: NetworkConfig,
+    store: Arc<dyn TransactionStore>,
+    storage: Arc<StorageRegistry>,
+    balance: Arc<BalanceTracker>,
+    sender_id: AccountId,
+    ft_contract_id: AccountId,
+}
+
+// A transfer that passed the pre-flight checks. `storage_deposit` is set when
+// the receiver has to be registered in the same transaction.
+struct PlannedTransfer {
+    record: TransactionRecord,
+    receiver_id: AccountId,
+    storage_deposit: Option<u128>,
+}
+
+// A signed batch transaction, kept under its hash from before it is
+// broadcast until its outcome is stored: enough to look it up, send the very
+// same transaction again, and map its actions back to the transfers.
+#[derive(Serialize, Deserialize)]
+struct SubmittedBatch {
+    signed_transaction: SignedTransaction,
+    /// ID and storage deposit of each transfer in the batch.
+    transfers: Vec<(String, Option<u128>)>,
+    /// ID of the transfer behind each action.
+    owners: Vec<String>,
+    registering: Vec<AccountId>,
+}
+
+async fn process_batch(batch: Vec<TransactionRecord>, ctx: Arc<BatchContext>, redis_pool: Pool) {
+
+    let mut planned: Vec<PlannedTransfer> = Vec::with_capacity(batch.len());
+    let mut rejected: Vec<(TransactionRecord, String)> = Vec::new();
+
+    for record in batch {
+        let receiver_id = match AccountId::from_str(&record.request.reciever_id) {
+            Ok(receiver_id) => receiver_id,
+            Err(e) => {
+                let message = format!("Invalid receiver_id {}: {}", record.request.reciever_id, e);
+                rejected.push((record, message));
+                continue;
+            }
+        };
+        // Requests with amount 0 are refused; this catches any stored before.
+        if record.raw_amount() == 0 {
+            rejected.push((record, "The amount is 0".to_string()));
+            continue;
+        }
+
+        // A failed lookup is treated like an unregistered receiver in auto
+        // mode (registration_only refunds the deposit if it wasn't needed)
+        // and like a registered one in fail mode.
+        let registered = match ctx.storage.is_registered(&receiver_id).await {
+            Ok(registered) => registered,
+            Err(e) => {
+                warn!("storage_balance_of lookup for {} failed: {}", receiver_id, e);
+                ctx.settings.storage_registration == StorageRegistrationMode::Fail
+            }
+        };
+
+        let storage_deposit = if registered {
+            None
+        } else {
+            match ctx.settings.storage_registration {
+                StorageRegistrationMode::Auto => match ctx.storage.min_deposit().await {
+                    Ok(min_deposit) => Some(min_deposit),
+                    Err(e) => {
+                        let message = format!(
+                            "Could not read storage_balance_bounds to register {}: {}",
+                            receiver_id, e
+                        );
+                        rejected.push((record, message));
+                        continue;
                     }
+                },
+                StorageRegistrationMode::Fail => {
+                    let message = format!(
+                        "Receiver {} is not registered with {} (no storage deposit)",
+                        receiver_id, ctx.settings.ft_contract_id
+                    );
+                    rejected.push((record, message));
+                    continue;
                 }
+            }
+        };
+
+        planned.push(PlannedTransfer {
+            record,
+            receiver_id,
+            storage_deposit,
+        });
+    }
 
-                info!("Sending batch of {} transfers...", transfers_count);
+    let mut conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            // Without a connection the outcome can't be stored, so the
+            // transfers stay in the processing list.
+            error!("Worker failed to get Redis connection: {}", e);
+            return;
+        }
+    };
+
+    for (record, message) in rejected {
+        warn!("Transfer {} not sent: {}", record.id, message);
+        store_outcome(
+            &mut conn,
+            ctx.store.as_ref(),
+            &ctx.balance,
+            vec![record],
+            TransactionStatus::Failure,
+            |record| record.error_message = Some(message.clone()),
+        )
+        .await;
+    }
 
-                let transaction_result = transaction
-                    .with_signer(signer)
-                    .send_to(&network_config)
+    for transfers in split_by_actions(planned) {
+        submit(&ctx, &mut conn, transfers).await;
+    }
+}
+
+// Splits planned transfers into groups that fit one transaction each,
+// counting a storage_deposit next to every ft_transfer that needs one.
+fn split_by_actions(planned: Vec<PlannedTransfer>) -> Vec<Vec<PlannedTransfer>> {
+    let mut groups = Vec::new();
+    let mut group = Vec::new();
+    let mut actions = 0;
+    for transfer in planned {
+        let needed = 1 + usize::from(transfer.storage_deposit.is_some());
+        if actions + needed > MAX_ACTIONS_PER_TRANSACTION {
+            groups.push(std::mem::take(&mut group));
+            actions = 0;
+        }
+        actions += needed;
+        group.push(transfer);
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    groups
+}
+
+// The function calls of a batch transaction, with `owners[i]` the ID of the
+// transfer behind call `i` and the receivers it registers.
+struct PlannedActions {
+    calls: Vec<(&'static str, serde_json::Value, u128)>,
+    owners: Vec<String>,
+    registering: Vec<AccountId>,
+}
+
+// An ft_transfer for every transfer, preceded by a storage_deposit the first
+// time a receiver that isn't registered yet comes up.
+fn plan_actions(transfers: &[PlannedTransfer]) -> PlannedActions {
+    let mut calls = Vec::with_capacity(transfers.len() * 2);
+    let mut owners = Vec::with_capacity(transfers.len() * 2);
+    let mut registering: Vec<AccountId> = Vec::new();
+
+    for transfer in transfers {
+        if let Some(deposit) = transfer.storage_deposit
+            && !registering.contains(&transfer.receiver_id)
+        {
+            calls.push((
+                "storage_deposit",
+                json!({
+                    "account_id": transfer.receiver_id,
+                    "registration_only": true,
+                }),
+                deposit,
+            ));
+            owners.push(transfer.record.id.clone());
+            registering.push(transfer.receiver_id.clone());
+        }
+
+        let request = &transfer.record.request;
+        calls.push((
+            "ft_transfer",
+            json!({
+                "receiver_id": request.reciever_id,
+                "amount": U128(transfer.record.raw_amount()),
+                "memo": request.memo,
+            }),
+            1,
+        ));
+        owners.push(transfer.record.id.clone());
+    }
+    PlannedActions {
+        calls,
+        owners,
+        registering,
+    }
+}
+
+/// Sends `transfers` as one transaction and stores the outcome of each.
+///
+/// The transaction is signed first and its hash stored with the transfers
+/// before it is broadcast, so a transfer whose outcome isn't known is looked
+/// up on chain instead of being paid again (see [`resolve`]).
+async fn submit(
+    ctx: &BatchContext,
+    conn: &mut deadpool_redis::Connection,
+    mut transfers: Vec<PlannedTransfer>,
+) {
+    let PlannedActions {
+        calls,
+        owners,
+        registering,
+    } = plan_actions(&transfers);
+
+    let mut transaction = Transaction::construct(ctx.sender_id.clone(), ctx.ft_contract_id.clone());
+    for (method_name, args, deposit) in calls {
+        transaction = transaction.add_action(Action::FunctionCall(Box::new(FunctionCallAction {
+            method_name: method_name.to_string(),
+            args: args.to_string().into_bytes(),
+            gas: FUNCTION_CALL_GAS,
+            deposit,
+        })));
+    }
+    let key = ctx.key_pool.acquire().await;
+    let signer = match &key {
+        Some(key) => key.signer().await,
+        None => {
+            warn!("No healthy pool key available; signing with the master key.");
+            Arc::clone(&ctx.signer)
+        }
+    };
+    let presigned = match transaction
+        .with_signer(signer)
+        .presign_with(&ctx.network_config)
+        .await
+    {
+        Ok(presigned) => presigned,
+        Err(e) => {
+            let result = Err(e);
+            if let Some(key) = &key {
+                ctx.key_pool.record_result(key, &result);
+            }
+            let Err(e) = result else { return };
+            // Nothing was sent, so the transfers can safely go out again.
+            let retryable = retry::is_retryable_send_error(&e);
+            error!("Error signing batch (retryable: {}): {}", retryable, e);
+            let records = transfers.into_iter().map(|transfer| transfer.record).collect();
+            fail_or_retry(ctx, conn, records, None, e.to_string(), retryable).await;
+            return;
+        }
+    };
+    let TransactionableOrSigned::Signed((signed_transaction, _)) = &presigned.tr else {
+        unreachable!("presign_with always signs the transaction");
+    };
+    let hash = signed_transaction.get_hash().to_string();
+    let batch = SubmittedBatch {
+        signed_transaction: signed_transaction.clone(),
+        transfers: transfers
+            .iter()
+            .map(|transfer| (transfer.record.id.clone(), transfer.storage_deposit))
+            .collect(),
+        owners,
+        registering,
+    };
+
+    for transfer in transfers.iter_mut() {
+        if let Err(e) = transfer.record.transition(TransactionStatus::Submitted) {
+            warn!("{}", e);
+        }
+        transfer.record.txn_hash = Some(hash.clone());
+    }
+    if let Err(e) = mark_submitted(ctx, conn, &hash, &batch, &mut transfers).await {
+        // Nothing was sent, so the transfers can safely go out again.
+        error!("Not sending batch {}: {}", hash, e);
+        drop_batch(conn, &hash).await;
+        if !transfers.is_empty() {
+            let records = transfers.into_iter().map(|transfer| transfer.record).collect();
+            fail_or_retry(ctx, conn, records, None, e, true).await;
+        }
+        return;
+    }
+
+    info!(
+        "Sending batch of {} transfers ({} storage registrations)... Hash: {}",
+        transfers.len(),
+        batch.registering.len(),
+        hash
+    );
+    let transaction_result = presigned.send_to(&ctx.network_config).await;
+    if let Some(key) = &key {
+        ctx.key_pool.record_result(key, &transaction_result);
+    }
+    settle(ctx, conn, transfers, &hash, &batch, transaction_result).await;
+}
+
+/// Stores `batch` under its hash, then the transfers as `Submitted` with that
+/// hash. Transfers that finished meanwhile are dropped from `transfers`; the
+/// batch can't be sent then, since it still pays them.
+async fn mark_submitted(
+    ctx: &BatchContext,
+    conn: &mut deadpool_redis::Connection,
+    hash: &str,
+    batch: &SubmittedBatch,
+    transfers: &mut Vec<PlannedTransfer>,
+) -> Result<(), String> {
+    let batch_json = serde_json::to_string(batch).map_err(|e| e.to_string())?;
+    queue::save_submitted(conn, hash, &batch_json)
+        .await
+        .map_err(|e| format!("Failed to store the signed batch: {}", e))?;
+
+    let submitted: Vec<TransactionRecord> =
+        transfers.iter().map(|transfer| transfer.record.clone()).collect();
+    let left_alone = ctx
+        .store
+        .update(&submitted)
+        .await
+        .map_err(|e| format!("Failed to mark transfers as submitted: {}", e))?;
+    if left_alone.is_empty() {
+        return Ok(());
+    }
+    // Someone else finished these meanwhile; sending them again would pay twice.
+    transfers.retain(|transfer| !left_alone.contains(&transfer.record.id));
+    if let Err(e) = queue::ack(conn, &left_alone).await {
+        error!("Failed to ack finished transfers: {}", e);
+    }
+    Err(format!("{} of its transfers already finished", left_alone.len()))
+}
+
+/// Stores what came of the batch `hash`, sent or re-sent. A failed batch is
+/// broken up: if the receipt outcome names the failed action, only its
+/// transfer is failed and the rest are resubmitted; otherwise the batch is
+/// split in half and each half is submitted on its own until the culprit is
+/// alone. Actions in a NEAR transaction succeed or fail together, so none of
+/// the transfers of a failed batch went through.
+async fn settle(
+    ctx: &BatchContext,
+    conn: &mut deadpool_redis::Connection,
+    mut transfers: Vec<PlannedTransfer>,
+    hash: &str,
+    batch: &SubmittedBatch,
+    transaction_result: Result<FinalExecutionOutcomeView, ExecuteTransactionError>,
+) {
+    match transaction_result {
+        Ok(result) if matches!(result.status, FinalExecutionStatus::SuccessValue(_)) => {
+            info!("Batch successful. Hash: {}", hash);
+            for receiver_id in &batch.registering {
+                ctx.storage.mark_registered(receiver_id).await;
+            }
+            let records = transfers.into_iter().map(|transfer| transfer.record).collect();
+            let stored = store_outcome(
+                conn,
+                ctx.store.as_ref(),
+                &ctx.balance,
+                records,
+                TransactionStatus::Success,
+                |record| {
+                    record.record_attempt(Some(hash.to_string()), None, false);
+                    record.txn_hash = Some(hash.to_string());
+                },
+            )
+            .await;
+            // Until the outcome is stored, the batch is what proves it went through.
+            if stored {
+                drop_batch(conn, hash).await;
+            }
+        }
+        Ok(result)
+            if matches!(&result.status, FinalExecutionStatus::Failure(error) if retry::is_retryable_execution_error(error)) =>
+        {
+            warn!("Batch rejected with a retryable error. Status: {:?}", result.status);
+            drop_batch(conn, hash).await;
+            let records = transfers.into_iter().map(|transfer| transfer.record).collect();
+            fail_or_retry(
+                ctx,
+                conn,
+                records,
+                Some(hash.to_string()),
+                format!("{:?}", result.status),
+                true,
+            )
+            .await;
+        }
+        Ok(result) => {
+            error!("Batch failed. Status: {:?}", result.status);
+            drop_batch(conn, hash).await;
+            let culprit = culprit(&result, &ctx.ft_contract_id, &batch.owners, &transfers);
+
+            match culprit {
+                Some(index) => {
+                    let failed = transfers.remove(index);
+                    fail_or_retry(
+                        ctx,
+                        conn,
+                        vec![failed.record],
+                        Some(hash.to_string()),
+                        format!("{:?}", result.status),
+                        false,
+                    )
+                    .await;
+                    if !transfers.is_empty() {
+                        info!("Resubmitting {} transfers without the failed one.", transfers.len());
+                        Box::pin(submit(ctx, conn, transfers)).await;
+                    }
+                }
+                None if transfers.len() > 1 => {
+                    let second_half = transfers.split_off(transfers.len() / 2);
+                    info!(
+                        "Failed action unknown; bisecting batch into {} and {} transfers.",
+                        transfers.len(),
+                        second_half.len()
+                    );
+                    Box::pin(submit(ctx, conn, transfers)).await;
+                    Box::pin(submit(ctx, conn, second_half)).await;
+                }
+                None => {
+                    let records = transfers.into_iter().map(|transfer| transfer.record).collect();
+                    fail_or_retry(
+                        ctx,
+                        conn,
+                        records,
+                        Some(hash.to_string()),
+                        format!("{:?}", result.status),
+                        false,
+                    )
                     .await;
+                }
+            }
+        }
+        Err(e) if retry::is_rejected_send_error(&e) => {
+            let retryable = retry::is_retryable_send_error(&e);
+            error!("Batch rejected (retryable: {}): {}", retryable, e);
+            drop_batch(conn, hash).await;
+            let records = transfers.into_iter().map(|transfer| transfer.record).collect();
+            fail_or_retry(ctx, conn, records, Some(hash.to_string()), e.to_string(), retryable)
+                .await;
+        }
+        Err(e) => {
+            // The batch may still have gone through; sending the transfers
+            // again in a new transaction could pay them twice.
+            warn!("Outcome of batch {} unknown ({}); looking it up later.", hash, e);
+            let records: Vec<TransactionRecord> =
+                transfers.into_iter().map(|transfer| transfer.record).collect();
+            park_unresolved(ctx, conn, &records).await;
+        }
+    }
+}
+
+// Drops a batch once its outcome is stored. If this fails, a later lookup
+// finds the transfers finished and leaves them alone.
+async fn drop_batch(conn: &mut deadpool_redis::Connection, hash: &str) {
+    if let Err(e) = queue::forget_submitted(conn, hash).await {
+        error!("Failed to drop batch {}: {}", hash, e);
+    }
+}
+
+/// Settles transfers left `Submitted` without a stored outcome, after a
+/// restart or when the network didn't say what came of their batch. The
+/// batch is looked up by its hash and settled from what the chain reports;
+/// only if no node knows it is the very same signed transaction sent again,
+/// so it can still go through at most once.
+async fn resolve(
+    ctx: &BatchContext,
+    conn: &mut deadpool_redis::Connection,
+    records: Vec<TransactionRecord>,
+) {
+    let mut by_hash: HashMap<Option<String>, Vec<TransactionRecord>> = HashMap::new();
+    for record in records {
+        by_hash.entry(record.txn_hash.clone()).or_default().push(record);
+    }
 
-                // --- THE FIX: Spawn a new task to update Redis ---
-                tokio::spawn(async move {
-                    let mut conn = match redis_pool.get().await {
-                        Ok(conn) => conn,
+    for (hash, records) in by_hash {
+        let batch = match &hash {
+            Some(hash) => match queue::load_submitted(conn, hash).await {
+                Ok(batch) => batch.and_then(|json| serde_json::from_str::<SubmittedBatch>(&json).ok()),
+                Err(e) => {
+                    error!("Failed to load batch {}: {}", hash, e);
+                    park_unresolved(ctx, conn, &records).await;
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let (hash, batch) = match (hash, batch) {
+            (Some(hash), Some(batch)) => (hash, batch),
+            // A batch is stored before it is sent and dropped only once its
+            // outcome is, so these were never sent in their current batch.
+            (hash, _) => {
+                let message = "The batch was never sent".to_string();
+                fail_or_retry(ctx, conn, records, hash, message, true).await;
+                continue;
+            }
+        };
+
+        // Settle every transfer of the batch, not only the ones asked about.
+        let ids: Vec<String> = batch.transfers.iter().map(|(id, _)| id.clone()).collect();
+        let current = match ctx.store.get_many(&ids).await {
+            Ok(current) => current,
+            Err(e) => {
+                error!("Failed to load the transfers of batch {}: {}", hash, e);
+                park_unresolved(ctx, conn, &records).await;
+                continue;
+            }
+        };
+        let transfers: Vec<PlannedTransfer> = current
+            .into_iter()
+            .filter(|record| {
+                record.status == TransactionStatus::Submitted
+                    && record.txn_hash.as_deref() == Some(hash.as_str())
+            })
+            .filter_map(|record| {
+                let receiver_id = AccountId::from_str(&record.request.reciever_id).ok()?;
+                let storage_deposit = batch
+                    .transfers
+                    .iter()
+                    .find(|(id, _)| id == &record.id)
+                    .and_then(|(_, deposit)| *deposit);
+                Some(PlannedTransfer {
+                    record,
+                    receiver_id,
+                    storage_deposit,
+                })
+            })
+            .collect();
+        if transfers.is_empty() {
+            drop_batch(conn, &hash).await;
+            continue;
+        }
+
+        let sender_id = batch.signed_transaction.transaction.signer_id().clone();
+        let transaction_result =
+            match look_up(&ctx.network_config, batch.signed_transaction.get_hash(), &sender_id).await {
+                Lookup::Outcome(outcome) => Ok(*outcome),
+                Lookup::Unknown => {
+                    info!("Batch {} is unknown to the network; sending it again.", hash);
+                    rebroadcast(&ctx.network_config, batch.signed_transaction.clone()).await
+                }
+                Lookup::Unavailable(message) => {
+                    warn!("Could not look up batch {}: {}", hash, message);
+                    park_unresolved(ctx, conn, &records).await;
+                    continue;
+                }
+            };
+        settle(ctx, conn, transfers, &hash, &batch, transaction_result).await;
+    }
+}
+
+// Leaves transfers in the processing list and has them looked up again later.
+async fn park_unresolved(
+    ctx: &BatchContext,
+    conn: &mut deadpool_redis::Connection,
+    records: &[TransactionRecord],
+) {
+    let ids: Vec<String> = records.iter().map(|record| record.id.clone()).collect();
+    let ready_at_ms = Utc::now().timestamp_millis() + ctx.settings.retry_max_delay_ms as i64;
+    if let Err(e) = queue::park_unresolved(conn, &ids, ready_at_ms).await {
+        // They are still in the processing list, so the next start looks them up.
+        error!("Failed to park {} unresolved transfers: {}", ids.len(), e);
+    }
+}
+
+// What the network knows about a transaction.
+enum Lookup {
+    Outcome(Box<FinalExecutionOutcomeView>),
+    /// Every node asked answered that it doesn't know the transaction.
+    Unknown,
+    /// No answer, or the transaction isn't final yet.
+    Unavailable(String),
+}
+
+// Asks the RPC endpoints in turn for the final outcome of a transaction. It
+// only counts as unknown when every endpoint says so.
+async fn look_up(
+    network_config: &NetworkConfig,
+    tx_hash: CryptoHash,
+    sender_id: &AccountId,
+) -> Lookup {
+    let mut unknown = false;
+    let mut failure = None;
+    for endpoint in &network_config.rpc_endpoints {
+        let client = JsonRpcClient::connect(endpoint.url.as_str());
+        let request = methods::tx::RpcTransactionStatusRequest {
+            transaction_info: TransactionInfo::TransactionId {
+                tx_hash,
+                sender_account_id: sender_id.clone(),
+            },
+            wait_until: TxExecutionStatus::Final,
+        };
+        match client.call(request).await {
+            Ok(RpcTransactionResponse {
+                final_execution_outcome: Some(outcome),
+                ..
+            }) => return Lookup::Outcome(Box::new(outcome.into_outcome())),
+            Ok(_) => return Lookup::Unavailable("the transaction is not final yet".to_string()),
+            Err(JsonRpcError::ServerError(JsonRpcServerError::HandlerError(
+                RpcTransactionError::UnknownTransaction { .. },
+            ))) => unknown = true,
+            Err(e) => failure = Some(e.to_string()),
+        }
+    }
+    match failure {
+        Some(message) => Lookup::Unavailable(message),
+        None if unknown => Lookup::Unknown,
+        None => Lookup::Unavailable("no RPC endpoint is configured".to_string()),
+    }
+}
+
+// Broadcasts an already signed transaction again, moving on to the next RPC
+// endpoint only when one can't be reached. Sending the same transaction
+// twice is harmless: its nonce lets it execute at most once.
+async fn rebroadcast(
+    network_config: &NetworkConfig,
+    signed_transaction: SignedTransaction,
+) -> Result<FinalExecutionOutcomeView, ExecuteTransactionError> {
+    let mut result = Err(RetryError::NoRpcEndpoints);
+    for endpoint in &network_config.rpc_endpoints {
+        let client = JsonRpcClient::connect(endpoint.url.as_str());
+        let request = methods::broadcast_tx_commit::RpcBroadcastTxCommitRequest {
+            signed_transaction: signed_transaction.clone(),
+        };
+        match client.call(request).await {
+            Err(e @ JsonRpcError::TransportError(_)) => result = Err(RetryError::RetriesExhausted(e)),
+            other => {
+                result = other.map_err(RetryError::Critical);
+                break;
+            }
+        }
+    }
+    result.map_err(ExecuteTransactionError::TransactionError)
+}
+
+/// Records a failed attempt on each transfer, then either parks it in the
+/// retry set with backoff or, when the error is permanent or `max_attempts`
+/// is used up, stores it as a final failure.
+async fn fail_or_retry(
+    ctx: &BatchContext,
+    conn: &mut deadpool_redis::Connection,
+    records: Vec<TransactionRecord>,
+    txn_hash: Option<String>,
+    message: String,
+    retryable: bool,
+) {
+    let mut failed = Vec::with_capacity(records.len());
+    for mut record in records {
+        let attempt = record.record_attempt(txn_hash.clone(), Some(message.clone()), retryable);
+        if !retryable || attempt >= ctx.settings.max_attempts {
+            failed.push(record);
+            continue;
+        }
+
+        let delay = retry::backoff_delay(
+            attempt,
+            ctx.settings.retry_base_delay_ms,
+            ctx.settings.retry_max_delay_ms,
+        );
+        let ready_at_ms = Utc::now().timestamp_millis() + delay.as_millis() as i64;
+        if let Err(e) = record.transition(TransactionStatus::Retrying) {
+            warn!("{}", e);
+        }
+        match ctx.store.update(std::slice::from_ref(&record)).await {
+            Ok(left_alone) if !left_alone.is_empty() => {
+                warn!("Transfer {} already finished; not retrying it.", record.id);
+                if let Err(e) = queue::ack(conn, &left_alone).await {
+                    error!("Failed to ack {}: {}", record.id, e);
+                }
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // Left in the processing list, so it is re-sent on the next start.
+                error!("Failed to store retry of {}: {}", record.id, e);
+                continue;
+            }
+        }
+        match queue::schedule_retry(conn, &record.id, ready_at_ms).await {
+            Ok(_) => info!(
+                "Transfer {} will be retried in {:?} (attempt {} of {}).",
+                record.id, delay, attempt, ctx.settings.max_attempts
+            ),
+            // Left in the processing list, so it is re-sent on the next start.
+            Err(e) => error!("Failed to schedule retry of {}: {}", record.id, e),
+        }
+    }
+
+    store_outcome(
+        conn,
+        ctx.store.as_ref(),
+        &ctx.balance,
+        failed,
+        TransactionStatus::Failure,
+        |record| {
+            record.txn_hash = txn_hash.clone();
+            record.error_message = Some(message.clone());
+        },
+    )
+    .await;
+}
+
+// Position in `transfers` of the transfer whose action failed, if known.
+fn culprit(
+    result: &FinalExecutionOutcomeView,
+    ft_contract_id: &AccountId,
+    owners: &[String],
+    transfers: &[PlannedTransfer],
+) -> Option<usize> {
+    failed_action_index(result, ft_contract_id)
+        .and_then(|action_index| owners.get(action_index))
+        .and_then(|id| transfers.iter().position(|transfer| &transfer.record.id == id))
+}
+
+/// Finds the index of the action that failed, looking first at the final
+/// status and then at the outcomes of the receipts executed on the FT contract.
+fn failed_action_index(
+    result: &FinalExecutionOutcomeView,
+    ft_contract_id: &AccountId,
+) -> Option<usize> {
+    let action_index = |error: &TxExecutionError| match error {
+        TxExecutionError::ActionError(ActionError {
+            index: Some(index), ..
+        }) => Some(*index as usize),
+        _ => None,
+    };
+
+    if let FinalExecutionStatus::Failure(error) = &result.status
+        && let Some(index) = action_index(error)
+    {
+        return Some(index);
+    }
+
+    result
+        .receipts_outcome
+        .iter()
+        .filter(|receipt| &receipt.outcome.executor_id == ft_contract_id)
+        .find_map(|receipt| match &receipt.outcome.status {
+            ExecutionStatusView::Failure(error) => action_index(error),
+            _ => None,
+        })
+}
+
+/// Moves freshly dequeued records to `Batched`. Records that were cancelled
+/// meanwhile are dropped from the queue, and records older than the TTL are
+/// marked `Expired` instead of being sent.
+///
+/// Every record is claimed with a conditional write on the status it was
+/// read in, so a record another worker moved on in the meantime is left to
+/// that worker instead of being sent twice.
+async fn claim_for_batch(
+    conn: &mut deadpool_redis::Connection,
+    store: &dyn TransactionStore,
+    records: Vec<TransactionRecord>,
+    transfer_ttl_secs: u64,
+    balance: &BalanceTracker,
+) -> Vec<TransactionRecord> {
+    let now = Utc::now();
+    let mut claims: Vec<(TransactionStatus, TransactionRecord)> = Vec::with_capacity(records.len());
+    let mut skipped = Vec::new();
+    let mut campaign_statuses: HashMap<String, CampaignStatus> = HashMap::new();
+    let mut unresolved = Vec::new();
+
+    for mut record in records {
+        let from = record.status;
+        // A transfer submitted before a restart may have gone through, so
+        // its batch is looked up by hash instead of being sent again.
+        if record.status == TransactionStatus::Submitted {
+            unresolved.push(record.id);
+            continue;
+        }
+        if let Some(campaign_id) = record.campaign_id.clone() {
+            let status = match campaign_statuses.get(&campaign_id) {
+                Some(status) => *status,
+                None => {
+                    // If the campaign can't be read the transfer goes out as usual.
+                    let status = match campaign::get(conn, &campaign_id).await {
+                        Ok(campaign) => campaign.map(|c| c.status).unwrap_or_default(),
                         Err(e) => {
-                            error!("Worker failed to get Redis connection: {}", e);
-                            return;
+                            error!("Failed to load campaign {}: {}", campaign_id, e);
+                            CampaignStatus::Active
                         }
                     };
-
-                    match transaction_result {
-                        Ok(result)
-                            if matches!(result.status, FinalExecutionStatus::SuccessValue(_)) =>
-                        {
-                            info!("Batch successful. Hash: {}", result.transaction.hash);
-                            for (id, _) in batch {
-                                let key = format!("txn:{}", id);
-                                if let Ok(mut record) =
-                                    conn.get::<_, String>(&key).await.and_then(|json| {
-                                        Ok(serde_json::from_str::<TransactionRecord>(&json)
-                                            .unwrap())
-                                    })
-                                {
-                                    record.status = TransactionStatus::Success;
-                                    record.txn_hash = Some(result.transaction.hash.to_string());
-                                    let _: () = conn
-                                        .set(&key, serde_json::to_string(&record).unwrap())
-                                        .await
-                                        .unwrap();
-                                }
-                            }
-                        }
-                        Ok(result) => {
-                            error!("Batch failed. Status: {:?}", result.status);
-                            for (id, _) in batch {
-                                let key = format!("txn:{}", id);
-                                if let Ok(mut record) =
-                                    conn.get::<_, String>(&key).await.and_then(|json| {
-                                        Ok(serde_json::from_str::<TransactionRecord>(&json)
-                                            .unwrap())
-                                    })
-                                {
-                                    record.status = TransactionStatus::Failure;
-                                    record.error_message = Some(format!("{:?}", result.status));
-                                    let _: () = conn
-                                        .set(&key, serde_json::to_string(&record).unwrap())
-                                        .await
-                                        .unwrap();
-                                }
-                            }
-                        }
+                    campaign_statuses.insert(campaign_id.clone(), status);
+                    status
+                }
+            };
+            match status {
+                CampaignStatus::Active => {}
+                CampaignStatus::Paused => {
+                    match campaign::hold(conn, &record).await {
+                        Ok(true) => {}
+                        Ok(false) => info!(
+                            "Campaign {} was resumed meanwhile; re-queued transfer {}.",
+                            campaign_id, record.id
+                        ),
+                        Err(e) => error!(
+                            "Failed to hold transfer {} of a paused campaign: {}",
+                            record.id, e
+                        ),
+                    }
+                    continue;
+                }
+                CampaignStatus::Cancelled => {
+                    match record.transition(TransactionStatus::Cancelled) {
+                        Ok(_) => claims.push((from, record)),
                         Err(e) => {
-                            error!("Error sending batch: {}", e);
-                            for (id, _) in batch {
-                                let key = format!("txn:{}", id);
-                                if let Ok(mut record) =
-                                    conn.get::<_, String>(&key).await.and_then(|json| {
-                                        Ok(serde_json::from_str::<TransactionRecord>(&json)
-                                            .unwrap())
-                                    })
-                                {
-                                    record.status = TransactionStatus::Failure;
-                                    record.error_message = Some(e.to_string());
-                                    let _: () = conn
-                                        .set(&key, serde_json::to_string(&record).unwrap())
-                                        .await
-                                        .unwrap();
-                                }
-                            }
+                            warn!("{}", e);
+                            skipped.push(record.id);
                         }
                     }
-                });
-            });
+                    continue;
+                }
+            }
+        }
+
+        let age = now.signed_duration_since(record.created_at);
+        let next = if transfer_ttl_secs > 0 && age.num_seconds() >= transfer_ttl_secs as i64 {
+            TransactionStatus::Expired
+        } else {
+            TransactionStatus::Batched
+        };
+        match record.transition(next) {
+            Ok(_) => claims.push((from, record)),
+            Err(e) => {
+                warn!("{}; dropping it from the queue.", e);
+                skipped.push(record.id);
+            }
+        }
+    }
+
+    let now_ms = now.timestamp_millis();
+    if let Err(e) = queue::park_unresolved(conn, &unresolved, now_ms).await {
+        // They stay in the processing list, so the next start looks them up.
+        error!("Failed to park submitted transfers: {}", e);
+    }
+
+    let left_alone = match store.update_from(&claims).await {
+        Ok(left_alone) => left_alone,
+        Err(e) => {
+            // Expired and cancelled transfers stay in the processing list.
+            // The status is informational for the batch, so it still goes out.
+            error!("Failed to claim transfers: {}", e);
+            if let Err(e) = queue::ack(conn, &skipped).await {
+                error!("Failed to ack skipped transfers: {}", e);
+            }
+            return claims
+                .into_iter()
+                .map(|(_, record)| record)
+                .filter(|record| record.status == TransactionStatus::Batched)
+                .collect();
+        }
+    };
+    if !left_alone.is_empty() {
+        warn!("{} transfers changed since they were read; leaving them alone.", left_alone.len());
+        skipped.extend(left_alone.iter().cloned());
+    }
+
+    let mut batch = Vec::with_capacity(claims.len());
+    let mut finished = Vec::new();
+    for (_, record) in claims {
+        if left_alone.contains(&record.id) {
+            continue;
+        }
+        if record.status == TransactionStatus::Batched {
+            batch.push(record);
+        } else {
+            finished.push(record);
+        }
+    }
+
+    if !finished.is_empty() {
+        let expired = finished
+            .iter()
+            .filter(|record| record.status == TransactionStatus::Expired)
+            .count();
+        if expired > 0 {
+            info!("{} transfers expired before they could be sent.", expired);
+        }
+        if finished.len() > expired {
+            info!("{} transfers of cancelled campaigns were dropped.", finished.len() - expired);
+        }
+        // They are stored as finished, so their reservations go even if the
+        // ack fails; a re-queued copy would be skipped.
+        balance.settle(&finished);
+        skipped.extend(finished.iter().map(|record| record.id.clone()));
+    }
+    if let Err(e) = queue::ack(conn, &skipped).await {
+        error!("Failed to ack skipped transfers: {}", e);
+    }
+    batch
+}
+
+/// Stores the final state of `finished`, then removes them from the
+/// processing list. A crash in between leaves them in the list; on the next
+/// start they are re-queued and dropped, since a finished record can't be
+/// batched again. Records another writer already finished are left alone
+/// and acked as well; only the ones written are returned.
+async fn complete(
+    conn: &mut deadpool_redis::Connection,
+    store: &dyn TransactionStore,
+    finished: Vec<TransactionRecord>,
+) -> StoreResult<Vec<TransactionRecord>> {
+    if finished.is_empty() {
+        return Ok(finished);
+    }
+    let left_alone = store.update(&finished).await?;
+    let ids: Vec<String> = finished.iter().map(|record| record.id.clone()).collect();
+    queue::ack(conn, &ids).await?;
+    if !left_alone.is_empty() {
+        warn!("{} transfers had already finished; kept their stored outcome.", left_alone.len());
+    }
+    Ok(finished
+        .into_iter()
+        .filter(|record| !left_alone.contains(&record.id))
+        .collect())
+}
+
+/// Writes the outcome of a batch to the transaction store and only then
+/// removes the transfers from the processing list, so a crash in between
+/// re-sends them instead of losing them. Returns whether both went through.
+async fn store_outcome<F>(
+    conn: &mut deadpool_redis::Connection,
+    store: &dyn TransactionStore,
+    balance: &BalanceTracker,
+    batch: Vec<TransactionRecord>,
+    status: TransactionStatus,
+    apply: F,
+) -> bool
+where
+    F: Fn(&mut TransactionRecord),
+{
+    let mut finished = Vec::with_capacity(batch.len());
+    for mut record in batch {
+        if let Err(e) = record.transition(status) {
+            warn!("{}", e);
+        }
+        apply(&mut record);
+        finished.push(record);
+    }
+    let count = finished.len();
+    match complete(conn, store, finished).await {
+        Ok(written) => {
+            balance.settle(&written);
+            true
+        }
+        // The transfers stay in the processing list and are re-sent on the next start.
+        Err(e) => {
+            error!("Failed to store outcome of {} transfers: {}", count, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TokenTransferRequest;
+
+    fn planned(receiver: &str, storage_deposit: Option<u128>) -> PlannedTransfer {
+        let request: TokenTransferRequest =
+            serde_json::from_value(json!({"reciever_id": receiver, "amount": "10"})).unwrap();
+        PlannedTransfer {
+            record: TransactionRecord::new("sender.near".to_string(), request, 0),
+            receiver_id: receiver.parse().unwrap(),
+            storage_deposit,
+        }
+    }
+
+    fn ids(transfers: &[PlannedTransfer]) -> Vec<&str> {
+        transfers
+            .iter()
+            .map(|transfer| transfer.record.id.as_str())
+            .collect()
+    }
+
+    // An outcome whose final status is `status` and whose receipts, executed
+    // by the given accounts, ended as given.
+    fn outcome(
+        status: serde_json::Value,
+        receipts: &[(&str, serde_json::Value)],
+    ) -> FinalExecutionOutcomeView {
+        let hash = "11111111111111111111111111111111";
+        let execution = |executor: &str, status: &serde_json::Value| {
+            json!({
+                "proof": [],
+                "block_hash": hash,
+                "id": hash,
+                "outcome": {
+                    "logs": [],
+                    "receipt_ids": [],
+                    "gas_burnt": 0,
+                    "tokens_burnt": "0",
+                    "executor_id": executor,
+                    "status": status,
+                    "metadata": {"version": 1, "gas_profile": null},
+                },
+            })
+        };
+        serde_json::from_value(json!({
+            "status": status,
+            "transaction": {
+                "signer_id": "sender.near",
+                "public_key": format!("ed25519:{}", hash),
+                "nonce": 1,
+                "receiver_id": "ft.near",
+                "actions": [],
+                "signature": format!("ed25519:{}", "1".repeat(64)),
+                "hash": hash,
+            },
+            "transaction_outcome": execution("sender.near", &json!({"SuccessReceiptId": hash})),
+            "receipts_outcome": receipts
+                .iter()
+                .map(|(executor, status)| execution(executor, status))
+                .collect::<Vec<_>>(),
+        }))
+        .unwrap()
+    }
+
+    fn action_failure(index: Option<u64>) -> serde_json::Value {
+        json!({"ActionError": {"index": index, "kind": {"FunctionCallError": {"ExecutionError": "Smart contract panicked"}}}})
+    }
+
+    #[test]
+    fn storage_deposits_count_towards_the_action_limit() {
+        // Four registrations (8 actions) and two plain transfers fill one
+        // transaction; the next registration no longer fits.
+        let mut transfers: Vec<PlannedTransfer> = (0..4)
+            .map(|i| planned(&format!("new{}.near", i), Some(125)))
+            .collect();
+        transfers.push(planned("old0.near", None));
+        transfers.push(planned("old1.near", None));
+        transfers.push(planned("new4.near", Some(125)));
+        let expected = ids(&transfers)
+            .into_iter()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        let groups = split_by_actions(transfers);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(ids(&groups[0]), expected[..6]);
+        assert_eq!(ids(&groups[1]), expected[6..]);
+        for group in &groups {
+            assert!(plan_actions(group).calls.len() <= MAX_ACTIONS_PER_TRANSACTION);
+        }
+    }
+
+    #[test]
+    fn plain_transfers_fill_a_whole_transaction() {
+        let transfers: Vec<PlannedTransfer> = (0..MAX_ACTIONS_PER_TRANSACTION + 1)
+            .map(|i| planned(&format!("old{}.near", i), None))
+            .collect();
+        let groups = split_by_actions(transfers);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len(), MAX_ACTIONS_PER_TRANSACTION);
+        assert_eq!(groups[1].len(), 1);
+    }
+
+    #[test]
+    fn a_repeated_receiver_is_registered_once() {
+        let transfers = vec![
+            planned("new.near", Some(125)),
+            planned("new.near", Some(125)),
+        ];
+        let actions = plan_actions(&transfers);
+        let methods: Vec<&str> = actions.calls.iter().map(|(method, _, _)| *method).collect();
+        assert_eq!(methods, ["storage_deposit", "ft_transfer", "ft_transfer"]);
+        assert_eq!(
+            actions.owners,
+            [
+                transfers[0].record.id.clone(),
+                transfers[0].record.id.clone(),
+                transfers[1].record.id.clone(),
+            ]
+        );
+        assert_eq!(actions.registering, ["new.near".parse::<AccountId>().unwrap()]);
+        // Splitting still counts both deposits, which only errs on the safe side.
+        assert_eq!(split_by_actions(transfers).len(), 1);
+    }
+
+    #[test]
+    fn failed_actions_are_blamed_on_their_transfer() {
+        let transfers = vec![
+            planned("old.near", None),
+            planned("new.near", Some(125)),
+            planned("other.near", None),
+        ];
+        let owners = plan_actions(&transfers).owners;
+        let ft_contract_id: AccountId = "ft.near".parse().unwrap();
+
+        // Actions: ft_transfer(old), storage_deposit(new), ft_transfer(new),
+        // ft_transfer(other).
+        for (action, transfer) in [(0, 0), (1, 1), (2, 1), (3, 2)] {
+            let result = outcome(json!({"Failure": action_failure(Some(action))}), &[]);
+            assert_eq!(failed_action_index(&result, &ft_contract_id), Some(action as usize));
+            assert_eq!(
+                culprit(&result, &ft_contract_id, &owners, &transfers),
+                Some(transfer)
+            );
         }
     }
+
+    #[test]
+    fn the_failed_action_is_read_from_the_contract_receipts() {
+        let transfers = vec![planned("new.near", Some(125)), planned("old.near", None)];
+        let owners = plan_actions(&transfers).owners;
+        let ft_contract_id: AccountId = "ft.near".parse().unwrap();
+        let result = outcome(
+            json!({"Failure": action_failure(None)}),
+            &[
+                ("other.near", json!({"Failure": action_failure(Some(0))})),
+                ("ft.near", json!({"Failure": action_failure(Some(2))})),
+            ],
+        );
+        assert_eq!(failed_action_index(&result, &ft_contract_id), Some(2));
+        assert_eq!(culprit(&result, &ft_contract_id, &owners, &transfers), Some(1));
+
+        let unknown = outcome(json!({"Failure": action_failure(None)}), &[]);
+        assert_eq!(culprit(&unknown, &ft_contract_id, &owners, &transfers), None);
+    }
 }